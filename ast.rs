@@ -1,4 +1,13 @@
+use std::collections::HashMap;
 use std::error;
+use std::fmt;
+use std::mem;
+use std::ops;
+
+use super::context::EvalContext;
+use super::formatter::FormatterConfig;
+use super::parser::ParseConfig;
+use super::token::Span;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
@@ -10,33 +19,2225 @@ pub enum Node {
     Divide(Box<Node>, Box<Node>),
     Caret(Box<Node>, Box<Node>),
     Negative(Box<Node>),
+    // Logical not, 1 when the operand is 0 and 0 otherwise
+    Not(Box<Node>),
     Number(f64),
+    // A named constant such as pi, evaluating to its value rather than to a variable's
+    Constant(MathConstant),
+    Variable(String),
+    // `let name = value in body`, binding `name` only while evaluating `body`
+    Let(String, Box<Node>, Box<Node>),
+    // Call of a built-in function such as `sin(x)` or `max(a, b, c)`
+    Function(String, Vec<Node>),
+    // Sum and product of any number of operands, built by `flatten` from chains of Add
+    // and Multiply. An empty sum is 0 and an empty product is 1.
+    FlatAdd(Vec<Node>),
+    FlatMul(Vec<Node>),
+}
+
+// Identifier of a node within a tree, its position in a pre-order walk plus an offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub usize);
+
+impl Node {
+    // Direct operands of this node, in source order
+    #[must_use]
+    pub fn children(&self) -> Vec<&Node> {
+        use self::Node::*;
+        match self {
+            Number(_) | Constant(_) | Variable(_) => Vec::new(),
+            Negative(a) | Not(a) => vec![a],
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+            | Or(a, b) | Let(_, a, b) => vec![a, b],
+            Function(_, args) | FlatAdd(args) | FlatMul(args) => args.iter().collect(),
+        }
+    }
+
+    // The node reached by following `path` from this one, each step the index of a child in
+    // `children` order: [1, 0] is the first operand of the right operand. The empty path is
+    // this node; None if a step is past the last child of its node.
+    #[must_use]
+    pub fn depth_at_path(&self, path: &[usize]) -> Option<&Node> {
+        path.iter().try_fold(self, |node, &index| node.children().get(index).copied())
+    }
+
+    // A copy of the tree with the node at `path` (see `depth_at_path`) replaced by
+    // `replacement`, None if there is no node there
+    #[must_use]
+    pub fn replace_at_path(&self, path: &[usize], replacement: Node) -> Option<Node> {
+        let mut tree = self.clone();
+        let mut target = &mut tree;
+        for &index in path {
+            target = target.child_mut(index)?;
+        }
+        *target = replacement;
+        Some(tree)
+    }
+
+    // Mutable access to child `index` in `children` order
+    fn child_mut(&mut self, index: usize) -> Option<&mut Node> {
+        use self::Node::*;
+        match self {
+            Negative(a) | Not(a) if index == 0 => Some(a),
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+            | Or(a, b) | Let(_, a, b) => match index {
+                0 => Some(a),
+                1 => Some(b),
+                _ => None,
+            },
+            Function(_, args) | FlatAdd(args) | FlatMul(args) => args.get_mut(index),
+            _ => None,
+        }
+    }
+
+    // A copy of the tree with an ID on every node, numbered in pre-order (a node before its
+    // `children`) from `offset`. Copies made with offsets at least `count_nodes` apart share
+    // no IDs, so their nodes can be told apart once combined.
+    #[must_use]
+    pub fn clone_deep_with_offset(&self, offset: usize) -> IdNode {
+        IdNode::from_pre_order(self, &mut NodeId(offset))
+    }
+
+    // A copy with every number within 10^-precision of a KNOWN_CONSTANTS value replaced by
+    // the Node::Constant naming it, so `3.14159 * r^2` with precision 5 reads `pi * r^2`. Numbers
+    // inside a let binding the same name are kept, since the name means something else there.
+    #[must_use]
+    pub fn substitute_numbers_with_symbols(&self, precision: usize) -> Node {
+        let tolerance = 10f64.powi(-(precision.min(i32::MAX as usize) as i32));
+        symbolize(self, tolerance, &mut Vec::new())
+    }
+
+    // True if this is `a*var + b` for constants `a` and `b`, however it is written: `x + x`,
+    // `(x - 1)/2` and `7` are linear in x, `x^2`, `x*y` and `sin(x)` are not
+    #[must_use]
+    pub fn is_linear_in(&self, var: &str) -> bool {
+        linear_form(self, var).is_some()
+    }
+
+    // The coefficient `a` of `a*var + b`, 0 for a constant, None if this is not linear in `var`
+    #[must_use]
+    pub fn linear_coefficient(&self, var: &str) -> Option<f64> {
+        linear_form(self, var).map(|(a, _)| a)
+    }
+
+    // Coefficients [a0, a1, a2, ...] of this as a polynomial in `var`, `a_i` multiplying `var^i`,
+    // None if it is not one. Other variables make it None too, since the coefficients have to be
+    // numbers. There is one coefficient for each degree up to `polynomial_degree`, which reads
+    // the degree off the tree, so `x - x` gives [0, 0]. Degrees above MAX_POLYNOMIAL_DEGREE
+    // are None as well.
+    #[must_use]
+    pub fn to_polynomial_coefficients(&self, var: &str) -> Option<Vec<f64>> {
+        if polynomial_degree(self, var)? > MAX_POLYNOMIAL_DEGREE {
+            return None;
+        }
+        polynomial(self, var)
+    }
+}
+
+// Constants `Node::substitute_numbers_with_symbols` recognises, with the names written for them
+pub const KNOWN_CONSTANTS: &[(&str, f64)] = &[
+    ("pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("tau", std::f64::consts::TAU),
+];
+
+// The constants a Node::Constant can name, in the order of KNOWN_CONSTANTS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MathConstant {
+    Pi,
+    E,
+    Tau,
+}
+
+impl MathConstant {
+    pub const ALL: [MathConstant; 3] = [MathConstant::Pi, MathConstant::E, MathConstant::Tau];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        KNOWN_CONSTANTS[self as usize].0
+    }
+
+    #[must_use]
+    pub fn value(self) -> f64 {
+        KNOWN_CONSTANTS[self as usize].1
+    }
+}
+
+// `bound` holds the names of the let bindings in force
+fn symbolize<'a>(node: &'a Node, tolerance: f64, bound: &mut Vec<&'a str>) -> Node {
+    use self::Node::*;
+    let mut sub = |node: &'a Node| Box::new(symbolize(node, tolerance, bound));
+    match node {
+        Number(x) => MathConstant::ALL
+            .into_iter()
+            .find(|c| (x - c.value()).abs() < tolerance && !bound.contains(&c.name()))
+            .map_or_else(|| node.clone(), Constant),
+        Constant(_) | Variable(_) => node.clone(),
+        Negative(a) => Negative(sub(a)),
+        Not(a) => Not(sub(a)),
+        Add(a, b) => Add(sub(a), sub(b)),
+        Subtract(a, b) => Subtract(sub(a), sub(b)),
+        Multiply(a, b) => Multiply(sub(a), sub(b)),
+        Divide(a, b) => Divide(sub(a), sub(b)),
+        Caret(a, b) => Caret(sub(a), sub(b)),
+        And(a, b) => And(sub(a), sub(b)),
+        Or(a, b) => Or(sub(a), sub(b)),
+        Let(name, value, body) => {
+            let value = sub(value);
+            bound.push(name);
+            let body = Box::new(symbolize(body, tolerance, bound));
+            bound.pop();
+            Let(name.clone(), value, body)
+        }
+        Function(f, args) => {
+            Function(f.clone(), args.iter().map(|arg| symbolize(arg, tolerance, bound)).collect())
+        }
+        FlatAdd(items) => FlatAdd(items.iter().map(|item| symbolize(item, tolerance, bound)).collect()),
+        FlatMul(items) => FlatMul(items.iter().map(|item| symbolize(item, tolerance, bound)).collect()),
+    }
+}
+
+// `node` as `(a, b)` with node = a*var + b, None if it is not linear in `var`. Other variables
+// make it non-linear, since the coefficients have to be numbers; subtrees without any variable
+// are evaluated.
+fn linear_form(node: &Node, var: &str) -> Option<(f64, f64)> {
+    use self::Node::*;
+    // (a*x + b)(c*x + d) stays linear only while one side is constant
+    fn product((a, b): (f64, f64), (c, d): (f64, f64)) -> Option<(f64, f64)> {
+        match (a == 0.0, c == 0.0) {
+            (true, _) => Some((b * c, b * d)),
+            (_, true) => Some((a * d, b * d)),
+            _ => None,
+        }
+    }
+    if free_variables(node).is_empty() {
+        return Some((0.0, eval(node.clone()).ok()?));
+    }
+    let form = |node: &Node| linear_form(node, var);
+    match node {
+        Variable(v) if v == var => Some((1.0, 0.0)),
+        Negative(a) => form(a).map(|(a, b)| (-a, -b)),
+        Add(l, r) => form(l).zip(form(r)).map(|((a, b), (c, d))| (a + c, b + d)),
+        Subtract(l, r) => form(l).zip(form(r)).map(|((a, b), (c, d))| (a - c, b - d)),
+        FlatAdd(items) => items.iter().try_fold((0.0, 0.0), |(a, b), item| {
+            let (c, d) = form(item)?;
+            Some((a + c, b + d))
+        }),
+        Multiply(l, r) => product(form(l)?, form(r)?),
+        FlatMul(items) => items.iter().try_fold((0.0, 1.0), |acc, item| product(acc, form(item)?)),
+        Divide(l, r) => match (form(l)?, form(r)?) {
+            ((a, b), (c, d)) if c == 0.0 && d != 0.0 => Some((a / d, b / d)),
+            _ => None,
+        },
+        Caret(base, exponent) => match form(exponent)? {
+            (e, n) if e == 0.0 && n == 1.0 => form(base),
+            (e, n) if e == 0.0 && n == 0.0 => Some((0.0, 1.0)),
+            _ => None,
+        },
+        Let(name, value, body) => form(&substitute(body, name, value)),
+        _ => None,
+    }
+}
+
+// Highest degree `Node::to_polynomial_coefficients` expands, keeping the coefficient vector
+// and the time to multiply it out small
+pub const MAX_POLYNOMIAL_DEGREE: u32 = 1024;
+
+// Coefficients of `node` in `var`, lowest degree first. Subtrees without any variable are
+// evaluated.
+fn polynomial(node: &Node, var: &str) -> Option<Vec<f64>> {
+    use self::Node::*;
+    fn sum(a: Vec<f64>, b: Vec<f64>, sign: f64) -> Vec<f64> {
+        let mut result = vec![0.0; a.len().max(b.len())];
+        a.iter().enumerate().for_each(|(i, c)| result[i] += c);
+        b.iter().enumerate().for_each(|(i, c)| result[i] += sign * c);
+        result
+    }
+    fn product(a: &[f64], b: &[f64]) -> Vec<f64> {
+        let mut result = vec![0.0; a.len() + b.len() - 1];
+        for (i, x) in a.iter().enumerate() {
+            for (j, y) in b.iter().enumerate() {
+                result[i + j] += x * y;
+            }
+        }
+        result
+    }
+    if free_variables(node).is_empty() {
+        return Some(vec![eval(node.clone()).ok()?]);
+    }
+    let poly = |node: &Node| polynomial(node, var);
+    match node {
+        Variable(v) if v == var => Some(vec![0.0, 1.0]),
+        Negative(a) => Some(poly(a)?.into_iter().map(|c| -c).collect()),
+        Add(a, b) => Some(sum(poly(a)?, poly(b)?, 1.0)),
+        Subtract(a, b) => Some(sum(poly(a)?, poly(b)?, -1.0)),
+        FlatAdd(items) => items.iter().try_fold(vec![0.0], |acc, item| Some(sum(acc, poly(item)?, 1.0))),
+        Multiply(a, b) => Some(product(&poly(a)?, &poly(b)?)),
+        FlatMul(items) => items.iter().try_fold(vec![1.0], |acc, item| Some(product(&acc, &poly(item)?))),
+        Divide(a, b) => match poly(b)?.as_slice() {
+            [d] if *d != 0.0 => Some(poly(a)?.into_iter().map(|c| c / d).collect()),
+            _ => None,
+        },
+        Caret(base, exponent) => match **exponent {
+            Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= f64::from(MAX_POLYNOMIAL_DEGREE) => {
+                // Exponentiation by squaring
+                let (mut square, mut times, mut result) = (poly(base)?, n as u32, vec![1.0]);
+                while times > 0 {
+                    if times & 1 == 1 {
+                        result = product(&result, &square);
+                    }
+                    times >>= 1;
+                    if times > 0 {
+                        square = product(&square, &square);
+                    }
+                }
+                Some(result)
+            }
+            _ => None,
+        },
+        Let(name, value, body) => poly(&substitute(body, name, value)),
+        _ => None,
+    }
+}
+
+// A node together with the byte range of source it was parsed from.
+// `children` mirrors `node.children()`, giving each operand its own span.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedNode<'a> {
+    pub node: Node,
+    pub span: Span,
+    pub children: Vec<SpannedNode<'a>>,
+    source: &'a str,
+}
+
+impl<'a> SpannedNode<'a> {
+    // Pair `node` with spans listed in post-order, popping them from the end of `spans`
+    pub(crate) fn from_post_order(node: Node, spans: &mut Vec<Span>, source: &'a str) -> Self {
+        let span = spans.pop().unwrap_or_default();
+        let mut children: Vec<SpannedNode<'a>> = node
+            .children()
+            .into_iter()
+            .rev()
+            .map(|child| SpannedNode::from_post_order(child.clone(), spans, source))
+            .collect();
+        children.reverse();
+        SpannedNode { node, span, children, source }
+    }
+
+    // The source text this node was parsed from, empty for a Parser built from tokens
+    #[must_use]
+    pub fn text(&self) -> &'a str {
+        self.source.get(self.span.start..self.span.end).unwrap_or("")
+    }
+}
+
+// A node's ID, what the node holds besides its operands, and its operands with their own IDs
+// in `children` order, from `Node::clone_deep_with_offset`. `to_node` rebuilds the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdNode {
+    pub id: NodeId,
+    pub kind: NodeKind,
+    pub children: Vec<IdNode>,
+}
+
+// The variant of a Node and the data it stores next to its operands, such as the value of a
+// Number or the name of a Function
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeKind {
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Caret,
+    Negative,
+    Not,
+    Number(f64),
+    Constant(MathConstant),
+    Variable(String),
+    Let(String),
+    Function(String),
+    FlatAdd,
+    FlatMul,
+}
+
+impl NodeKind {
+    fn of(node: &Node) -> NodeKind {
+        match node {
+            Node::And(..) => NodeKind::And,
+            Node::Or(..) => NodeKind::Or,
+            Node::Add(..) => NodeKind::Add,
+            Node::Subtract(..) => NodeKind::Subtract,
+            Node::Multiply(..) => NodeKind::Multiply,
+            Node::Divide(..) => NodeKind::Divide,
+            Node::Caret(..) => NodeKind::Caret,
+            Node::Negative(_) => NodeKind::Negative,
+            Node::Not(_) => NodeKind::Not,
+            Node::Number(n) => NodeKind::Number(*n),
+            Node::Constant(c) => NodeKind::Constant(*c),
+            Node::Variable(name) => NodeKind::Variable(name.clone()),
+            Node::Let(name, ..) => NodeKind::Let(name.clone()),
+            Node::Function(name, _) => NodeKind::Function(name.clone()),
+            Node::FlatAdd(_) => NodeKind::FlatAdd,
+            Node::FlatMul(_) => NodeKind::FlatMul,
+        }
+    }
+
+    // The node of this kind with `operands`, None if it takes a different number of them
+    fn with_operands(&self, operands: Vec<Node>) -> Option<Node> {
+        let unary = |make: fn(Box<Node>) -> Node, operands: Vec<Node>| {
+            <[Node; 1]>::try_from(operands).ok().map(|[a]| make(Box::new(a)))
+        };
+        let binary = |make: fn(Box<Node>, Box<Node>) -> Node, operands: Vec<Node>| {
+            <[Node; 2]>::try_from(operands).ok().map(|[a, b]| make(Box::new(a), Box::new(b)))
+        };
+        match self {
+            NodeKind::And => binary(Node::And, operands),
+            NodeKind::Or => binary(Node::Or, operands),
+            NodeKind::Add => binary(Node::Add, operands),
+            NodeKind::Subtract => binary(Node::Subtract, operands),
+            NodeKind::Multiply => binary(Node::Multiply, operands),
+            NodeKind::Divide => binary(Node::Divide, operands),
+            NodeKind::Caret => binary(Node::Caret, operands),
+            NodeKind::Negative => unary(Node::Negative, operands),
+            NodeKind::Not => unary(Node::Not, operands),
+            NodeKind::Number(n) => operands.is_empty().then_some(Node::Number(*n)),
+            NodeKind::Constant(c) => operands.is_empty().then_some(Node::Constant(*c)),
+            NodeKind::Variable(name) => operands.is_empty().then(|| Node::Variable(name.clone())),
+            NodeKind::Let(name) => <[Node; 2]>::try_from(operands)
+                .ok()
+                .map(|[value, body]| Node::Let(name.clone(), Box::new(value), Box::new(body))),
+            NodeKind::Function(name) => Some(Node::Function(name.clone(), operands)),
+            NodeKind::FlatAdd => Some(Node::FlatAdd(operands)),
+            NodeKind::FlatMul => Some(Node::FlatMul(operands)),
+        }
+    }
+}
+
+impl IdNode {
+    // Number `node` and its descendants in pre-order, starting from `next`. Walks with explicit
+    // stacks rather than recursion, so deep trees cannot overflow the call stack.
+    fn from_pre_order(node: &Node, next: &mut NodeId) -> Self {
+        let root = *next;
+        // Descendants in pre-order with their number of operands
+        let mut order = Vec::new();
+        let mut pending: Vec<&Node> = node.children().into_iter().rev().collect();
+        while let Some(descendant) = pending.pop() {
+            let children = descendant.children();
+            order.push((NodeKind::of(descendant), children.len()));
+            pending.extend(children.into_iter().rev());
+        }
+        next.0 += order.len() + 1;
+        // Built from the last, a node finds its operands on top of `built`, the last one first
+        let mut built: Vec<IdNode> = Vec::new();
+        for (index, (kind, count)) in order.into_iter().enumerate().rev() {
+            let mut children = built.split_off(built.len() - count);
+            children.reverse();
+            built.push(IdNode { id: NodeId(root.0 + 1 + index), kind, children });
+        }
+        built.reverse();
+        IdNode { id: root, kind: NodeKind::of(node), children: built }
+    }
+
+    // The nodes of the tree in pre-order
+    fn pre_order(&self) -> Vec<&IdNode> {
+        let mut order = Vec::new();
+        let mut pending = vec![self];
+        while let Some(id_node) = pending.pop() {
+            order.push(id_node);
+            pending.extend(id_node.children.iter().rev());
+        }
+        order
+    }
+
+    // The subtree with ID `id`, None if no node of this tree has it
+    #[must_use]
+    pub fn find(&self, id: NodeId) -> Option<&IdNode> {
+        self.pre_order().into_iter().find(|id_node| id_node.id == id)
+    }
+
+    // IDs of every node of the tree, in pre-order
+    #[must_use]
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.pre_order().into_iter().map(|id_node| id_node.id).collect()
+    }
+
+    // The tree without its IDs, None if some node has the wrong number of children for its kind
+    #[must_use]
+    pub fn to_node(&self) -> Option<Node> {
+        let mut built: Vec<Node> = Vec::new();
+        for id_node in self.pre_order().into_iter().rev() {
+            let mut operands = built.split_off(built.len() - id_node.children.len());
+            operands.reverse();
+            built.push(id_node.kind.with_operands(operands)?);
+        }
+        built.pop()
+    }
+}
+
+// Operator overloads for building trees, e.g. `Node::Number(2.0) + Node::Number(3.0)`
+impl ops::Add for Node {
+    type Output = Node;
+    fn add(self, rhs: Node) -> Node {
+        Node::Add(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Sub for Node {
+    type Output = Node;
+    fn sub(self, rhs: Node) -> Node {
+        Node::Subtract(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Mul for Node {
+    type Output = Node;
+    fn mul(self, rhs: Node) -> Node {
+        Node::Multiply(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Div for Node {
+    type Output = Node;
+    fn div(self, rhs: Node) -> Node {
+        Node::Divide(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::BitAnd for Node {
+    type Output = Node;
+    fn bitand(self, rhs: Node) -> Node {
+        Node::And(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::BitOr for Node {
+    type Output = Node;
+    fn bitor(self, rhs: Node) -> Node {
+        Node::Or(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl ops::Neg for Node {
+    type Output = Node;
+    fn neg(self) -> Node {
+        Node::Negative(Box::new(self))
+    }
+}
+
+// The value of a constant tree, so `let x: f64 = parse("2+3").unwrap().into()` works in
+// scripts. Panics with the evaluation error if the tree reads a variable or fails to evaluate;
+// `f64::try_from(&node)` returns the error instead.
+impl From<Node> for f64 {
+    fn from(node: Node) -> f64 {
+        eval(node).unwrap_or_else(|e| panic!("eval failed: {}", e))
+    }
+}
+
+// Evaluate a constant tree without panicking, see `From<Node> for f64`
+impl TryFrom<&Node> for f64 {
+    type Error = EvalError;
+
+    fn try_from(node: &Node) -> Result<f64, EvalError> {
+        eval_with_context(node, &EvalContext::new())
+    }
+}
+
+// A tree equals a number only when it is that Number leaf, so `assert_eq!(folded, 5.0)` checks
+// a fold without spelling out `Node::Number(5.0)`. Trees that would evaluate to it are not equal.
+impl PartialEq<f64> for Node {
+    fn eq(&self, other: &f64) -> bool {
+        matches!(self, Node::Number(n) if n == other)
+    }
+}
+
+impl PartialEq<Node> for f64 {
+    fn eq(&self, other: &Node) -> bool {
+        other == self
+    }
+}
+
+// Build a Node from Rust syntax: `expr!(2.0 + 3.0 * -x)` is the tree the parser gives for
+// `2+3*-x`. Number literals become Node::Number and must be f64, names become Node::Variable.
+// `& | + - * / % ^`, prefix `-` and `!` and postfix `!` group with the parser's precedence and
+// associativity, so `1.0 | 2.0 & 3.0` is `(1|2)&3` and `2.0 ^ 3.0 ^ 2.0` is `2^(3^2)`; calls and
+// `let` are left to the parser. The expansion names `Node` unqualified, so it must be imported
+// where the macro is used, and the macro keeps working wherever this module is mounted.
+//
+// Each level splits its tokens at its own operators and hands the pieces to the next tighter
+// level. Parentheses arrive as a single token tree, so every operator a level sees is at its
+// own nesting depth. `-` is binary only after an operand, which `@sum` tracks as `val`/`op`.
+#[macro_export]
+macro_rules! expr {
+    (@bitwise [$($lhs:tt)*] [$($cur:tt)+] & $($rest:tt)+) => {
+        $crate::expr!(
+            @bitwise [$crate::expr!(@join [$($lhs)*] $crate::expr!(@sum [] [] op $($cur)+)), &] [] $($rest)+
+        )
+    };
+    (@bitwise [$($lhs:tt)*] [$($cur:tt)+] | $($rest:tt)+) => {
+        $crate::expr!(
+            @bitwise [$crate::expr!(@join [$($lhs)*] $crate::expr!(@sum [] [] op $($cur)+)), |] [] $($rest)+
+        )
+    };
+    (@bitwise $lhs:tt [$($cur:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::expr!(@bitwise $lhs [$($cur)* $t] $($rest)*)
+    };
+    (@bitwise [$($lhs:tt)*] [$($cur:tt)+]) => {
+        $crate::expr!(@join [$($lhs)*] $crate::expr!(@sum [] [] op $($cur)+))
+    };
+
+    (@sum [$($lhs:tt)*] [$($cur:tt)+] val + $($rest:tt)+) => {
+        $crate::expr!(
+            @sum [$crate::expr!(@join [$($lhs)*] $crate::expr!(@product [] [] $($cur)+)), +] [] op $($rest)+
+        )
+    };
+    (@sum [$($lhs:tt)*] [$($cur:tt)+] val - $($rest:tt)+) => {
+        $crate::expr!(
+            @sum [$crate::expr!(@join [$($lhs)*] $crate::expr!(@product [] [] $($cur)+)), -] [] op $($rest)+
+        )
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident ! $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* !] $flag $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident - $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* -] op $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident * $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* *] op $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident / $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* /] op $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident % $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* %] op $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident ^ $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* ^] op $($rest)*)
+    };
+    (@sum $lhs:tt [$($cur:tt)*] $flag:ident $t:tt $($rest:tt)*) => {
+        $crate::expr!(@sum $lhs [$($cur)* $t] val $($rest)*)
+    };
+    (@sum [$($lhs:tt)*] [$($cur:tt)+] $flag:ident) => {
+        $crate::expr!(@join [$($lhs)*] $crate::expr!(@product [] [] $($cur)+))
+    };
+
+    (@product [$($lhs:tt)*] [$($cur:tt)+] * $($rest:tt)+) => {
+        $crate::expr!(
+            @product [$crate::expr!(@join [$($lhs)*] $crate::expr!(@power [] $($cur)+)), *] [] $($rest)+
+        )
+    };
+    (@product [$($lhs:tt)*] [$($cur:tt)+] / $($rest:tt)+) => {
+        $crate::expr!(
+            @product [$crate::expr!(@join [$($lhs)*] $crate::expr!(@power [] $($cur)+)), /] [] $($rest)+
+        )
+    };
+    (@product [$($lhs:tt)*] [$($cur:tt)+] % $($rest:tt)+) => {
+        $crate::expr!(
+            @product [$crate::expr!(@join [$($lhs)*] $crate::expr!(@power [] $($cur)+)), %] [] $($rest)+
+        )
+    };
+    (@product $lhs:tt [$($cur:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::expr!(@product $lhs [$($cur)* $t] $($rest)*)
+    };
+    (@product [$($lhs:tt)*] [$($cur:tt)+]) => {
+        $crate::expr!(@join [$($lhs)*] $crate::expr!(@power [] $($cur)+))
+    };
+
+    // `^` groups from the right, so the first one splits the operand from the rest
+    (@power [$($cur:tt)+] ^ $($rest:tt)+) => {
+        Node::Caret(Box::new($crate::expr!(@unary $($cur)+)), Box::new($crate::expr!(@power [] $($rest)+)))
+    };
+    (@power [$($cur:tt)*] $t:tt $($rest:tt)*) => { $crate::expr!(@power [$($cur)* $t] $($rest)*) };
+    (@power [$($cur:tt)+]) => { $crate::expr!(@unary $($cur)+) };
+
+    (@unary - $($rest:tt)+) => { Node::Negative(Box::new($crate::expr!(@unary $($rest)+))) };
+    (@unary ! $($rest:tt)+) => { Node::Not(Box::new($crate::expr!(@unary $($rest)+))) };
+    (@unary $primary:tt $($bangs:tt)*) => {
+        $crate::expr!(@postfix [$crate::expr!(@primary $primary)] $($bangs)*)
+    };
+    (@postfix [$node:expr] ! $($rest:tt)*) => {
+        $crate::expr!(@postfix [Node::Function("factorial".to_string(), vec![$node])] $($rest)*)
+    };
+    (@postfix [$node:expr]) => { $node };
+    (@primary ($($inner:tt)+)) => { $crate::expr!($($inner)+) };
+    (@primary $value:literal) => { Node::Number($value) };
+    (@primary $name:ident) => { Node::Variable(stringify!($name).to_string()) };
+
+    (@join [] $rhs:expr) => { $rhs };
+    (@join [$lhs:expr, &] $rhs:expr) => { Node::And(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, |] $rhs:expr) => { Node::Or(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, +] $rhs:expr) => { Node::Add(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, -] $rhs:expr) => { Node::Subtract(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, *] $rhs:expr) => { Node::Multiply(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, /] $rhs:expr) => { Node::Divide(Box::new($lhs), Box::new($rhs)) };
+    (@join [$lhs:expr, %] $rhs:expr) => { Node::Function("mod".to_string(), vec![$lhs, $rhs]) };
+
+    (@$level:ident $($rest:tt)*) => {
+        compile_error!(concat!("expr!: cannot build a tree from `", stringify!($($rest)*), "`"))
+    };
+    ($($tokens:tt)+) => { $crate::expr!(@bitwise [] [] $($tokens)+) };
+}
+
+// Largest integer magnitude an f64 holds exactly (2^53)
+pub const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+// Errors raised while evaluating an AST
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    DivisionByZero,
+    UnsafeIntegerPrecision(f64),
+    NaNResult,
+    UnknownVariable(String),
+    UnknownFunction(String),
+    WrongArgumentCount { name: String, expected: usize, found: usize },
+    NotDifferentiable(String),
+    ZeroDerivative,
+    ConvergenceFailed,
+    NoSignChange,
+    NonFiniteResult,
+    PrecisionExceeded { ulps: u64 },
+    InvalidExpression(String),
+    InvalidArgument(String),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::UnsafeIntegerPrecision(n) => {
+                write!(f, "Number {} exceeds the 2^53 safe integer range", n)
+            }
+            EvalError::NaNResult => write!(f, "Result is not a number"),
+            EvalError::UnknownVariable(name) => write!(f, "Unknown variable {}", name),
+            EvalError::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            EvalError::WrongArgumentCount { name, expected, found } => {
+                write!(f, "Function {} takes {} arguments, got {}", name, expected, found)
+            }
+            EvalError::NotDifferentiable(what) => write!(f, "Cannot differentiate {}", what),
+            EvalError::ZeroDerivative => write!(f, "Derivative is zero"),
+            EvalError::ConvergenceFailed => write!(f, "Iteration did not converge"),
+            EvalError::NonFiniteResult => write!(f, "Result is not a finite number"),
+            EvalError::PrecisionExceeded { ulps } => {
+                write!(f, "Rounding may have moved the result by up to {} ULPs", ulps)
+            }
+            EvalError::NoSignChange => write!(f, "Expression has the same sign at both ends of the interval"),
+            EvalError::InvalidExpression(e) => write!(f, "Invalid expression: {}", e),
+            EvalError::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
+        }
+    }
+}
+
+impl error::Error for EvalError {}
+
+#[must_use = "evaluation errors are only reported through the returned Result"]
+pub fn eval(expr: Node) -> Result<f64, EvalError> {
+    eval_counted(expr, &mut 0)
+}
+
+// Evaluate an AST, adding the number of nodes visited to `visited`
+#[must_use = "evaluation errors are only reported through the returned Result"]
+pub fn eval_counted(expr: Node, visited: &mut usize) -> Result<f64, EvalError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("eval", depth = depth(&expr)).entered();
+    eval_node(&expr, &EvalContext::new(), visited)
+}
+
+// Evaluate an AST without consuming it, looking up variables in `ctx`
+#[must_use = "evaluation errors are only reported through the returned Result"]
+pub fn eval_with_context(expr: &Node, ctx: &EvalContext) -> Result<f64, EvalError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("eval", depth = depth(expr)).entered();
+    eval_node(expr, ctx, &mut 0)
+}
+
+fn eval_node(expr: &Node, ctx: &EvalContext, visited: &mut usize) -> Result<f64, EvalError> {
+    use self::Node::*;
+    *visited += 1;
+    let mut eval = |node: &Node| eval_node(node, ctx, visited);
+    match expr {
+        Number(i) => Ok(*i),
+        Constant(c) => Ok(c.value()),
+        Variable(name) => ctx
+            .get_var(name)
+            .ok_or_else(|| EvalError::UnknownVariable(name.clone())),
+        Add(a, b) => Ok(eval(a)? + eval(b)?),
+        Subtract(a, b) => Ok(eval(a)? - eval(b)?),
+        Multiply(a, b) => Ok(eval(a)? * eval(b)?),
+        Divide(a, b) => {
+            let divisor = eval(b)?;
+            if divisor == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            Ok(eval(a)? / divisor)
+        }
+        Caret(a, b) => Ok(eval(a)?.powf(eval(b)?)),
+        Negative(a) => Ok(-eval(a)?),
+        Not(a) => Ok(if eval(a)? == 0.0 { 1.0 } else { 0.0 }),
+        And(a, b) => Ok((eval(a)? as i64 & eval(b)? as i64) as f64),
+        Or(a, b) => Ok((eval(a)? as i64 | eval(b)? as i64) as f64),
+        Let(name, value, body) => {
+            // The binding lives in a scope that shadows `ctx` and is dropped afterwards
+            let mut scope = ctx.clone();
+            scope.set_var(name, eval(value)?);
+            eval_node(body, &scope, visited)
+        }
+        Function(name, args) => {
+            let values = args.iter().map(&mut eval).collect::<Result<Vec<f64>, EvalError>>()?;
+            match (ctx.get_function(name), values.as_slice()) {
+                (Some(function), [x]) => Ok(function(*x)),
+                (Some(_), _) => Err(EvalError::WrongArgumentCount {
+                    name: name.clone(),
+                    expected: 1,
+                    found: values.len(),
+                }),
+                (None, _) => call_function(name, &values),
+            }
+        }
+        FlatAdd(items) | FlatMul(items) => {
+            let values = items.iter().map(&mut eval).collect::<Result<Vec<f64>, EvalError>>()?;
+            Ok(fold_flat(values, matches!(expr, FlatMul(_))))
+        }
+    }
+}
+
+// The left-leaning chain of Add or Multiply a FlatAdd or FlatMul stands for, e.g.
+// FlatAdd([a, b, c]) is (a + b) + c. Other nodes are returned unchanged.
+pub(crate) fn binary_chain(node: &Node) -> Node {
+    let (items, identity) = match node {
+        Node::FlatAdd(items) => (items, 0.0),
+        Node::FlatMul(items) => (items, 1.0),
+        _ => return node.clone(),
+    };
+    let combine = if matches!(node, Node::FlatAdd(_)) { Node::Add } else { Node::Multiply };
+    let mut items = items.iter().cloned();
+    let first = items.next().unwrap_or(Node::Number(identity));
+    items.fold(first, |chain, item| combine(Box::new(chain), Box::new(item)))
+}
+
+// Sum or product of the evaluated operands of a FlatAdd or FlatMul, combined in order
+fn fold_flat(values: Vec<f64>, multiply: bool) -> f64 {
+    let mut values = values.into_iter();
+    let first = values.next().unwrap_or(if multiply { 1.0 } else { 0.0 });
+    values.fold(first, |acc, value| if multiply { acc * value } else { acc + value })
+}
+
+// Collapse chains of Add into FlatAdd and chains of Multiply into FlatMul, so
+// 1 + 2 + 3 + 4 becomes FlatAdd([1, 2, 3, 4]). Operand order is kept.
+pub fn flatten(node: Node) -> Node {
+    use self::Node::*;
+    // Push the flattened operands of `node` onto `items`, splicing nested sums or products
+    fn splice(node: Node, multiply: bool, items: &mut Vec<Node>) {
+        match (flatten(node), multiply) {
+            (FlatAdd(inner), false) | (FlatMul(inner), true) => items.extend(inner),
+            (other, _) => items.push(other),
+        }
+    }
+    let multiply = matches!(node, Multiply(..) | FlatMul(_));
+    match node {
+        Add(a, b) | Multiply(a, b) => {
+            let mut items = Vec::new();
+            splice(*a, multiply, &mut items);
+            splice(*b, multiply, &mut items);
+            if multiply {
+                FlatMul(items)
+            } else {
+                FlatAdd(items)
+            }
+        }
+        FlatAdd(items) | FlatMul(items) => {
+            let mut flat = Vec::new();
+            items.into_iter().for_each(|item| splice(item, multiply, &mut flat));
+            if multiply {
+                FlatMul(flat)
+            } else {
+                FlatAdd(flat)
+            }
+        }
+        And(a, b) => And(Box::new(flatten(*a)), Box::new(flatten(*b))),
+        Or(a, b) => Or(Box::new(flatten(*a)), Box::new(flatten(*b))),
+        Subtract(a, b) => Subtract(Box::new(flatten(*a)), Box::new(flatten(*b))),
+        Divide(a, b) => Divide(Box::new(flatten(*a)), Box::new(flatten(*b))),
+        Caret(a, b) => Caret(Box::new(flatten(*a)), Box::new(flatten(*b))),
+        Negative(a) => Negative(Box::new(flatten(*a))),
+        Not(a) => Not(Box::new(flatten(*a))),
+        Let(name, value, body) => Let(name, Box::new(flatten(*value)), Box::new(flatten(*body))),
+        Function(name, args) => Function(name, args.into_iter().map(flatten).collect()),
+        other => other,
+    }
+}
+
+// Apply the built-in function `name` to already evaluated arguments
+fn call_function(name: &str, args: &[f64]) -> Result<f64, EvalError> {
+    let unary: Option<fn(f64) -> f64> = match name {
+        "sin" => Some(f64::sin),
+        "cos" => Some(f64::cos),
+        "tan" => Some(f64::tan),
+        "sqrt" => Some(f64::sqrt),
+        "abs" => Some(f64::abs),
+        "exp" => Some(f64::exp),
+        "ln" => Some(f64::ln),
+        _ => None,
+    };
+    if let Some(function) = unary {
+        return match args {
+            [x] => Ok(function(*x)),
+            _ => Err(EvalError::WrongArgumentCount { name: name.to_string(), expected: 1, found: args.len() }),
+        };
+    }
+    // `mod` is the Euclidean remainder, never negative: mod(-7, 3) is 2 and mod(7, -3) is 1.
+    // `rem` truncates the quotient, so the sign follows the dividend: rem(-7, 3) is -1.
+    let binary: Option<fn(f64, f64) -> f64> = match name {
+        "mod" => Some(f64::rem_euclid),
+        "rem" => Some(|a, b| a % b),
+        _ => None,
+    };
+    if let Some(function) = binary {
+        return match args {
+            [_, b] if *b == 0.0 => Err(EvalError::DivisionByZero),
+            [a, b] => Ok(function(*a, *b)),
+            _ => Err(EvalError::WrongArgumentCount { name: name.to_string(), expected: 2, found: args.len() }),
+        };
+    }
+    if name == "factorial" {
+        return match args {
+            [n] if *n >= 0.0 && n.fract() == 0.0 => Ok((2..=(*n).min(171.0) as u32).map(f64::from).product()),
+            [n] => Err(EvalError::InvalidArgument(format!("factorial needs a non-negative integer, got {}", n))),
+            _ => Err(EvalError::WrongArgumentCount { name: name.to_string(), expected: 1, found: args.len() }),
+        };
+    }
+    let fold: fn(f64, f64) -> f64 = match name {
+        "min" => f64::min,
+        "max" => f64::max,
+        _ => return Err(EvalError::UnknownFunction(name.to_string())),
+    };
+    match args.split_first() {
+        Some((first, rest)) => Ok(rest.iter().copied().fold(*first, fold)),
+        None => Err(EvalError::WrongArgumentCount { name: name.to_string(), expected: 1, found: 0 }),
+    }
+}
+
+// Evaluate an AST entirely in single precision, every number being cast to f32 first.
+// Rounding happens at every step, so the result can differ from `eval` by more than the
+// f32 rounding of its result: `1/3` is 0.33333334, not 0.3333333333333333.
+#[must_use = "evaluation errors are only reported through the returned Result"]
+pub fn eval_f32(expr: Node) -> Result<f32, EvalError> {
+    eval_f32_node(&expr, &mut Vec::new())
+}
+
+// `scope` holds the let bindings in force, innermost last
+fn eval_f32_node<'a>(expr: &'a Node, scope: &mut Vec<(&'a str, f32)>) -> Result<f32, EvalError> {
+    use self::Node::*;
+    let mut eval = |node: &'a Node| eval_f32_node(node, scope);
+    match expr {
+        Number(i) => Ok(*i as f32),
+        Constant(c) => Ok(c.value() as f32),
+        Variable(name) => scope
+            .iter()
+            .rev()
+            .find(|(bound, _)| bound == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| EvalError::UnknownVariable(name.clone())),
+        Add(a, b) => Ok(eval(a)? + eval(b)?),
+        Subtract(a, b) => Ok(eval(a)? - eval(b)?),
+        Multiply(a, b) => Ok(eval(a)? * eval(b)?),
+        Divide(a, b) => {
+            let divisor = eval(b)?;
+            if divisor == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            Ok(eval(a)? / divisor)
+        }
+        Caret(a, b) => Ok(eval(a)?.powf(eval(b)?)),
+        Negative(a) => Ok(-eval(a)?),
+        Not(a) => Ok(if eval(a)? == 0.0 { 1.0 } else { 0.0 }),
+        And(a, b) => Ok((eval(a)? as i64 & eval(b)? as i64) as f32),
+        Or(a, b) => Ok((eval(a)? as i64 | eval(b)? as i64) as f32),
+        Let(name, value, body) => {
+            let value = eval(value)?;
+            scope.push((name, value));
+            let result = eval_f32_node(body, scope);
+            scope.pop();
+            result
+        }
+        Function(name, args) => {
+            let values = args.iter().map(&mut eval).collect::<Result<Vec<f32>, EvalError>>()?;
+            call_function_f32(name, &values)
+        }
+        FlatAdd(items) | FlatMul(items) => {
+            let values = items.iter().map(&mut eval).collect::<Result<Vec<f32>, EvalError>>()?;
+            let multiply = matches!(expr, FlatMul(_));
+            let mut values = values.into_iter();
+            let first = values.next().unwrap_or(if multiply { 1.0 } else { 0.0 });
+            Ok(values.fold(first, |acc, value| if multiply { acc * value } else { acc + value }))
+        }
+    }
+}
+
+// `call_function` in single precision: the arguments are widened, the function is computed by
+// `call_function` and its result rounded to f32, so both share one table of built-in functions
+fn call_function_f32(name: &str, args: &[f32]) -> Result<f32, EvalError> {
+    let wide: Vec<f64> = args.iter().map(|&arg| f64::from(arg)).collect();
+    call_function(name, &wide).map(|result| result as f32)
+}
+
+// Subtrees at most this deep are evaluated sequentially by eval_parallel
+#[cfg(feature = "rayon")]
+pub const PARALLEL_DEPTH_THRESHOLD: usize = 10;
+
+// Evaluate an AST, computing the two sides of deep binary nodes concurrently with rayon.
+// The result is identical to `eval` since every node performs the same operation.
+#[cfg(feature = "rayon")]
+pub fn eval_parallel(expr: Node) -> Result<f64, EvalError> {
+    let max_depth = depth(&expr);
+    eval_parallel_node(expr, max_depth)
+}
+
+// `max_depth` is an upper bound on the depth of `expr`, found once at the root
+#[cfg(feature = "rayon")]
+fn eval_parallel_node(expr: Node, max_depth: usize) -> Result<f64, EvalError> {
+    use self::Node::*;
+    if max_depth <= PARALLEL_DEPTH_THRESHOLD {
+        return eval_node(&expr, &EvalContext::new(), &mut 0);
+    }
+    let join = |a: Box<Node>, b: Box<Node>| {
+        rayon::join(
+            || eval_parallel_node(*a, max_depth - 1),
+            || eval_parallel_node(*b, max_depth - 1),
+        )
+    };
+    match expr {
+        Number(i) => Ok(i),
+        Constant(c) => Ok(c.value()),
+        Variable(name) => Err(EvalError::UnknownVariable(name)),
+        Let(..) | Function(..) | Not(_) | FlatAdd(_) | FlatMul(_) => {
+            eval_node(&expr, &EvalContext::new(), &mut 0)
+        }
+        Negative(a) => Ok(-eval_parallel_node(*a, max_depth - 1)?),
+        Add(a, b) => {
+            let (x, y) = join(a, b);
+            Ok(x? + y?)
+        }
+        Subtract(a, b) => {
+            let (x, y) = join(a, b);
+            Ok(x? - y?)
+        }
+        Multiply(a, b) => {
+            let (x, y) = join(a, b);
+            Ok(x? * y?)
+        }
+        Divide(a, b) => {
+            let (x, y) = join(a, b);
+            let divisor = y?;
+            if divisor == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            Ok(x? / divisor)
+        }
+        Caret(a, b) => {
+            let (x, y) = join(a, b);
+            Ok(x?.powf(y?))
+        }
+        And(a, b) => {
+            let (x, y) = join(a, b);
+            Ok((x? as i64 & y? as i64) as f64)
+        }
+        Or(a, b) => {
+            let (x, y) = join(a, b);
+            Ok((x? as i64 | y? as i64) as f64)
+        }
+    }
+}
+
+// Total number of nodes in the tree
+#[must_use]
+#[inline]
+pub fn count_nodes(node: &Node) -> usize {
+    use self::Node::*;
+    match node {
+        Number(_) | Constant(_) | Variable(_) => 1,
+        Negative(a) | Not(a) => 1 + count_nodes(a),
+        Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+        | Or(a, b) | Let(_, a, b) => 1 + count_nodes(a) + count_nodes(b),
+        Function(_, args) | FlatAdd(args) | FlatMul(args) => 1 + args.iter().map(count_nodes).sum::<usize>(),
+    }
+}
+
+// How many times each kind of node occurs, keyed by operator name such as "add" or "power".
+// Numbers and variables are both counted as "literal". Walks the tree with an explicit stack,
+// so deep trees cannot overflow the call stack.
+#[must_use]
+pub fn count_operator_frequency(node: &Node) -> HashMap<&'static str, usize> {
+    use self::Node::*;
+    let mut counts = HashMap::new();
+    let mut pending = vec![node];
+    while let Some(node) = pending.pop() {
+        let name = match node {
+            Number(_) | Constant(_) | Variable(_) => "literal",
+            Add(..) => "add",
+            Subtract(..) => "subtract",
+            Multiply(..) => "multiply",
+            Divide(..) => "divide",
+            Caret(..) => "power",
+            Negative(_) => "negative",
+            Not(_) => "not",
+            And(..) => "and",
+            Or(..) => "or",
+            Let(..) => "let",
+            Function(..) => "function",
+            FlatAdd(_) => "flat_add",
+            FlatMul(_) => "flat_mul",
+        };
+        *counts.entry(name).or_insert(0) += 1;
+        pending.extend(node.children());
+    }
+    counts
+}
+
+// Number of levels in the tree, a lone number has depth 1
+#[must_use]
+#[inline]
+pub fn depth(node: &Node) -> usize {
+    use self::Node::*;
+    match node {
+        Number(_) | Constant(_) | Variable(_) => 1,
+        Negative(a) | Not(a) => 1 + depth(a),
+        Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+        | Or(a, b) | Let(_, a, b) => 1 + depth(a).max(depth(b)),
+        Function(_, args) | FlatAdd(args) | FlatMul(args) => 1 + args.iter().map(depth).max().unwrap_or(0),
+    }
+}
+
+// Evaluate an AST through a mutable reference, replacing every subtree that
+// evaluates successfully with its `Number` result. A fully constant tree is left
+// as a single `Number`; otherwise both sides of each operator are still folded as
+// far as possible, leaving variables (and failing subtrees) in place.
+pub fn eval_annotate(node: &mut Node) -> Result<f64, EvalError> {
+    use self::Node::*;
+    // Fold both operands before reporting an error from either
+    fn both(a: &mut Node, b: &mut Node) -> Result<(f64, f64), EvalError> {
+        let (x, y) = (eval_annotate(a), eval_annotate(b));
+        Ok((x?, y?))
+    }
+    let value = match node {
+        Number(i) => return Ok(*i),
+        Constant(c) => return Ok(c.value()),
+        Variable(name) => return Err(EvalError::UnknownVariable(name.clone())),
+        Add(a, b) => both(a, b).map(|(x, y)| x + y)?,
+        Subtract(a, b) => both(a, b).map(|(x, y)| x - y)?,
+        Multiply(a, b) => both(a, b).map(|(x, y)| x * y)?,
+        Divide(a, b) => {
+            let (x, divisor) = both(a, b)?;
+            if divisor == 0.0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            x / divisor
+        }
+        Caret(a, b) => both(a, b).map(|(x, y)| x.powf(y))?,
+        Negative(a) => -eval_annotate(a)?,
+        Not(a) => {
+            if eval_annotate(a)? == 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        And(a, b) => both(a, b).map(|(x, y)| (x as i64 & y as i64) as f64)?,
+        Or(a, b) => both(a, b).map(|(x, y)| (x as i64 | y as i64) as f64)?,
+        // The body refers to the bound name, so only the value is folded in place
+        Let(name, value, body) => {
+            let mut scope = EvalContext::new();
+            scope.set_var(name, eval_annotate(value)?);
+            eval_node(body, &scope, &mut 0)?
+        }
+        Function(name, args) => {
+            // Fold every argument before reporting an error from any of them
+            let values: Vec<_> = args.iter_mut().map(eval_annotate).collect();
+            let values = values.into_iter().collect::<Result<Vec<f64>, EvalError>>()?;
+            call_function(name, &values)?
+        }
+        FlatAdd(items) | FlatMul(items) => {
+            let values: Vec<_> = items.iter_mut().map(eval_annotate).collect();
+            let values = values.into_iter().collect::<Result<Vec<f64>, EvalError>>()?;
+            fold_flat(values, matches!(node, FlatMul(_)))
+        }
+    };
+    *node = Number(value);
+    Ok(value)
+}
+
+// Evaluate `node` at `steps` evenly spaced values of `var` from `start` to `end`, both
+// included, returning `(x, f(x))` pairs for plotting. Needs at least 2 steps and
+// `start <= end`. A NaN or infinite f(x) is a NonFiniteResult error, see `eval_range_lenient`.
+pub fn eval_range(
+    node: &Node,
+    var: &str,
+    start: f64,
+    end: f64,
+    steps: usize,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    sample_range(node, var, start, end, steps, true)
+}
+
+// Like `eval_range`, but NaN and infinite values of f(x) are returned as they are, e.g. to
+// leave gaps in a plot of `1/x` rather than failing
+pub fn eval_range_lenient(
+    node: &Node,
+    var: &str,
+    start: f64,
+    end: f64,
+    steps: usize,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    sample_range(node, var, start, end, steps, false)
+}
+
+fn sample_range(
+    node: &Node,
+    var: &str,
+    start: f64,
+    end: f64,
+    steps: usize,
+    strict: bool,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    if steps < 2 {
+        return Err(EvalError::InvalidArgument(format!("a range needs at least 2 steps, got {}", steps)));
+    }
+    if start.is_nan() || end.is_nan() || start > end {
+        return Err(EvalError::InvalidArgument(format!("range start {} must not be after its end {}", start, end)));
+    }
+    let mut ctx = EvalContext::new();
+    (0..steps)
+        .map(|i| {
+            // The last step is `end` exactly, whatever the rounding of the step width
+            let x = if i == steps - 1 { end } else { start + (end - start) * i as f64 / (steps - 1) as f64 };
+            ctx.set_var(var, x);
+            let y = eval_with_context(node, &ctx)?;
+            if strict && !y.is_finite() {
+                return Err(EvalError::NonFiniteResult);
+            }
+            Ok((x, y))
+        })
+        .collect()
+}
+
+// Evaluate an AST, applying the evaluation checks enabled in `config`
+pub fn eval_with_config(expr: Node, config: &ParseConfig) -> Result<f64, EvalError> {
+    if config.strict_integer_precision {
+        if let Some(&n) = check_integer_safety(&expr).first() {
+            return Err(EvalError::UnsafeIntegerPrecision(n));
+        }
+    }
+    let result = eval(expr)?;
+    if config.strict_nan && result.is_nan() {
+        return Err(EvalError::NaNResult);
+    }
+    Ok(result)
+}
+
+// Evaluate an AST and check how far rounding could have moved the result. The tree is
+// evaluated once more with every +, -, * and / rounded down and once with them rounded up,
+// which is emulated by working out the sign of each operation's rounding error. The result
+// is accepted when both ends lie within `ulp_tolerance` ULPs of it, otherwise the error
+// reports the larger distance. Powers and built-in functions are assumed to be accurate to
+// one ULP and monotonic between the bounds of their operands.
+// A tolerance of zero skips the check, so this is then the same as `eval`; otherwise a NaN
+// or infinite result is a NonFiniteResult error.
+pub fn eval_with_precision(node: Node, ulp_tolerance: u64) -> Result<f64, EvalError> {
+    if ulp_tolerance == 0 {
+        return eval(node);
+    }
+    let bounds = eval_bounds(&node, &mut Vec::new())?;
+    if !bounds.mid.is_finite() {
+        return Err(EvalError::NonFiniteResult);
+    }
+    let ulps = ulp_distance(bounds.lo, bounds.mid).max(ulp_distance(bounds.mid, bounds.hi));
+    if ulps > ulp_tolerance {
+        return Err(EvalError::PrecisionExceeded { ulps });
+    }
+    Ok(bounds.mid)
+}
+
+// A value as `eval` computes it, between the results of rounding everything down and up
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    lo: f64,
+    mid: f64,
+    hi: f64,
+}
+
+impl Bounds {
+    fn exact(value: f64) -> Self {
+        Bounds { lo: value, mid: value, hi: value }
+    }
+}
+
+// Number of representable f64 values from `a` to `b`, saturating for NaN or infinite ends
+fn ulp_distance(a: f64, b: f64) -> u64 {
+    if !a.is_finite() || !b.is_finite() {
+        return u64::MAX;
+    }
+    // Map the bits onto a line ordered like the values, with both zeros at 0
+    let ordered = |x: f64| {
+        let bits = x.to_bits() as i64;
+        if bits < 0 { -(bits & i64::MAX) } else { bits }
+    };
+    (ordered(a) as i128 - ordered(b) as i128).unsigned_abs() as u64
+}
+
+// Round `value` down and up, given the sign of the exact result minus `value`
+fn directed(value: f64, error: f64) -> (f64, f64) {
+    if !value.is_finite() || error == 0.0 {
+        (value, value)
+    } else if error > 0.0 {
+        (value, value.next_up())
+    } else {
+        (value.next_down(), value)
+    }
+}
+
+// Interval version of `eval_node`, the let bindings in scope are kept in `scope`
+fn eval_bounds(node: &Node, scope: &mut Vec<(String, Bounds)>) -> Result<Bounds, EvalError> {
+    use self::Node::*;
+    match node {
+        Number(n) => Ok(Bounds::exact(*n)),
+        Constant(c) => Ok(Bounds::exact(c.value())),
+        FlatAdd(_) | FlatMul(_) => eval_bounds(&binary_chain(node), scope),
+        Variable(name) => match scope.iter().rev().find(|(bound, _)| bound == name) {
+            Some((_, bounds)) => Ok(*bounds),
+            None => Err(EvalError::UnknownVariable(name.clone())),
+        },
+        Negative(a) => {
+            let a = eval_bounds(a, scope)?;
+            Ok(Bounds { lo: -a.hi, mid: -a.mid, hi: -a.lo })
+        }
+        Let(name, value, body) => {
+            let value = eval_bounds(value, scope)?;
+            scope.push((name.clone(), value));
+            let result = eval_bounds(body, scope);
+            scope.pop();
+            result
+        }
+        Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) => {
+            let (a, b) = (eval_bounds(a, scope)?, eval_bounds(b, scope)?);
+            // Each operation paired with the sign of its rounding error, found exactly
+            let op: fn(f64, f64) -> (f64, f64) = match node {
+                Add(..) => |x, y| {
+                    let sum = x + y;
+                    let y_part = sum - x;
+                    (sum, (x - (sum - y_part)) + (y - y_part))
+                },
+                Subtract(..) => |x, y| {
+                    let diff = x - y;
+                    let y_part = diff - x;
+                    (diff, (x - (diff - y_part)) - (y + y_part))
+                },
+                Multiply(..) => |x, y| {
+                    let product = x * y;
+                    (product, x.mul_add(y, -product))
+                },
+                _ => |x, y| {
+                    let quotient = x / y;
+                    (quotient, (-quotient).mul_add(y, x) * y.signum())
+                },
+            };
+            if matches!(node, Divide(..)) {
+                if b.mid == 0.0 {
+                    return Err(EvalError::DivisionByZero);
+                }
+                if b.lo <= 0.0 && b.hi >= 0.0 {
+                    // Rounding could make the divisor zero, so the quotient is unbounded
+                    return Ok(Bounds { lo: f64::NEG_INFINITY, mid: a.mid / b.mid, hi: f64::INFINITY });
+                }
+            }
+            let mut bounds = Bounds { lo: f64::INFINITY, mid: op(a.mid, b.mid).0, hi: f64::NEG_INFINITY };
+            for (x, y) in [(a.lo, b.lo), (a.lo, b.hi), (a.hi, b.lo), (a.hi, b.hi)] {
+                let (value, error) = op(x, y);
+                let (down, up) = directed(value, error);
+                bounds.lo = bounds.lo.min(down);
+                bounds.hi = bounds.hi.max(up);
+            }
+            Ok(bounds)
+        }
+        Caret(..) | Not(_) | And(..) | Or(..) | Function(..) => {
+            let args = node
+                .children()
+                .into_iter()
+                .map(|child| eval_bounds(child, scope))
+                .collect::<Result<Vec<Bounds>, EvalError>>()?;
+            let apply = |values: &[f64]| -> Result<f64, EvalError> {
+                match node {
+                    Caret(..) => Ok(values[0].powf(values[1])),
+                    Not(_) => Ok(if values[0] == 0.0 { 1.0 } else { 0.0 }),
+                    And(..) => Ok((values[0] as i64 & values[1] as i64) as f64),
+                    Or(..) => Ok((values[0] as i64 | values[1] as i64) as f64),
+                    Function(name, _) => call_function(name, values),
+                    _ => unreachable!("only operations listed above reach here"),
+                }
+            };
+            let mid = apply(&args.iter().map(|b| b.mid).collect::<Vec<f64>>())?;
+            let mut bounds = Bounds { lo: mid, mid, hi: mid };
+            // Try every combination of lower and upper operand bounds, or for long argument
+            // lists only all lower and all upper bounds
+            let corners: Vec<usize> = if args.len() <= 3 {
+                (0..1 << args.len()).collect()
+            } else {
+                vec![0, usize::MAX]
+            };
+            for corner in corners {
+                let values: Vec<f64> = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| if corner >> i.min(63) & 1 == 1 { b.hi } else { b.lo })
+                    .collect();
+                match apply(&values) {
+                    Ok(value) if !value.is_nan() => {
+                        bounds.lo = bounds.lo.min(value);
+                        bounds.hi = bounds.hi.max(value);
+                    }
+                    _ => return Ok(Bounds { lo: f64::NEG_INFINITY, mid, hi: f64::INFINITY }),
+                }
+            }
+            if matches!(node, Caret(..) | Function(..)) {
+                bounds.lo = bounds.lo.next_down();
+                bounds.hi = bounds.hi.next_up();
+            }
+            Ok(bounds)
+        }
+    }
+}
+
+// An upper bound on `node` for every value of its variables in the inclusive ranges
+// `var_bounds`, by interval arithmetic: `x^2` with x in [-1, 2] is at most 4. The bound can be
+// loose, e.g. `x - x` with x in [0, 1] gives 1, and ignores rounding in the last digit. None
+// when an interval is unbounded, such as `1/x` with x in [-1, 1], reads a variable without a
+// range, or uses an operation without interval rules (`&`, `|`, fractional powers of ranges
+// reaching below zero, functions other than sqrt, abs, exp, ln, sin, cos, min and max).
+#[must_use]
+pub fn max_value_upper_bound(node: &Node, var_bounds: &HashMap<&str, (f64, f64)>) -> Option<f64> {
+    interval(node, var_bounds, &mut Vec::new()).map(|(_, hi)| hi)
+}
+
+// Range `(lo, hi)` of `node`, with the let bindings in force in `scope`
+fn interval<'a>(
+    node: &'a Node,
+    vars: &HashMap<&str, (f64, f64)>,
+    scope: &mut Vec<(&'a str, (f64, f64))>,
+) -> Option<(f64, f64)> {
+    use self::Node::*;
+    // The smallest range holding every value of `values`, None if one is NaN or infinite
+    fn hull(values: &[f64]) -> Option<(f64, f64)> {
+        if !values.iter().all(|value| value.is_finite()) {
+            return None;
+        }
+        let lo = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let hi = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        Some((lo, hi))
+    }
+    let range = match node {
+        Number(n) => (*n, *n),
+        Constant(c) => (c.value(), c.value()),
+        Variable(name) => match scope.iter().rev().find(|(bound, _)| bound == name) {
+            Some((_, range)) => *range,
+            None => *vars.get(name.as_str())?,
+        },
+        FlatAdd(items) | FlatMul(items) => {
+            let multiply = matches!(node, FlatMul(_));
+            let mut range = if multiply { (1.0, 1.0) } else { (0.0, 0.0) };
+            for item in items {
+                let ((a_lo, a_hi), (b_lo, b_hi)) = (range, interval(item, vars, scope)?);
+                range = if multiply {
+                    hull(&[a_lo * b_lo, a_lo * b_hi, a_hi * b_lo, a_hi * b_hi])?
+                } else {
+                    hull(&[a_lo + b_lo, a_hi + b_hi])?
+                };
+            }
+            range
+        }
+        Let(name, value, body) => {
+            let value = interval(value, vars, scope)?;
+            scope.push((name, value));
+            let body = interval(body, vars, scope);
+            scope.pop();
+            body?
+        }
+        Negative(a) => {
+            let (lo, hi) = interval(a, vars, scope)?;
+            (-hi, -lo)
+        }
+        Not(_) => (0.0, 1.0),
+        Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) => {
+            let ((a_lo, a_hi), (b_lo, b_hi)) = (interval(a, vars, scope)?, interval(b, vars, scope)?);
+            match node {
+                Add(..) => hull(&[a_lo + b_lo, a_hi + b_hi])?,
+                Subtract(..) => hull(&[a_lo - b_hi, a_hi - b_lo])?,
+                Multiply(..) => hull(&[a_lo * b_lo, a_lo * b_hi, a_hi * b_lo, a_hi * b_hi])?,
+                Divide(..) if b_lo <= 0.0 && b_hi >= 0.0 => return None,
+                Divide(..) => hull(&[a_lo / b_lo, a_lo / b_hi, a_hi / b_lo, a_hi / b_hi])?,
+                // A whole power is monotonic on either side of 0, and even ones are lowest at 0
+                _ if b_lo == b_hi && b_lo.fract() == 0.0 && b_lo >= 0.0 => {
+                    let (lo, hi) = hull(&[a_lo.powf(b_lo), a_hi.powf(b_lo)])?;
+                    let even = b_lo % 2.0 == 0.0 && b_lo > 0.0;
+                    if even && a_lo < 0.0 && a_hi > 0.0 {
+                        (0.0, hi)
+                    } else {
+                        (lo, hi)
+                    }
+                }
+                // With a positive base, x^y is monotonic in each operand
+                _ if a_lo > 0.0 => {
+                    hull(&[a_lo.powf(b_lo), a_lo.powf(b_hi), a_hi.powf(b_lo), a_hi.powf(b_hi)])?
+                }
+                _ => return None,
+            }
+        }
+        Function(name, args) => {
+            let args = args.iter().map(|arg| interval(arg, vars, scope)).collect::<Option<Vec<_>>>()?;
+            match (name.as_str(), args.as_slice()) {
+                ("sqrt", [(lo, hi)]) if *lo >= 0.0 => (lo.sqrt(), hi.sqrt()),
+                ("ln", [(lo, hi)]) if *lo > 0.0 => hull(&[lo.ln(), hi.ln()])?,
+                ("exp", [(lo, hi)]) => hull(&[lo.exp(), hi.exp()])?,
+                ("abs", [(lo, hi)]) if *lo >= 0.0 => (*lo, *hi),
+                ("abs", [(lo, hi)]) if *hi <= 0.0 => (-hi, -lo),
+                ("abs", [(lo, hi)]) => (0.0, hi.max(-lo)),
+                ("sin" | "cos", [_]) => (-1.0, 1.0),
+                ("min" | "max", [first, rest @ ..]) => {
+                    let pick = if name == "min" { f64::min } else { f64::max };
+                    rest.iter().fold(*first, |(lo, hi), (a, b)| (pick(lo, *a), pick(hi, *b)))
+                }
+                _ => return None,
+            }
+        }
+        And(..) | Or(..) => return None,
+    };
+    hull(&[range.0, range.1])
+}
+
+// Evaluate an AST exactly as a fraction `(numerator, denominator)` in lowest terms with a
+// positive denominator. Literals are read from their shortest decimal form, so `0.1` is 1/10.
+// None when a step leaves the rationals (a fractional power, division by zero, an unbound variable)
+// or a numerator or denominator overflows i64.
+#[must_use]
+pub fn eval_rational(node: &Node) -> Option<(i64, i64)> {
+    fn reduce(num: i128, den: i128) -> Option<(i64, i64)> {
+        if den == 0 {
+            return None;
+        }
+        let (mut a, mut b) = (num.unsigned_abs(), den.unsigned_abs());
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        let divisor = a.max(1) as i128;
+        let sign = if den < 0 { -1 } else { 1 };
+        let num = i64::try_from(sign * num / divisor).ok()?;
+        let den = i64::try_from(sign * den / divisor).ok()?;
+        Some((num, den))
+    }
+    fn literal(n: f64) -> Option<(i64, i64)> {
+        if !n.is_finite() {
+            return None;
+        }
+        let text = n.abs().to_string();
+        let (int_part, frac_part) = text.split_once('.').unwrap_or((&text, ""));
+        let digits: i128 = format!("{}{}", int_part, frac_part).parse().ok()?;
+        let den = 10i128.checked_pow(frac_part.len() as u32)?;
+        reduce(if n < 0.0 { -digits } else { digits }, den)
+    }
+    fn power(base: (i64, i64), exponent: (i64, i64)) -> Option<(i64, i64)> {
+        if exponent.1 != 1 {
+            return None;
+        }
+        // checked_pow squares, so this takes O(log times) steps. Every base but 0, 1 and -1
+        // overflows i64 by times = 63, so that is all an exponent can reach.
+        let times = u32::try_from(exponent.0.unsigned_abs()).ok()?;
+        let (mut num, mut den) = (base.0.checked_pow(times)?, base.1.checked_pow(times)?);
+        if exponent.0 < 0 {
+            (num, den) = (den, num);
+        }
+        reduce(num as i128, den as i128)
+    }
+    // `scope` holds the let bindings in force, innermost last
+    fn walk<'a>(node: &'a Node, scope: &mut Vec<(&'a str, (i64, i64))>) -> Option<(i64, i64)> {
+        use self::Node::*;
+        let (a, b) = match node {
+            Number(n) => return literal(*n),
+            Constant(_) => return None,
+            Variable(name) => return scope.iter().rev().find(|(bound, _)| bound == name).map(|(_, v)| *v),
+            Function(..) => return None,
+            FlatAdd(items) | FlatMul(items) => {
+                let multiply = matches!(node, FlatMul(_));
+                return items.iter().try_fold((if multiply { 1 } else { 0 }, 1), |(an, ad), item| {
+                    let (bn, bd) = walk(item, scope)?;
+                    let (an, ad, bn, bd) = (an as i128, ad as i128, bn as i128, bd as i128);
+                    if multiply {
+                        reduce(an * bn, ad * bd)
+                    } else {
+                        reduce(an * bd + bn * ad, ad * bd)
+                    }
+                });
+            }
+            Negative(a) => {
+                let (num, den) = walk(a, scope)?;
+                return reduce(-(num as i128), den as i128);
+            }
+            Not(a) => return Some((if walk(a, scope)?.0 == 0 { 1 } else { 0 }, 1)),
+            Let(name, value, body) => {
+                let value = walk(value, scope)?;
+                scope.push((name, value));
+                let result = walk(body, scope);
+                scope.pop();
+                return result;
+            }
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+            | Or(a, b) => (walk(a, scope)?, walk(b, scope)?),
+        };
+        let ((an, ad), (bn, bd)) = ((a.0 as i128, a.1 as i128), (b.0 as i128, b.1 as i128));
+        match node {
+            Add(..) => reduce(an * bd + bn * ad, ad * bd),
+            Subtract(..) => reduce(an * bd - bn * ad, ad * bd),
+            Multiply(..) => reduce(an * bn, ad * bd),
+            Divide(..) => reduce(an * bd, ad * bn),
+            Caret(..) => power(a, b),
+            // Bitwise operators work on the truncated integer parts, as in `eval`
+            And(..) => reduce((an / ad) & (bn / bd), 1),
+            _ => reduce((an / ad) | (bn / bd), 1),
+        }
+    }
+    walk(node, &mut Vec::new())
+}
+
+// Evaluate an AST as a fraction `(numerator, denominator)` of trees with integer coefficients,
+// e.g. `x/2 + 1/3` becomes `(x * 3 + 2, 6)`. Whenever both parts are plain integers they are
+// reduced by their GCD with a positive denominator, so a constant tree becomes two Numbers in
+// lowest terms. Trees this cannot keep exact, such as non-integer literals, let bindings,
+// function calls, logical operators or numbers beyond 2^53, are evaluated as floating point
+// instead, giving `(value, 1)`.
+pub fn eval_symbolic_fraction(node: Node) -> Result<(Node, Node), EvalError> {
+    type Fraction = (Node, Node);
+    fn constant(node: &Node) -> Option<i128> {
+        match node {
+            Node::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => Some(*n as i128),
+            _ => None,
+        }
+    }
+    fn integer(n: i128) -> Option<Node> {
+        (n.unsigned_abs() as f64 <= MAX_SAFE_INTEGER).then_some(Node::Number(n as f64))
+    }
+    fn mul(a: Node, b: Node) -> Option<Node> {
+        match (constant(&a), constant(&b)) {
+            (Some(x), Some(y)) => integer(x * y),
+            (Some(1), _) => Some(b),
+            (_, Some(1)) => Some(a),
+            _ => Some(Node::Multiply(Box::new(a), Box::new(b))),
+        }
+    }
+    fn add(a: Node, b: Node, subtract: bool) -> Option<Node> {
+        match (constant(&a), constant(&b)) {
+            (Some(x), Some(y)) => integer(if subtract { x - y } else { x + y }),
+            (_, Some(0)) => Some(a),
+            (Some(0), _) if !subtract => Some(b),
+            _ if subtract => Some(Node::Subtract(Box::new(a), Box::new(b))),
+            _ => Some(Node::Add(Box::new(a), Box::new(b))),
+        }
+    }
+    // Build num/den, reducing it when both are integers
+    fn fraction(num: Node, den: Node) -> Result<Option<Fraction>, EvalError> {
+        match (constant(&num), constant(&den)) {
+            (_, Some(0)) => Err(EvalError::DivisionByZero),
+            (Some(n), Some(d)) => {
+                let (mut a, mut b) = (n.unsigned_abs(), d.unsigned_abs());
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                let divisor = d.signum() * a as i128;
+                Ok(integer(n / divisor).zip(integer(d / divisor)))
+            }
+            _ => Ok(Some((num, den))),
+        }
+    }
+    // None when the tree has to be evaluated as floating point instead
+    fn walk(node: &Node) -> Result<Option<Fraction>, EvalError> {
+        use self::Node::*;
+        let (a, b) = match node {
+            Number(_) => return Ok(constant(node).map(|_| (node.clone(), Number(1.0)))),
+            Constant(_) | Variable(_) => return Ok(Some((node.clone(), Number(1.0)))),
+            Negative(a) => return Ok(walk(a)?.and_then(|(num, den)| Some((mul(Number(-1.0), num)?, den)))),
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) => {
+                match (walk(a)?, walk(b)?) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Ok(None),
+                }
+            }
+            FlatAdd(_) | FlatMul(_) => return walk(&binary_chain(node)),
+            Let(..) | Function(..) | And(..) | Or(..) | Not(_) => return Ok(None),
+        };
+        let ((an, ad), (bn, bd)) = (a, b);
+        let parts = match node {
+            Add(..) | Subtract(..) => {
+                let subtract = matches!(node, Subtract(..));
+                let num = mul(an, bd.clone()).zip(mul(bn, ad.clone())).and_then(|(x, y)| add(x, y, subtract));
+                num.zip(mul(ad, bd))
+            }
+            Multiply(..) => mul(an, bn).zip(mul(ad, bd)),
+            Divide(..) => {
+                if constant(&bn) == Some(0) {
+                    return Err(EvalError::DivisionByZero);
+                }
+                mul(an, bd).zip(mul(ad, bn))
+            }
+            _ => {
+                // Only a constant integer exponent keeps the power a fraction, and 0 to a
+                // negative power is left to floating point, where it is infinite
+                let exponent = match (constant(&bn), constant(&bd)) {
+                    (Some(k), Some(1)) if k.abs() <= 64 && !(k < 0 && constant(&an) == Some(0)) => k,
+                    _ => return Ok(None),
+                };
+                let (mut num, mut den) = (Number(1.0), Number(1.0));
+                for _ in 0..exponent.abs() {
+                    match mul(num, an.clone()).zip(mul(den, ad.clone())) {
+                        Some(next) => (num, den) = next,
+                        None => return Ok(None),
+                    }
+                }
+                Some(if exponent < 0 { (den, num) } else { (num, den) })
+            }
+        };
+        match parts {
+            Some((num, den)) => fraction(num, den),
+            None => Ok(None),
+        }
+    }
+    match walk(&node)? {
+        Some(result) => Ok(result),
+        None => Ok((Node::Number(eval(node)?), Node::Number(1.0))),
+    }
+}
+
+// Replace every free occurrence of the variable `name` with `value`.
+// A let that rebinds `name` hides it from its body, which is left untouched. A let binding a
+// free variable of `value` is renamed first, so `x` in `let y = 2 in x*y` becoming `y+1`
+// gives `let y_1 = 2 in (y+1)*y_1` rather than capturing the new `y`.
+#[must_use]
+pub fn substitute(node: &Node, name: &str, value: &Node) -> Node {
+    use self::Node::*;
+    let sub = |node: &Node| Box::new(substitute(node, name, value));
+    match node {
+        Variable(v) if v == name => value.clone(),
+        Number(_) | Constant(_) | Variable(_) => node.clone(),
+        Negative(a) => Negative(sub(a)),
+        Not(a) => Not(sub(a)),
+        Add(a, b) => Add(sub(a), sub(b)),
+        Subtract(a, b) => Subtract(sub(a), sub(b)),
+        Multiply(a, b) => Multiply(sub(a), sub(b)),
+        Divide(a, b) => Divide(sub(a), sub(b)),
+        Caret(a, b) => Caret(sub(a), sub(b)),
+        And(a, b) => And(sub(a), sub(b)),
+        Or(a, b) => Or(sub(a), sub(b)),
+        Let(bound, v, body) if bound == name => Let(bound.clone(), sub(v), body.clone()),
+        Let(bound, v, body) if free_variables(value).contains(bound) => {
+            let (fresh, body) = rename_binder(bound, body, &[value]);
+            Let(fresh, sub(v), sub(&body))
+        }
+        Let(bound, v, body) => Let(bound.clone(), sub(v), sub(body)),
+        Function(f, args) => Function(f.clone(), args.iter().map(|arg| substitute(arg, name, value)).collect()),
+        FlatAdd(items) => FlatAdd(items.iter().map(|item| substitute(item, name, value)).collect()),
+        FlatMul(items) => FlatMul(items.iter().map(|item| substitute(item, name, value)).collect()),
+    }
+}
+
+// A name for the let binder `bound` that appears nowhere in `body` or `avoid`, such as `y_1`
+// for `y`, with `body` rewritten to read it
+fn rename_binder(bound: &str, body: &Node, avoid: &[&Node]) -> (String, Node) {
+    fn names<'a>(node: &'a Node, found: &mut Vec<&'a str>) {
+        match node {
+            Node::Variable(v) => found.push(v),
+            Node::Let(name, ..) => found.push(name),
+            _ => {}
+        }
+        node.children().into_iter().for_each(|child| names(child, found));
+    }
+    let mut taken = Vec::new();
+    std::iter::once(body).chain(avoid.iter().copied()).for_each(|node| names(node, &mut taken));
+    let fresh = (1..)
+        .map(|i| format!("{}_{}", bound, i))
+        .find(|candidate| !taken.contains(&candidate.as_str()))
+        .unwrap_or_default();
+    let body = substitute(body, bound, &Node::Variable(fresh.clone()));
+    (fresh, body)
+}
+
+// Names of the variables `node` reads that no enclosing let binds, sorted and without repeats
+#[must_use]
+pub fn free_variables(node: &Node) -> Vec<String> {
+    fn walk(node: &Node, bound: &mut Vec<String>, found: &mut Vec<String>) {
+        match node {
+            Node::Variable(v) if !bound.contains(v) => found.push(v.clone()),
+            Node::Let(name, value, body) => {
+                walk(value, bound, found);
+                bound.push(name.clone());
+                walk(body, bound, found);
+                bound.pop();
+            }
+            _ => node.children().into_iter().for_each(|child| walk(child, bound, found)),
+        }
+    }
+    let mut found = Vec::new();
+    walk(node, &mut Vec::new(), &mut found);
+    found.sort();
+    found.dedup();
+    found
+}
+
+// Degree of `node` as a polynomial in `var`, or None if it is not one (e.g. `1/x` or `sin(x)`).
+// Subtrees without `var` have degree 0 and other variables count as constants. The degree is
+// read off the tree without simplifying, so `x - x` has degree 1.
+#[must_use]
+pub fn polynomial_degree(node: &Node, var: &str) -> Option<u32> {
+    use self::Node::*;
+    if !free_variables(node).iter().any(|v| v == var) {
+        return Some(0);
+    }
+    let degree = |node: &Node| polynomial_degree(node, var);
+    match node {
+        Variable(_) => Some(1),
+        Negative(a) => degree(a),
+        Add(a, b) | Subtract(a, b) => Some(degree(a)?.max(degree(b)?)),
+        Multiply(a, b) => degree(a)?.checked_add(degree(b)?),
+        FlatAdd(items) => items.iter().try_fold(0, |max, item| Some(max.max(degree(item)?))),
+        FlatMul(items) => items.iter().try_fold(0u32, |sum, item| sum.checked_add(degree(item)?)),
+        Divide(a, b) if !free_variables(b).iter().any(|v| v == var) => degree(a),
+        Caret(a, b) => match **b {
+            Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= f64::from(u32::MAX) => {
+                degree(a)?.checked_mul(n as u32)
+            }
+            _ => None,
+        },
+        Let(name, value, body) => degree(&substitute(body, name, value)),
+        _ => None,
+    }
+}
+
+// Symbolic derivative of `node` with respect to the variable `var`.
+// The result is not simplified, e.g. d/dx of `2*x` is `0 * x + 2 * 1`.
+pub fn diff(node: &Node, var: &str) -> Result<Node, EvalError> {
+    use self::Node::*;
+    fn func(name: &str, arg: &Node) -> Node {
+        Function(name.to_string(), vec![arg.clone()])
+    }
+    fn uses(node: &Node, var: &str) -> bool {
+        match node {
+            Variable(v) => v == var,
+            Number(_) | Constant(_) => false,
+            Negative(a) | Not(a) => uses(a, var),
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+            | Or(a, b) | Let(_, a, b) => uses(a, var) || uses(b, var),
+            Function(_, args) | FlatAdd(args) | FlatMul(args) => args.iter().any(|arg| uses(arg, var)),
+        }
+    }
+    let d = |node: &Node| diff(node, var);
+    let derivative = match node {
+        Number(_) | Constant(_) => Number(0.0),
+        Variable(v) => Number(if v == var { 1.0 } else { 0.0 }),
+        Negative(a) => -d(a)?,
+        Add(a, b) => d(a)? + d(b)?,
+        Subtract(a, b) => d(a)? - d(b)?,
+        Multiply(a, b) => d(a)? * (**b).clone() + (**a).clone() * d(b)?,
+        Divide(a, b) => {
+            let numerator = d(a)? * (**b).clone() - (**a).clone() * d(b)?;
+            numerator / Caret(b.clone(), Box::new(Number(2.0)))
+        }
+        // Power rule for a constant exponent, otherwise d(a^b) = a^b * (b' ln a + b a' / a)
+        Caret(a, b) if !uses(b, var) => {
+            let lowered = Caret(a.clone(), Box::new((**b).clone() - Number(1.0)));
+            (**b).clone() * lowered * d(a)?
+        }
+        Caret(a, b) => {
+            let rate = d(b)? * func("ln", a) + (**b).clone() * d(a)? / (**a).clone();
+            node.clone() * rate
+        }
+        // Chain rule through the binding: d/dx (let n = v in b) = let n = v in (db/dx + db/dn * dv/dx),
+        // with the binder renamed when it would capture a variable of dv/dx or hide `var`
+        Let(name, value, body) => {
+            let rate = d(value)?;
+            let (name, body) = if name == var || free_variables(&rate).contains(name) {
+                rename_binder(name, body, &[value, &rate])
+            } else {
+                (name.clone(), (**body).clone())
+            };
+            let chained = d(&body)? + diff(&body, &name)? * rate;
+            Let(name, value.clone(), Box::new(chained))
+        }
+        Function(name, args) => {
+            let arg = match args.as_slice() {
+                [arg] => arg,
+                _ => return Err(EvalError::NotDifferentiable(format!("{}()", name))),
+            };
+            let outer = match name.as_str() {
+                "sin" => func("cos", arg),
+                "cos" => -func("sin", arg),
+                "tan" => Number(1.0) / Caret(Box::new(func("cos", arg)), Box::new(Number(2.0))),
+                "exp" => func("exp", arg),
+                "ln" => Number(1.0) / arg.clone(),
+                "sqrt" => Number(1.0) / (Number(2.0) * func("sqrt", arg)),
+                "abs" => arg.clone() / func("abs", arg),
+                _ => return Err(EvalError::NotDifferentiable(format!("{}()", name))),
+            };
+            outer * d(arg)?
+        }
+        FlatAdd(items) => FlatAdd(items.iter().map(d).collect::<Result<Vec<Node>, EvalError>>()?),
+        // Product rule: one term per operand, with that operand replaced by its derivative
+        FlatMul(items) => {
+            let mut terms = Vec::with_capacity(items.len());
+            for (i, item) in items.iter().enumerate() {
+                let mut term = items.clone();
+                term[i] = d(item)?;
+                terms.push(FlatMul(term));
+            }
+            FlatAdd(terms)
+        }
+        Not(_) => return Err(EvalError::NotDifferentiable("!".into())),
+        And(..) => return Err(EvalError::NotDifferentiable("&".into())),
+        Or(..) => return Err(EvalError::NotDifferentiable("|".into())),
+    };
+    Ok(derivative)
+}
+
+// Partial derivatives of `node` with respect to each of `vars`, in the same order.
+// Fails with the first error `diff` reports for any of them.
+pub fn diff_partial(node: &Node, vars: &[&str]) -> Result<Vec<Node>, EvalError> {
+    vars.iter().map(|var| diff(node, var)).collect()
+}
+
+// The gradient of `node` over `vars`, the same as `diff_partial`
+#[inline]
+pub fn gradient(node: &Node, vars: &[&str]) -> Result<Vec<Node>, EvalError> {
+    diff_partial(node, vars)
+}
+
+// The gradient of `expr` at the point `vars`, one partial derivative for each variable, in
+// the same order. A variable of `expr` missing from `vars` is EvalError::UnknownVariable.
+pub fn eval_gradient(expr: Node, vars: &[(&str, f64)]) -> Result<Vec<f64>, EvalError> {
+    let mut ctx = EvalContext::new();
+    for (name, value) in vars {
+        ctx.set_var(name, *value);
+    }
+    vars.iter().map(|(name, _)| eval_with_context(&diff(&expr, name)?, &ctx)).collect()
+}
+
+// The value of a constant expression with a bitwise operator (`&`, `|` or `!`) as a binary
+// string such as `0b1111`. None if there is no such operator, or if the result or an operand
+// of one of them is not a non-negative integer, e.g. `0xFF & (-1)`.
+#[must_use]
+pub fn eval_symbolic_bitwise(node: &Node) -> Option<String> {
+    let mut operands = Vec::new();
+    let mut pending = vec![node];
+    while let Some(current) = pending.pop() {
+        if matches!(current, Node::And(..) | Node::Or(..) | Node::Not(_)) {
+            operands.extend(current.children());
+        }
+        pending.extend(current.children());
+    }
+    let whole = |node: &Node| {
+        eval(node.clone()).ok().filter(|v| *v >= 0.0 && v.fract() == 0.0 && *v < u64::MAX as f64)
+    };
+    if operands.is_empty() || !operands.into_iter().all(|operand| whole(operand).is_some()) {
+        return None;
+    }
+    whole(node).map(|value| format!("0b{:b}", value as u64))
+}
+
+// Return every Number literal whose magnitude exceeds MAX_SAFE_INTEGER,
+// where f64 can no longer represent each integer exactly
+#[must_use]
+pub fn check_integer_safety(node: &Node) -> Vec<f64> {
+    fn walk(node: &Node, unsafe_numbers: &mut Vec<f64>) {
+        use self::Node::*;
+        match node {
+            Number(n) => {
+                if n.abs() > MAX_SAFE_INTEGER {
+                    unsafe_numbers.push(*n);
+                }
+            }
+            Constant(_) | Variable(_) => {}
+            Negative(a) | Not(a) => walk(a, unsafe_numbers),
+            Add(a, b) | Subtract(a, b) | Multiply(a, b) | Divide(a, b) | Caret(a, b) | And(a, b)
+            | Or(a, b) | Let(_, a, b) => {
+                walk(a, unsafe_numbers);
+                walk(b, unsafe_numbers);
+            }
+            Function(_, args) | FlatAdd(args) | FlatMul(args) => {
+                args.iter().for_each(|arg| walk(arg, unsafe_numbers))
+            }
+        }
+    }
+    let mut unsafe_numbers = Vec::new();
+    walk(node, &mut unsafe_numbers);
+    unsafe_numbers
+}
+
+// Put the AST in a canonical form so that trees differing only in the order of
+// commutative operands compare equal, e.g. `b+a` and `a+b`. Children are normalized
+// first, then the operands of Add, Multiply, And and Or are ordered by their Display text.
+#[must_use]
+pub fn normalize(node: Node) -> Node {
+    fn sorted(a: Node, b: Node) -> (Box<Node>, Box<Node>) {
+        let (a, b) = both(a, b);
+        if b.to_string() < a.to_string() {
+            (b, a)
+        } else {
+            (a, b)
+        }
+    }
+    fn both(a: Node, b: Node) -> (Box<Node>, Box<Node>) {
+        (Box::new(normalize(a)), Box::new(normalize(b)))
+    }
+    fn sorted_all(items: Vec<Node>) -> Vec<Node> {
+        let mut items: Vec<Node> = items.into_iter().map(normalize).collect();
+        items.sort_by_cached_key(|item| item.to_string());
+        items
+    }
+    use self::Node::*;
+    match node {
+        Add(a, b) => {
+            let (a, b) = sorted(*a, *b);
+            Add(a, b)
+        }
+        Multiply(a, b) => {
+            let (a, b) = sorted(*a, *b);
+            Multiply(a, b)
+        }
+        And(a, b) => {
+            let (a, b) = sorted(*a, *b);
+            And(a, b)
+        }
+        Or(a, b) => {
+            let (a, b) = sorted(*a, *b);
+            Or(a, b)
+        }
+        Subtract(a, b) => {
+            let (a, b) = both(*a, *b);
+            Subtract(a, b)
+        }
+        Divide(a, b) => {
+            let (a, b) = both(*a, *b);
+            Divide(a, b)
+        }
+        Caret(a, b) => {
+            let (a, b) = both(*a, *b);
+            Caret(a, b)
+        }
+        Negative(a) => Negative(Box::new(normalize(*a))),
+        Not(a) => Not(Box::new(normalize(*a))),
+        Let(name, value, body) => {
+            let (value, body) = both(*value, *body);
+            Let(name, value, body)
+        }
+        Function(name, args) => Function(name, args.into_iter().map(normalize).collect()),
+        FlatAdd(items) => FlatAdd(sorted_all(items)),
+        FlatMul(items) => FlatMul(sorted_all(items)),
+        Number(_) | Constant(_) | Variable(_) => node,
+    }
+}
+
+// Remove operations that leave their operand unchanged, bottom-up: `x + 0`, `0 + x`,
+// `x - 0`, `x * 1`, `1 * x`, `x / 1` and `x ^ 1` become `x`, `0 - x` becomes `-x`, `--x`
+// becomes `x` and `x ^ 0` becomes 1 unless x is constant and not finite, so `(1/0) ^ 0` keeps
+// its division by zero. `x * 0` is kept, since it is NaN for an infinite x.
+#[must_use]
+pub fn simplify(node: Node) -> Node {
+    use self::Node::*;
+    let is = |node: &Node, value: f64| matches!(node, Number(n) if *n == value);
+    let finite = |node: &Node| eval_with_context(node, &EvalContext::new()).is_ok_and(f64::is_finite);
+    let both = |a: Box<Node>, b: Box<Node>| (simplify(*a), simplify(*b));
+    match node {
+        Add(a, b) => match both(a, b) {
+            (a, b) if is(&b, 0.0) => a,
+            (a, b) if is(&a, 0.0) => b,
+            (a, b) => Add(Box::new(a), Box::new(b)),
+        },
+        Subtract(a, b) => match both(a, b) {
+            (a, b) if is(&b, 0.0) => a,
+            (a, b) if is(&a, 0.0) => simplify(Negative(Box::new(b))),
+            (a, b) => Subtract(Box::new(a), Box::new(b)),
+        },
+        Multiply(a, b) => match both(a, b) {
+            (a, b) if is(&b, 1.0) => a,
+            (a, b) if is(&a, 1.0) => b,
+            (a, b) => Multiply(Box::new(a), Box::new(b)),
+        },
+        Divide(a, b) => match both(a, b) {
+            (a, b) if is(&b, 1.0) => a,
+            (a, b) => Divide(Box::new(a), Box::new(b)),
+        },
+        Caret(a, b) => match both(a, b) {
+            (a, b) if is(&b, 1.0) => a,
+            (a, b) if is(&b, 0.0) && (!free_variables(&a).is_empty() || finite(&a)) => Number(1.0),
+            (a, b) => Caret(Box::new(a), Box::new(b)),
+        },
+        Negative(a) => match simplify(*a) {
+            Negative(inner) => *inner,
+            a => Negative(Box::new(a)),
+        },
+        Not(a) => Not(Box::new(simplify(*a))),
+        And(a, b) => {
+            let (a, b) = both(a, b);
+            And(Box::new(a), Box::new(b))
+        }
+        Or(a, b) => {
+            let (a, b) = both(a, b);
+            Or(Box::new(a), Box::new(b))
+        }
+        Let(name, value, body) => {
+            let (value, body) = both(value, body);
+            Let(name, Box::new(value), Box::new(body))
+        }
+        Function(name, args) => Function(name, args.into_iter().map(simplify).collect()),
+        FlatAdd(items) => FlatAdd(items.into_iter().map(simplify).collect()),
+        FlatMul(items) => FlatMul(items.into_iter().map(simplify).collect()),
+        Number(_) | Constant(_) | Variable(_) => node,
+    }
+}
+
+// Apply user rules bottom-up: once the children of a node are rewritten, the first
+// (pattern, replacement) pair whose pattern matches the node replaces it, and the result is
+// not rewritten again. Every variable of a pattern is a wildcard standing for any subtree,
+// and one that appears twice must match equal subtrees, so `x - x` matches `(a+1) - (a+1)`.
+#[must_use]
+pub fn rewrite_rules(rules: &[(Node, Node)], mut node: Node) -> Node {
+    let mut index = 0;
+    while let Some(child) = node.child_mut(index) {
+        *child = rewrite_rules(rules, mem::replace(child, Node::Number(0.0)));
+        index += 1;
+    }
+    for (pattern, replacement) in rules {
+        let mut bindings = Vec::new();
+        if match_pattern(pattern, &node, &mut bindings) {
+            return instantiate(replacement, &bindings);
+        }
+    }
+    node
 }
 
-pub fn eval(expr: Node) -> Result<f64, Box<dyn error::Error>> {
+// True if `node` has the shape of `pattern`, with the subtree each wildcard stands for
+// added to `bindings`
+fn match_pattern<'a>(pattern: &'a Node, node: &'a Node, bindings: &mut Vec<(&'a str, &'a Node)>) -> bool {
     use self::Node::*;
-    match expr {
-        Number(i) => Ok(i),
-        Add(a, b) => Ok(eval(*a)? + eval(*b)?),
-        Subtract(a, b) => Ok(eval(*a)? - eval(*b)?),
-        Multiply(a, b) => Ok(eval(*a)? * eval(*b)?),
-        Divide(a, b) => {
-            let divisor = eval(*b)?;
-            if divisor == 0.0 {
-                return Err("Division by zero".into());
+    let same_operator = match (pattern, node) {
+        (Variable(name), _) => match bindings.iter().find(|(bound, _)| bound == name) {
+            Some((_, bound)) => return *bound == node,
+            None => {
+                bindings.push((name, node));
+                return true;
             }
-            Ok(eval(*a)? / divisor)
+        },
+        (Number(a), Number(b)) => a == b,
+        (Function(f, _), Function(g, _)) | (Let(f, ..), Let(g, ..)) => f == g,
+        _ => mem::discriminant(pattern) == mem::discriminant(node),
+    };
+    let (pattern_children, children) = (pattern.children(), node.children());
+    same_operator
+        && pattern_children.len() == children.len()
+        && pattern_children.into_iter().zip(children).all(|(p, child)| match_pattern(p, child, bindings))
+}
+
+// `template` with each wildcard of `bindings` replaced by its subtree
+fn instantiate(template: &Node, bindings: &[(&str, &Node)]) -> Node {
+    if let Node::Variable(name) = template {
+        if let Some((_, bound)) = bindings.iter().find(|(wildcard, _)| wildcard == name) {
+            return (*bound).clone();
+        }
+    }
+    let mut node = template.clone();
+    let mut index = 0;
+    while let Some(child) = node.child_mut(index) {
+        *child = instantiate(child, bindings);
+        index += 1;
+    }
+    node
+}
+
+// Render an AST in infix notation with only the parentheses the parser needs to
+// rebuild the same tree: a child is wrapped when it binds less tightly than its parent,
+// or equally tightly on the side that associativity would not group it with.
+#[must_use]
+pub fn to_infix_minimal_parens(node: &Node) -> String {
+    // Binding strength, following the OperPrec levels with 6 for plain operands.
+    // A let body extends as far as possible, so a let operand is always wrapped.
+    fn precedence(node: &Node) -> u8 {
+        use self::Node::*;
+        match node {
+            Let(..) => 0,
+            And(..) | Or(..) => 1,
+            Add(..) | Subtract(..) => 2,
+            Multiply(..) | Divide(..) => 3,
+            FlatAdd(items) if !items.is_empty() => 2,
+            FlatMul(items) if !items.is_empty() => 3,
+            Caret(..) => 4,
+            Negative(_) | Not(_) => 5,
+            Number(n) if *n < 0.0 => 5,
+            Number(_) | Constant(_) | Variable(_) | Function(..) | FlatAdd(_) | FlatMul(_) => 6,
+        }
+    }
+    fn wrap(node: &Node, parens: bool) -> String {
+        if parens {
+            format!("({})", to_infix_minimal_parens(node))
+        } else {
+            to_infix_minimal_parens(node)
         }
-        Caret(a, b) => Ok(eval(*a)?.powf(eval(*b)?)),
-        Negative(a) => Ok(-eval(*a)?),
-        And(a, b) => Ok((eval(*a)? as i64 & eval(*b)? as i64) as f64),
-        Or(a, b) => Ok((eval(*a)? as i64 | eval(*b)? as i64) as f64),
     }
+    use self::Node::*;
+    let (symbol, a, b) = match node {
+        Number(n) => return FormatterConfig::default().format_number(*n),
+        Constant(c) => return c.name().to_string(),
+        Variable(name) => return name.clone(),
+        Let(name, value, body) => {
+            let (value, body) = (to_infix_minimal_parens(value), to_infix_minimal_parens(body));
+            return format!("let {} = {} in {}", name, value, body);
+        }
+        Function(name, args) => {
+            let args: Vec<String> = args.iter().map(to_infix_minimal_parens).collect();
+            return format!("{}({})", name, args.join(", "));
+        }
+        Negative(a) => {
+            let parens = precedence(a) < 5 || matches!(**a, Number(n) if n < 0.0);
+            return format!("-{}", wrap(a, parens));
+        }
+        Not(a) => return format!("!{}", wrap(a, precedence(a) < 5)),
+        // Written as the left-leaning chain it stands for, so every later operand of the
+        // same precedence is wrapped
+        FlatAdd(items) | FlatMul(items) => {
+            let (symbol, identity) = if matches!(node, FlatAdd(_)) { ("+", "0") } else { ("*", "1") };
+            let prec = precedence(node);
+            let parts: Vec<String> = items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| wrap(item, precedence(item) < prec || (i > 0 && precedence(item) == prec)))
+                .collect();
+            return if parts.is_empty() { identity.to_string() } else { parts.join(&format!(" {} ", symbol)) };
+        }
+        Add(a, b) => ("+", a, b),
+        Subtract(a, b) => ("-", a, b),
+        Multiply(a, b) => ("*", a, b),
+        Divide(a, b) => ("/", a, b),
+        Caret(a, b) => ("^", a, b),
+        And(a, b) => ("&", a, b),
+        Or(a, b) => ("|", a, b),
+    };
+    let prec = precedence(node);
+    let right_assoc = matches!(node, Caret(..));
+    let left_parens = precedence(a) < prec || (right_assoc && precedence(a) == prec);
+    let right_parens = precedence(b) < prec || (!right_assoc && precedence(b) == prec);
+    format!("{} {} {}", wrap(a, left_parens), symbol, wrap(b, right_parens))
 }
 
 #[cfg(test)]
+#[deny(unused_must_use)]
 mod tests {
     use super::*;
+    use crate::parsemath::parser::Parser;
+
+    fn parse(expr: &str) -> Node {
+        Parser::new(expr).unwrap().parse().unwrap()
+    }
 
     #[test]
     fn test_subtraction() {
@@ -70,4 +2271,834 @@ mod tests {
         let expr = Node::Caret(Box::new(Node::Number(2.0)), Box::new(Node::Number(10.0)));
         assert_eq!(eval(expr).unwrap(), 1024.0);
     }
+
+    #[test]
+    fn test_eval_f32() {
+        assert_eq!(eval_f32(parse("0.1 + 0.2")), Ok(0.1f32 + 0.2f32));
+        assert_eq!(eval_f32(parse("2^128")), Ok(f32::INFINITY));
+        assert_eq!(eval_f32(parse("factorial(5) - mod(-7, 3)")), Ok(118.0));
+        assert_eq!(eval_f32(parse("factorial(40)")), Ok(f32::INFINITY));
+        assert_eq!(eval_f32(parse("x + 1")), Err(EvalError::UnknownVariable("x".into())));
+        assert_eq!(eval_f32(parse("rem(1, 0)")), Err(EvalError::DivisionByZero));
+        assert!(matches!(eval_f32(parse("sin(1, 2)")), Err(EvalError::WrongArgumentCount { .. })));
+        assert!(matches!(eval_f32(parse("foo(1)")), Err(EvalError::UnknownFunction(_))));
+        for expr in ["sqrt(2)", "ln(10) * exp(0.5)", "max(1, 5, 3) - min(4, 2)", "rem(-7, 3)", "factorial(6)"] {
+            let wide = eval(parse(expr)).unwrap();
+            assert_eq!(eval_f32(parse(expr)), Ok(wide as f32), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_eval_counted() {
+        let expr = Node::Number(2.0) + Node::Number(3.0) * Node::Number(4.0);
+        let mut visited = 0;
+        assert_eq!(eval_counted(expr.clone(), &mut visited).unwrap(), 14.0);
+        assert_eq!(visited, count_nodes(&expr));
+    }
+
+    #[test]
+    fn test_node_into_f64() {
+        let x: f64 = parse("2+3").into();
+        assert_eq!(x, 5.0);
+        let node = parse("2^10");
+        assert_eq!(f64::try_from(&node), Ok(1024.0));
+        assert_eq!(f64::try_from(&Node::Variable("x".into())), Err(EvalError::UnknownVariable("x".into())));
+    }
+
+    #[test]
+    #[should_panic(expected = "eval failed: Division by zero")]
+    fn test_node_into_f64_panics() {
+        let _: f64 = Node::Divide(Box::new(Node::Number(1.0)), Box::new(Node::Number(0.0))).into();
+    }
+
+    #[test]
+    fn test_expr_macro() {
+        assert_eq!(expr!(2.0 + 3.0 * 4.0), parse("2+3*4"));
+        assert_eq!(eval(expr!(2.0 + 3.0 * 4.0)), Ok(14.0));
+        assert_eq!(expr!((2.0 + 3.0) * 4.0), parse("(2+3)*4"));
+        assert_eq!(expr!(8.0 - 4.0 - 2.0 / -x), parse("8-4-2/-x"));
+        assert_eq!(expr!(-(1.5 - y) * ((z))), parse("-(1.5-y)*((z))"));
+        assert_eq!(expr!(6.0 | 2.0 - 1.0), parse("6|2-1"));
+        assert_eq!(expr!(7.0), Node::Number(7.0));
+    }
+
+    #[test]
+    fn test_expr_macro_follows_parser_precedence() {
+        assert_eq!(expr!(1.0 | 2.0 & 3.0), parse("1 | 2 & 3"));
+        assert_eq!(expr!(1.0 & 2.0 | 3.0), parse("1 & 2 | 3"));
+        assert_eq!(expr!(2.0 ^ 3.0 ^ 2.0), parse("2^3^2"));
+        assert_eq!(eval(expr!(2.0 ^ 3.0 ^ 2.0)), Ok(512.0));
+        assert_eq!(expr!(-x ^ 2.0 * 3.0), parse("-x^2*3"));
+        assert_eq!(expr!(2.0 ^ -x ^ 2.0), parse("2^-x^2"));
+        assert_eq!(expr!(1.0 - -2.0 - 3.0), parse("1--2-3"));
+        assert_eq!(expr!(7.0 % 3.0 * 2.0), parse("7 % 3 * 2"));
+        assert_eq!(expr!(-3.0! + 2.0 ^ 3.0!), parse("-3! + 2^3!"));
+        assert_eq!(expr!(!x & y), parse("!x & y"));
+        assert_eq!(expr!(1.0 + (2.0 | x) ^ 2.0), parse("1 + (2|x)^2"));
+    }
+
+    #[test]
+    fn test_count_nodes() {
+        assert_eq!(count_nodes(&Node::Number(1.0)), 1);
+        let expr = Node::Add(
+            Box::new(Node::Number(1.0)),
+            Box::new(Node::Negative(Box::new(Node::Number(2.0)))),
+        );
+        assert_eq!(count_nodes(&expr), 4);
+    }
+
+    #[test]
+    fn test_depth_at_path() {
+        let node = parse("2*x + max(1, -y)");
+        assert_eq!(node.depth_at_path(&[]), Some(&node));
+        assert_eq!(node.depth_at_path(&[0, 1]), Some(&Node::Variable("x".into())));
+        assert_eq!(node.depth_at_path(&[1, 1, 0]), Some(&Node::Variable("y".into())));
+        assert_eq!(node.depth_at_path(&[2]), None);
+        assert_eq!(node.depth_at_path(&[0, 0, 0]), None);
+        assert_eq!(node.depth_at_path(&[1, 1, 1]), None);
+    }
+
+    #[test]
+    fn test_replace_at_path() {
+        let node = parse("2*x + max(1, -y)");
+        let replaced = node.replace_at_path(&[1, 1, 0], parse("z^2")).unwrap();
+        assert_eq!(replaced, parse("2*x + max(1, -(z^2))"));
+        assert_eq!(replaced.depth_at_path(&[1, 1, 0]), Some(&parse("z^2")));
+        assert_eq!(node.replace_at_path(&[], Node::Number(1.0)), Some(Node::Number(1.0)));
+        assert_eq!(node.replace_at_path(&[0, 1, 0], Node::Number(1.0)), None);
+        assert_eq!(node.replace_at_path(&[1, 2], Node::Number(1.0)), None);
+        assert_eq!(node.depth_at_path(&[1, 1, 0]), Some(&Node::Variable("y".into())));
+    }
+
+    #[test]
+    fn test_clone_deep_with_offset() {
+        let node = parse("2*x + max(1, -y)");
+        let first = node.clone_deep_with_offset(0);
+        let second = node.clone_deep_with_offset(count_nodes(&node));
+        assert_eq!((first.to_node(), second.to_node()), (Some(node.clone()), Some(node.clone())));
+        let (first_ids, second_ids) = (first.ids(), second.ids());
+        assert_eq!(first_ids.len(), 8);
+        assert!(first_ids.iter().all(|id| !second_ids.contains(id)));
+
+        // Pre-order: 0 is the sum, 1 the product, 2 and 3 its operands, 4 the call of max
+        let subtree = |tree: &IdNode, id: usize| tree.find(NodeId(id)).and_then(IdNode::to_node);
+        assert_eq!(subtree(&first, 1), Some(parse("2*x")));
+        assert_eq!(subtree(&first, 3), Some(parse("x")));
+        assert_eq!(subtree(&first, 6), Some(parse("-y")));
+        assert_eq!(subtree(&second, 8 + 1), Some(parse("2*x")));
+        assert_eq!(subtree(&second, 8 + 4), Some(parse("max(1, -y)")));
+        assert_eq!(subtree(&first, 8), None);
+
+        // Both copies under one root keep IDs that resolve to their own subtrees
+        let combined = IdNode {
+            id: NodeId(2 * count_nodes(&node)),
+            kind: NodeKind::Subtract,
+            children: vec![first, second],
+        };
+        let tree = combined.to_node().unwrap();
+        assert_eq!(tree, node.clone() - node.clone());
+        let resolve = |id: usize| combined.find(NodeId(id)).and_then(IdNode::to_node);
+        assert_eq!(resolve(3).as_ref(), tree.depth_at_path(&[0, 0, 1]));
+        assert_eq!(resolve(8 + 7).as_ref(), tree.depth_at_path(&[1, 1, 1, 0]));
+        assert_eq!(resolve(8 + 7), Some(parse("y")));
+        let mut all_ids = combined.ids();
+        all_ids.sort();
+        all_ids.dedup();
+        assert_eq!(all_ids.len(), 2 * count_nodes(&node) + 1);
+        assert_eq!(Node::Number(1.0).clone_deep_with_offset(7).ids(), [NodeId(7)]);
+
+        // Each node keeps only its own data, so a kind given the wrong operands rebuilds nothing
+        let leaf = IdNode { id: NodeId(0), kind: NodeKind::Variable("x".into()), children: Vec::new() };
+        assert_eq!(leaf.to_node(), Some(parse("x")));
+        let lopsided = IdNode { id: NodeId(1), kind: NodeKind::Add, children: vec![leaf.clone()] };
+        assert_eq!(lopsided.to_node(), None);
+        let with_operand = IdNode { id: NodeId(1), kind: NodeKind::Number(2.0), children: vec![leaf] };
+        assert_eq!(with_operand.to_node(), None);
+        let call = parse("max(1, let a = 2 in a, !x)").clone_deep_with_offset(3);
+        assert_eq!(call.children.len(), 3);
+        assert_eq!(call.ids(), (3..3 + 7).map(NodeId).collect::<Vec<_>>());
+        assert_eq!(call.to_node(), Some(parse("max(1, let a = 2 in a, !x)")));
+    }
+
+    #[test]
+    fn test_eval_symbolic_bitwise() {
+        let binary = |expr: &str| eval_symbolic_bitwise(&parse(expr));
+        assert_eq!(binary("0xFF & 0x0F").as_deref(), Some("0b1111"));
+        assert_eq!(binary("(4 | 1) + 2").as_deref(), Some("0b111"));
+        assert_eq!(binary("!0").as_deref(), Some("0b1"));
+        assert_eq!(binary("2+3"), None);
+        assert_eq!(binary("0xFF & (-1)"), None);
+        assert_eq!(binary("(3 & 1) - 2"), None);
+        assert_eq!(binary("1.5 | 2"), None);
+        assert_eq!(binary("x & 1"), None);
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(depth(&Node::Number(1.0)), 1);
+        let expr = Node::Add(
+            Box::new(Node::Number(1.0)),
+            Box::new(Node::Negative(Box::new(Node::Number(2.0)))),
+        );
+        assert_eq!(depth(&expr), 3);
+    }
+
+    // Build a full binary tree of the given depth mixing all arithmetic operators
+    #[cfg(feature = "rayon")]
+    fn balanced_tree(depth: usize, seed: usize) -> Node {
+        if depth == 1 {
+            return Node::Number((seed % 7 + 1) as f64);
+        }
+        let a = Box::new(balanced_tree(depth - 1, seed * 2));
+        let b = Box::new(balanced_tree(depth - 1, seed * 2 + 1));
+        match seed % 4 {
+            0 => Node::Add(a, b),
+            1 => Node::Subtract(a, b),
+            2 => Node::Multiply(a, b),
+            _ => Node::Divide(a, b),
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_eval_parallel_matches_eval() {
+        for depth in [1, 5, 11, 14] {
+            let tree = balanced_tree(depth, 1);
+            assert_eq!(eval_parallel(tree.clone()), eval(tree));
+        }
+        let by_zero = Node::Divide(Box::new(balanced_tree(12, 1)), Box::new(Node::Number(0.0)));
+        assert_eq!(eval_parallel(by_zero), Err(EvalError::DivisionByZero));
+    }
+
+    // Timing sensitive, run with `cargo test --features rayon -- --ignored` on an 8-core machine
+    #[cfg(feature = "rayon")]
+    #[test]
+    #[ignore]
+    fn test_eval_parallel_speedup() {
+        use std::time::Instant;
+        let tree = balanced_tree(21, 1);
+
+        let start = Instant::now();
+        let sequential = eval(tree.clone());
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = eval_parallel(tree);
+        let parallel_time = start.elapsed();
+
+        assert_eq!(sequential, parallel);
+        assert!(
+            sequential_time.as_secs_f64() >= 1.5 * parallel_time.as_secs_f64(),
+            "sequential {:?}, parallel {:?}",
+            sequential_time,
+            parallel_time
+        );
+    }
+
+    #[test]
+    fn test_eval_annotate_folds_tree() {
+        let original = parse("2*(3+4)-6/3^1");
+        let mut tree = original.clone();
+        let expected = eval(original).unwrap();
+        assert_eq!(eval_annotate(&mut tree).unwrap(), expected);
+        assert_eq!(tree, Node::Number(expected));
+        assert_eq!(eval_annotate(&mut tree).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_eval_annotate_partial_on_error() {
+        let mut tree = parse("(1+2)/(3-3)");
+        assert_eq!(eval_annotate(&mut tree), Err(EvalError::DivisionByZero));
+        assert_eq!(tree, Node::Divide(Box::new(Node::Number(3.0)), Box::new(Node::Number(0.0))));
+    }
+
+    #[test]
+    fn test_operator_overloads_build_tree() {
+        let (two, three) = (Node::Number(2.0), Node::Number(3.0));
+        let b = |n: &Node| Box::new(n.clone());
+        assert_eq!(two.clone() + three.clone(), Node::Add(b(&two), b(&three)));
+        assert_eq!(two.clone() - three.clone(), Node::Subtract(b(&two), b(&three)));
+        assert_eq!(two.clone() * three.clone(), Node::Multiply(b(&two), b(&three)));
+        assert_eq!(two.clone() / three.clone(), Node::Divide(b(&two), b(&three)));
+        assert_eq!(two.clone() & three.clone(), Node::And(b(&two), b(&three)));
+        assert_eq!(two.clone() | three.clone(), Node::Or(b(&two), b(&three)));
+        assert_eq!(-two.clone(), Node::Negative(b(&two)));
+    }
+
+    #[test]
+    fn test_operator_overloads_eval() {
+        let a = Node::Number(2.0) * Node::Number(4.5);
+        let b = -(Node::Number(1.0) - Node::Number(7.0));
+        let expected = eval(a.clone()).unwrap() + eval(b.clone()).unwrap();
+        assert_eq!(eval(a + b).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_eval_annotate_keeps_variables() {
+        let mut tree = parse("x*(2+3)+4*5");
+        assert_eq!(eval_annotate(&mut tree), Err(EvalError::UnknownVariable("x".into())));
+        let expected = parse("x*5+20");
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn test_eval_with_context() {
+        let expr = parse("x*x+y");
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 3.0);
+        assert_eq!(eval_with_context(&expr, &ctx), Err(EvalError::UnknownVariable("y".into())));
+        ctx.set_var("y", 1.5);
+        assert_eq!(eval_with_context(&expr, &ctx).unwrap(), 10.5);
+        assert_eq!(eval(expr), Err(EvalError::UnknownVariable("x".into())));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let expr = Node::Divide(Box::new(Node::Number(1.0)), Box::new(Node::Number(0.0)));
+        assert_eq!(eval(expr), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_check_integer_safety() {
+        let big = parse("0xFFFFFFFFFFFFFFFF&1");
+        assert_eq!(check_integer_safety(&big), vec![18_446_744_073_709_551_615.0]);
+        let small = parse("0xFFFFFF&1");
+        assert!(check_integer_safety(&small).is_empty());
+    }
+
+    #[test]
+    fn test_eval_with_config_strict_integer_precision() {
+        let strict = ParseConfig { strict_integer_precision: true, ..ParseConfig::default() };
+        let big = parse("0xFFFFFFFFFFFFFFFF");
+        assert!(eval_with_config(big.clone(), &ParseConfig::default()).is_ok());
+        assert_eq!(
+            eval_with_config(big, &strict),
+            Err(EvalError::UnsafeIntegerPrecision(18_446_744_073_709_551_615.0))
+        );
+        let small = parse("0xFFFFFF");
+        assert_eq!(eval_with_config(small, &strict), Ok(16_777_215.0));
+    }
+
+    #[test]
+    fn test_eval_with_config_strict_nan() {
+        let expr = Node::Caret(Box::new(Node::Number(-8.0)), Box::new(Node::Number(0.5)));
+        assert!(eval_with_config(expr.clone(), &ParseConfig::default()).unwrap().is_nan());
+        let strict = ParseConfig { strict_nan: true, ..ParseConfig::default() };
+        assert_eq!(eval_with_config(expr, &strict), Err(EvalError::NaNResult));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let cases = [
+            ("x + 0", "x"),
+            ("0 + x * 1", "x"),
+            ("1 * (x - 0) / 1", "x"),
+            ("0 - x", "-x"),
+            ("--x", "x"),
+            ("0 - -x", "x"),
+            ("x ^ 1 ^ y", "x ^ 1 ^ y"),
+            ("(x ^ 1) ^ 0", "1"),
+            ("(2 + 3) ^ 0", "1"),
+            ("(1 / 0) ^ 0", "(1 / 0) ^ 0"),
+            ("((0 - 1) ^ 0.5) ^ 0", "((-1) ^ 0.5) ^ 0"),
+            ("(2 ^ 2000) ^ 0", "(2 ^ 2000) ^ 0"),
+            ("(1 / y) ^ 0", "1"),
+            ("x * 0", "x * 0"),
+            ("max(y + 0, let z = 1 * x in z)", "max(y, let z = x in z)"),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(simplify(parse(expr)), parse(expected), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_to_infix_minimal_parens() {
+        let cases = [
+            ("2+3*4", "2 + 3 * 4"),
+            ("(2+3)*4", "(2 + 3) * 4"),
+            ("2*(3+4)", "2 * (3 + 4)"),
+            ("(2-3)-4", "2 - 3 - 4"),
+            ("2-(3-4)", "2 - (3 - 4)"),
+            ("2+(3+4)", "2 + (3 + 4)"),
+            ("(2*3)/4", "2 * 3 / 4"),
+            ("2/(3*4)", "2 / (3 * 4)"),
+            ("2^3^2", "2 ^ 3 ^ 2"),
+            ("(2^3)^2", "(2 ^ 3) ^ 2"),
+            ("-(4+x)", "-(4 + x)"),
+            ("-x*2", "-x * 2"),
+            ("-(x*2)", "-(x * 2)"),
+            ("-2^2", "-2 ^ 2"),
+            ("-(2^2)", "-(2 ^ 2)"),
+            ("2^-3", "2 ^ -3"),
+            ("6|2&3", "6 | 2 & 3"),
+            ("6|(2&3)", "6 | (2 & 3)"),
+            ("(1|2)+3", "(1 | 2) + 3"),
+            ("1|2+3", "1 | 2 + 3"),
+            ("((x))", "x"),
+            ("--2.5*(y-1)", "--2.5 * (y - 1)"),
+        ];
+        for (expr, expected) in cases {
+            let node = parse(expr);
+            let text = to_infix_minimal_parens(&node);
+            assert_eq!(text, expected, "{}", expr);
+            assert_eq!(parse(&text), node, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_to_infix_minimal_parens_negative_literals() {
+        let node = Node::Negative(Box::new(Node::Number(-3.0)));
+        assert_eq!(to_infix_minimal_parens(&node), "-(-3)");
+        let node = Node::Caret(Box::new(Node::Number(-3.0)), Box::new(Node::Number(2.0)));
+        assert_eq!(to_infix_minimal_parens(&node), "-3 ^ 2");
+    }
+
+    #[test]
+    fn test_normalize_commutative() {
+        let parse = |expr: &str| normalize(parse(expr));
+        assert_eq!(parse("b+a"), parse("a+b"));
+        assert_eq!(parse("y*(x+2)"), parse("(2+x)*y"));
+        assert_eq!(parse("3|1&2"), parse("2&(1|3)"));
+        assert_eq!(parse("-(c*b)"), parse("-(b*c)"));
+    }
+
+    #[test]
+    fn test_normalize_keeps_non_commutative_order() {
+        let parse = |expr: &str| normalize(parse(expr));
+        assert_ne!(parse("b-a"), parse("a-b"));
+        assert_ne!(parse("b/a"), parse("a/b"));
+        assert_ne!(parse("2^3"), parse("3^2"));
+        assert_eq!(parse("(b+a)-c"), parse("(a+b)-c"));
+        let node = parse("2-1");
+        assert_eq!(normalize(node.clone()), node);
+    }
+
+    #[test]
+    fn test_eval_rational() {
+        let rational = |expr: &str| eval_rational(&parse(expr));
+        assert_eq!(rational("1/3"), Some((1, 3)));
+        assert_eq!(rational("6/2"), Some((3, 1)));
+        assert_eq!(rational("0.1+0.2"), Some((3, 10)));
+        assert_eq!(rational("-(2/4)"), Some((-1, 2)));
+        assert_eq!(rational("1/-3"), Some((-1, 3)));
+        assert_eq!(rational("(2/3)^2"), Some((4, 9)));
+        assert_eq!(rational("2^-2"), Some((1, 4)));
+        assert_eq!(rational("7/2|8"), Some((11, 1)));
+        assert_eq!(rational("1^4000000000"), Some((1, 1)));
+    }
+
+    #[test]
+    fn test_eval_rational_not_rational() {
+        let rational = |expr: &str| eval_rational(&parse(expr));
+        assert_eq!(rational("2^0.5"), None);
+        assert_eq!(rational("1/0"), None);
+        assert_eq!(rational("x+1"), None);
+        assert_eq!(rational("0^-1"), None);
+        assert_eq!(rational("10^30"), None);
+    }
+
+    #[test]
+    fn test_functions() {
+        let value = |expr: &str| eval(parse(expr));
+        assert_eq!(value("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(value("max(1, 7, 3) - min(4, 2)"), Ok(5.0));
+        assert_eq!(value("ln(exp(2))"), Ok(2.0));
+        assert_eq!(value("foo(1)"), Err(EvalError::UnknownFunction("foo".into())));
+        assert_eq!(
+            value("sin(1, 2)"),
+            Err(EvalError::WrongArgumentCount { name: "sin".into(), expected: 1, found: 2 })
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let slope = |expr: &str, x: f64| {
+            let derivative = diff(&parse(expr), "x").unwrap();
+            let mut ctx = EvalContext::new();
+            ctx.set_var("x", x);
+            eval_with_context(&derivative, &ctx).unwrap()
+        };
+        assert_eq!(slope("x^2 - 2", 3.0), 6.0);
+        assert_eq!(slope("3*x + y", 1.0), 3.0);
+        assert_eq!(slope("x/(1+x)", 1.0), 0.25);
+        assert!((slope("sin(x)*x", 0.5) - (0.5f64.cos() * 0.5 + 0.5f64.sin())).abs() < 1e-12);
+        assert!((slope("2^x", 1.0) - 2.0 * 2f64.ln()).abs() < 1e-12);
+        assert_eq!(slope("let y = x*x in y + x", 2.0), 5.0);
+    }
+
+    #[test]
+    fn test_free_variables() {
+        let vars = |expr: &str| free_variables(&parse(expr));
+        assert_eq!(vars("y*x + x"), ["x", "y"]);
+        assert_eq!(vars("let x = y in x + max(z, 2)"), ["y", "z"]);
+        assert_eq!(vars("let x = x in x"), ["x"]);
+        assert!(vars("2^3").is_empty());
+    }
+
+    #[test]
+    fn test_eval_symbolic_fraction() {
+        let fraction = |expr: &str| eval_symbolic_fraction(parse(expr));
+        assert_eq!(fraction("1/3 + 1/6"), Ok((Node::Number(1.0), Node::Number(2.0))));
+        assert_eq!(fraction("2/-4 * 3"), Ok((Node::Number(-3.0), Node::Number(2.0))));
+        assert_eq!(fraction("(2/3)^-2 - 1/4"), Ok((Node::Number(2.0), Node::Number(1.0))));
+        assert_eq!(fraction("6/3"), Ok((Node::Number(2.0), Node::Number(1.0))));
+        let (num, den) = fraction("x/2 + x/3").unwrap();
+        assert_eq!(den, Node::Number(6.0));
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 6.0);
+        assert_eq!(eval_with_context(&num, &ctx), Ok(30.0));
+    }
+
+    #[test]
+    fn test_eval_symbolic_fraction_fallback() {
+        let fraction = |expr: &str| eval_symbolic_fraction(parse(expr));
+        let (value, den) = fraction("1/3 + 0.5").unwrap();
+        assert!((eval(value).unwrap() - 5.0 / 6.0).abs() < 1e-12);
+        assert_eq!(den, Node::Number(1.0));
+        assert_eq!(fraction("0^-1"), Ok((Node::Number(f64::INFINITY), Node::Number(1.0))));
+        assert_eq!(fraction("1/(2-2)"), Err(EvalError::DivisionByZero));
+        assert_eq!(fraction("sqrt(y) + 1/2"), Err(EvalError::UnknownVariable("y".into())));
+    }
+
+    #[test]
+    fn test_max_value_upper_bound() {
+        let bound = |expr: &str, vars: &[(&'static str, (f64, f64))]| {
+            let node = parse(expr);
+            max_value_upper_bound(&node, &vars.iter().copied().collect())
+        };
+        assert_eq!(bound("x^2", &[("x", (-1.0, 2.0))]), Some(4.0));
+        assert_eq!(bound("x + y", &[("x", (0.0, 1.0)), ("y", (0.0, 1.0))]), Some(2.0));
+        assert_eq!(bound("1/x", &[("x", (-1.0, 1.0))]), None);
+        assert_eq!(bound("1/x", &[("x", (0.5, 1.0))]), Some(2.0));
+        assert_eq!(bound("3 - x^2", &[("x", (-1.0, 2.0))]), Some(3.0));
+        assert_eq!(bound("x^3 - x", &[("x", (-2.0, 1.0))]), Some(3.0));
+        assert_eq!(bound("let y = x*2 in max(y, sqrt(x)) + abs(x - 3)", &[("x", (1.0, 4.0))]), Some(10.0));
+        assert_eq!(bound("2^x * sin(x)", &[("x", (0.0, 3.0))]), Some(8.0));
+        assert_eq!(bound("x + z", &[("x", (0.0, 1.0))]), None);
+        assert_eq!(bound("x & 1", &[("x", (0.0, 1.0))]), None);
+        assert_eq!(bound("x^0.5", &[("x", (-1.0, 1.0))]), None);
+        assert_eq!(bound("7", &[]), Some(7.0));
+        let node = flatten(parse("x*y*2 + x + 1"));
+        let vars = [("x", (-1.0, 2.0)), ("y", (0.0, 3.0))].into_iter().collect();
+        assert_eq!(max_value_upper_bound(&node, &vars), Some(15.0));
+    }
+
+    #[test]
+    fn test_eval_with_precision() {
+        let node = |expr: &str| parse(expr);
+        let third = eval_with_precision(node("1.0/3.0 * 3.0"), 1).unwrap();
+        assert!((third - 1.0).abs() <= f64::EPSILON);
+        assert_eq!(eval_with_precision(node("1.5*4 - 2"), 1), Ok(4.0));
+        assert_eq!(eval_with_precision(node("let x = 0.1 in x + x"), 2), Ok(0.2));
+        assert!((eval_with_precision(node("sqrt(2)^2"), 4).unwrap() - 2.0).abs() < 1e-15);
+        for expr in ["2+3*4", "0.1+0.2", "1/3", "2^0.5", "sin(1)"] {
+            assert_eq!(eval_with_precision(node(expr), 0), eval(node(expr)));
+        }
+    }
+
+    #[test]
+    fn test_eval_with_precision_errors() {
+        let node = |expr: &str| parse(expr);
+        // Cancellation leaves only rounding error, so the bounds are far apart relative to it
+        let error = eval_with_precision(node("(0.1 + 0.2) - 0.3"), 10).unwrap_err();
+        assert!(matches!(error, EvalError::PrecisionExceeded { ulps } if ulps > 10));
+        assert_eq!(eval_with_precision(node("2^1024"), 1), Err(EvalError::NonFiniteResult));
+        assert_eq!(eval_with_precision(node("0 - 2^1024"), 0), Ok(f64::NEG_INFINITY));
+        assert_eq!(eval_with_precision(node("1/(1-1)"), 1), Err(EvalError::DivisionByZero));
+        let unbounded = EvalError::PrecisionExceeded { ulps: u64::MAX };
+        assert_eq!(eval_with_precision(node("1/(0.1*3-0.3)"), 1000), Err(unbounded));
+    }
+
+    #[test]
+    fn test_gradient() {
+        let node = parse("x^2 + y^2 + x*y");
+        let grad = gradient(&node, &["x", "y"]).unwrap();
+        assert_eq!(grad.len(), 2);
+        let mut ctx = EvalContext::new();
+        for (x, y) in [(1.0, 2.0), (-3.0, 0.5)] {
+            ctx.set_var("x", x);
+            ctx.set_var("y", y);
+            assert_eq!(eval_with_context(&grad[0], &ctx), Ok(2.0 * x + y));
+            assert_eq!(eval_with_context(&grad[1], &ctx), Ok(2.0 * y + x));
+        }
+        assert_eq!(diff_partial(&node, &["x", "y", "z"]).unwrap().len(), 3);
+        assert_eq!(diff_partial(&node, &[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_diff_partial_error() {
+        let node = Node::Or(Box::new(Node::Variable("x".into())), Box::new(Node::Variable("y".into())));
+        assert_eq!(diff_partial(&node, &["x", "y"]), Err(EvalError::NotDifferentiable("|".into())));
+    }
+
+    #[test]
+    fn test_diff_not_differentiable() {
+        let node = Node::And(Box::new(Node::Variable("x".into())), Box::new(Node::Number(1.0)));
+        assert_eq!(diff(&node, "x"), Err(EvalError::NotDifferentiable("&".into())));
+        let call = Node::Function("max".into(), vec![Node::Number(1.0), Node::Variable("x".into())]);
+        assert!(diff(&call, "x").is_err());
+    }
+
+    #[test]
+    fn test_flatten() {
+        let numbers = |ns: &[f64]| ns.iter().map(|&n| Node::Number(n)).collect::<Vec<Node>>();
+        assert_eq!(flatten(parse("1+2+3+4")), Node::FlatAdd(numbers(&[1.0, 2.0, 3.0, 4.0])));
+        assert_eq!(flatten(parse("2*(3*4)*5")), Node::FlatMul(numbers(&[2.0, 3.0, 4.0, 5.0])));
+        assert_eq!(
+            flatten(parse("1+2*3*4+5")),
+            Node::FlatAdd(vec![Node::Number(1.0), Node::FlatMul(numbers(&[2.0, 3.0, 4.0])), Node::Number(5.0)])
+        );
+        assert_eq!(flatten(parse("1-2")), parse("1-2"));
+    }
+
+    #[test]
+    fn test_flatten_preserves_value() {
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 1.5);
+        for expr in ["1+2+3+4", "2*x*x*3", "(1+2+3)*(4*5-x*x*2)", "max(1+2+3, x*2*2)^2", "-(x+1+x)/2"] {
+            let node = parse(expr);
+            let flat = flatten(node.clone());
+            assert_eq!(eval_with_context(&flat, &ctx), eval_with_context(&node, &ctx));
+            assert_eq!(to_infix_minimal_parens(&flat), to_infix_minimal_parens(&node));
+        }
+    }
+
+    #[test]
+    fn test_flat_empty_nodes() {
+        assert_eq!(eval(Node::FlatAdd(Vec::new())), Ok(0.0));
+        assert_eq!(eval(Node::FlatMul(Vec::new())), Ok(1.0));
+        assert_eq!(eval(Node::FlatAdd(vec![Node::Number(7.0)])), Ok(7.0));
+    }
+
+    #[test]
+    fn test_polynomial_degree() {
+        let degree = |expr: &str| polynomial_degree(&parse(expr), "x");
+        assert_eq!(degree("x^3 + 2*x + 1"), Some(3));
+        assert_eq!(degree("x^2 * x"), Some(3));
+        assert_eq!(degree("7"), Some(0));
+        assert_eq!(degree("x"), Some(1));
+        assert_eq!(degree("-(x^2 - y*x)/4"), Some(2));
+        assert_eq!(degree("(x+1)^2 * (x-1)"), Some(3));
+        assert_eq!(degree("sin(y) * x^0"), Some(0));
+        assert_eq!(degree("let t = x^2 in t*t"), Some(4));
+        let flat = flatten(parse("x*x*x + x"));
+        assert_eq!(polynomial_degree(&flat, "x"), Some(3));
+    }
+
+    #[test]
+    fn test_polynomial_degree_not_polynomial() {
+        let degree = |expr: &str| polynomial_degree(&parse(expr), "x");
+        for expr in ["1/x", "sin(x)", "x^0.5", "x^-1", "2^x", "x^y", "1 + 1/(x+1)", "x & 1"] {
+            assert_eq!(degree(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_count_operator_frequency() {
+        let node = parse("2+3*4+5");
+        let counts = count_operator_frequency(&node);
+        assert_eq!(counts, HashMap::from([("add", 2), ("multiply", 1), ("literal", 4)]));
+
+        let node = parse("let y = -x^2 in max(y, 1/x) - !y");
+        let counts = count_operator_frequency(&node);
+        assert_eq!(counts["literal"], 6);
+        assert_eq!((counts["let"], counts["function"], counts["not"]), (1, 1, 1));
+        let operators: usize = counts.iter().filter(|(name, _)| **name != "literal").map(|(_, n)| n).sum();
+        assert_eq!(operators, count_nodes(&node) - counts["literal"]);
+    }
+
+    #[test]
+    fn test_count_operator_frequency_deep_tree() {
+        let mut node = Node::Number(1.0);
+        for _ in 0..10_000 {
+            node = Node::Negative(Box::new(node));
+        }
+        let counts = count_operator_frequency(&node);
+        assert_eq!((counts["negative"], counts["literal"]), (10_000, 1));
+    }
+
+    #[test]
+    fn test_eval_range() {
+        assert_eq!(eval_range(&parse("x^2"), "x", 0.0, 2.0, 3), Ok(vec![(0.0, 0.0), (1.0, 1.0), (2.0, 4.0)]));
+        let points = eval_range(&parse("x - 0.5"), "x", -1.0, 1.0, 5).unwrap();
+        let ys: Vec<f64> = points.iter().map(|&(_, y)| y).collect();
+        assert_eq!(ys, [-1.5, -1.0, -0.5, 0.0, 0.5]);
+        assert!(ys.iter().any(|&y| y < 0.0) && ys.iter().any(|&y| y > 0.0));
+        let points = eval_range(&parse("t"), "t", 0.1, 0.7, 7).unwrap();
+        assert_eq!(points.last(), Some(&(0.7, 0.7)));
+    }
+
+    #[test]
+    fn test_eval_range_errors() {
+        let node = parse("1/x");
+        assert!(matches!(eval_range(&node, "x", 0.0, 1.0, 1), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(eval_range(&node, "x", 1.0, 0.0, 3), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(eval_range(&node, "x", f64::NAN, 1.0, 3), Err(EvalError::InvalidArgument(_))));
+        assert_eq!(eval_range(&parse("0^-x"), "x", 0.0, 2.0, 3), Err(EvalError::NonFiniteResult));
+        let points = eval_range_lenient(&parse("0^-x"), "x", 0.0, 2.0, 3).unwrap();
+        assert_eq!(points[0], (0.0, 1.0));
+        assert!(points[2].1.is_infinite());
+        assert_eq!(eval_range(&parse("y"), "x", 0.0, 1.0, 2), Err(EvalError::UnknownVariable("y".into())));
+    }
+
+    #[test]
+    fn test_linear_in() {
+        assert!(parse("3*x + 2").is_linear_in("x"));
+        assert_eq!(parse("3*x + 2").linear_coefficient("x"), Some(3.0));
+        assert_eq!(parse("x + x").linear_coefficient("x"), Some(2.0));
+        assert_eq!(normalize(parse("x + x")).linear_coefficient("x"), Some(2.0));
+        assert_eq!(parse("(x - 1)/2 - -x*sqrt(4)").linear_coefficient("x"), Some(2.5));
+        assert_eq!(flatten(parse("2*x*3 + 1 + x")).linear_coefficient("x"), Some(7.0));
+        assert_eq!(parse("let k = 4 in k*x^1").linear_coefficient("x"), Some(4.0));
+        assert_eq!(parse("7").linear_coefficient("x"), Some(0.0));
+        assert_eq!(parse("x - x").linear_coefficient("x"), Some(0.0));
+    }
+
+    #[test]
+    fn test_substitute_numbers_with_symbols() {
+        let constant = |c| Box::new(Node::Constant(c));
+        let pi = Node::Number(std::f64::consts::PI).substitute_numbers_with_symbols(10);
+        assert_eq!(pi, Node::Constant(MathConstant::Pi));
+        assert_eq!(Node::Number(1.5).substitute_numbers_with_symbols(10), Node::Number(1.5));
+        let area = Node::Multiply(constant(MathConstant::Pi), Box::new(parse("r^2")));
+        assert_eq!(parse("3.14159 * r^2").substitute_numbers_with_symbols(5), area);
+        assert_eq!(parse("3.14159 * r^2").substitute_numbers_with_symbols(6), parse("3.14159 * r^2"));
+        let sum = Node::Add(constant(MathConstant::E), constant(MathConstant::Tau));
+        assert_eq!(parse("2.71828 + 6.28318").substitute_numbers_with_symbols(4), sum);
+        let shadowed = parse("let e = 1 in e + 2.718281828");
+        assert_eq!(shadowed.substitute_numbers_with_symbols(5), shadowed);
+    }
+
+    #[test]
+    fn test_symbolized_evaluates_like_original() {
+        for expr in ["3.141592653589793 * 2", "2.718281828459045 ^ 2", "6.283185307179586 - 1", "sin(3.141592653589793)"] {
+            let original = parse(expr);
+            let symbolized = original.substitute_numbers_with_symbols(10);
+            assert_ne!(symbolized, original, "{}", expr);
+            assert_eq!(eval(symbolized), eval(original.clone()), "{}", expr);
+        }
+        let node = Node::Number(std::f64::consts::E).substitute_numbers_with_symbols(3);
+        assert_eq!(node.to_string(), "e");
+        assert_eq!(eval_f32(node), Ok(std::f32::consts::E));
+    }
+
+    #[test]
+    fn test_not_linear_in() {
+        for expr in ["x^2", "x*x", "x*y", "1/x", "sin(x)", "2^x", "x + y", "x & 1"] {
+            let node = parse(expr);
+            assert!(!node.is_linear_in("x"), "{}", expr);
+            assert_eq!(node.linear_coefficient("x"), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_rules() {
+        let x = || Box::new(Node::Variable("x".into()));
+        let double = [(Node::Multiply(x(), Box::new(Node::Number(2.0))), Node::Add(x(), x()))];
+        assert_eq!(rewrite_rules(&double, parse("5 * 2")), parse("5 + 5"));
+        assert_eq!(rewrite_rules(&double, parse("(3 * 2) * 2")), parse("(3 + 3) + (3 + 3)"));
+        assert_eq!(rewrite_rules(&double, parse("5 * 3")), parse("5 * 3"));
+
+        let cancel = [(Node::Subtract(x(), x()), Node::Number(0.0))];
+        assert_eq!(rewrite_rules(&cancel, parse("(a+1) - (a+1)")), Node::Number(0.0));
+        assert_eq!(rewrite_rules(&cancel, parse("y + sin(a*b - a*b)")), parse("y + sin(0)"));
+        assert_eq!(rewrite_rules(&cancel, parse("a - b")), parse("a - b"));
+    }
+
+    #[test]
+    fn test_rewrite_rules_first_match_wins() {
+        let rules = [(parse("x ^ 2"), parse("x * x")), (parse("x ^ y"), parse("exp(y * ln(x))"))];
+        assert_eq!(rewrite_rules(&rules, parse("a ^ 2")), parse("a * a"));
+        assert_eq!(rewrite_rules(&rules, parse("a ^ 3")), parse("exp(3 * ln(a))"));
+        assert_eq!(rewrite_rules(&[], parse("a ^ 3")), parse("a ^ 3"));
+    }
+
+    #[test]
+    fn test_to_polynomial_coefficients() {
+        let coefficients =
+            |expr: &str| parse(expr).to_polynomial_coefficients("x");
+        assert_eq!(coefficients("3*x^2 + 2*x + 1"), Some(vec![1.0, 2.0, 3.0]));
+        assert_eq!(coefficients("x^3"), Some(vec![0.0, 0.0, 0.0, 1.0]));
+        assert_eq!(coefficients("(x + 1)^2"), Some(vec![1.0, 2.0, 1.0]));
+        assert_eq!(coefficients("(x - 1)/2"), Some(vec![-0.5, 0.5]));
+        assert_eq!(coefficients("let y = x*x in -y + 2^3"), Some(vec![8.0, 0.0, -1.0]));
+        assert_eq!(coefficients("x - x"), Some(vec![0.0, 0.0]));
+        assert_eq!(coefficients("7"), Some(vec![7.0]));
+        assert_eq!(coefficients("(x + 1)^5"), Some(vec![1.0, 5.0, 10.0, 10.0, 5.0, 1.0]));
+        let highest = coefficients("(x/2)^1024 + 1").unwrap();
+        assert_eq!((highest.len(), highest[0], highest[1024]), (1025, 1.0, 0.5f64.powi(1024)));
+    }
+
+    #[test]
+    fn test_to_polynomial_coefficients_not_polynomial() {
+        let coefficients =
+            |expr: &str| parse(expr).to_polynomial_coefficients("x");
+        // Past MAX_POLYNOMIAL_DEGREE, however it is reached
+        let too_high = ["x^1025", "x^4000000000", "(x+1)^600^2", "(x^2)^600"];
+        for expr in ["sin(x)", "1/x", "x^0.5", "x*y", "y", "x/(2-2)"].into_iter().chain(too_high) {
+            assert_eq!(coefficients(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_node_eq_f64() {
+        let sum = Node::Add(Box::new(Node::Number(2.0)), Box::new(Node::Number(3.0)));
+        assert_eq!(Node::Number(5.0), 5.0_f64);
+        assert_eq!(5.0_f64, Node::Number(5.0));
+        assert_ne!(sum, 5.0_f64);
+        assert_ne!(Node::Number(f64::NAN), f64::NAN);
+        assert_ne!(Node::Variable("x".into()), 5.0_f64);
+    }
+
+    #[test]
+    fn test_node_eq_f64_after_fold() {
+        let mut node = parse("2+3");
+        eval_annotate(&mut node).unwrap();
+        assert_eq!(node, 5.0_f64);
+        let mut node = parse("x + 2*3");
+        assert!(eval_annotate(&mut node).is_err());
+        assert_ne!(node, 6.0_f64);
+        assert_eq!(*node.children()[1], 6.0_f64);
+    }
+
+    #[test]
+    fn test_eval_gradient() {
+        assert_eq!(eval_gradient(parse("x^2 + y^2"), &[("x", 3.0), ("y", 4.0)]), Ok(vec![6.0, 8.0]));
+        assert_eq!(eval_gradient(parse("x*y + 2*y"), &[("y", 1.0), ("x", 5.0)]), Ok(vec![7.0, 1.0]));
+        assert_eq!(eval_gradient(parse("7"), &[("x", 1.0)]), Ok(vec![0.0]));
+        assert_eq!(eval_gradient(parse("x"), &[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_eval_gradient_unknown_variable() {
+        let node = parse("x^2 + y^2");
+        let result = eval_gradient(node, &[("x", 3.0), ("z", 4.0)]);
+        assert_eq!(result, Err(EvalError::UnknownVariable("y".into())));
+    }
+
+    #[test]
+    fn test_substitute_avoids_capture() {
+        let result = substitute(&parse("let y = 2 in x*y"), "x", &parse("y+1"));
+        assert_eq!(result, parse("let y_1 = 2 in (y+1)*y_1"));
+        let mut ctx = EvalContext::new();
+        ctx.set_var("y", 5.0);
+        assert_eq!(eval_with_context(&result, &ctx), Ok(12.0));
+        let result = substitute(&parse("let y = 2 in x*y + y_1"), "x", &parse("y"));
+        assert_eq!(result, parse("let y_2 = 2 in y*y_2 + y_1"));
+        assert_eq!(substitute(&parse("let y = 2 in x*y"), "x", &parse("z")), parse("let y = 2 in z*y"));
+    }
+
+    #[test]
+    fn test_diff_let_shadowing() {
+        let slope = |expr: &str, x: f64| {
+            let derivative = diff(&parse(expr), "x").unwrap();
+            let mut ctx = EvalContext::new();
+            ctx.set_var("x", x);
+            eval_with_context(&derivative, &ctx).unwrap()
+        };
+        assert_eq!(slope("let y = x in let x = 3 in y*x", 2.0), 3.0);
+        assert_eq!(slope("let x = x*x in x + 1", 3.0), 6.0);
+        assert_eq!(slope("let y = x*x in let x = y in x*y", 2.0), 32.0);
+        assert_eq!(slope("let y = 2*x in y*y", 1.0), 8.0);
+    }
 }
@@ -0,0 +1,142 @@
+/// This stores named expressions and saves them to or loads them from a JSON file
+/// such as `{"area":"3.14*r^2","double":"2*x"}`, mapping each name to its source text.
+// Standard lib
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Internal modules
+use super::expression::Expression;
+use super::parser::ParseError;
+
+#[derive(Debug, Clone, Default)]
+pub struct ExpressionBank {
+    expressions: HashMap<String, Expression>,
+}
+
+impl ExpressionBank {
+    #[must_use]
+    pub fn new() -> Self {
+        ExpressionBank::default()
+    }
+
+    // Store `expr` under `name`, returning the expression it replaces
+    pub fn insert(&mut self, name: &str, expr: Expression) -> Option<Expression> {
+        self.expressions.insert(name.to_string(), expr)
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&Expression> {
+        self.expressions.get(name)
+    }
+
+    // Number of stored expressions
+    #[must_use]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.expressions.len()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.expressions.is_empty()
+    }
+
+    // Write every expression to `path`, replacing the file. Names are written in sorted order.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), io::Error> {
+        let sources: BTreeMap<&str, &str> =
+            self.expressions.iter().map(|(name, expr)| (name.as_str(), expr.source())).collect();
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, &sources)?;
+        writer.flush()
+    }
+
+    // Read a bank written by `save_to_file`, parsing every expression again
+    pub fn load_from_file(path: &Path) -> Result<Self, BankError> {
+        let reader = BufReader::new(File::open(path).map_err(BankError::IoError)?);
+        let sources: HashMap<String, String> =
+            serde_json::from_reader(reader).map_err(|e| BankError::DeserializationError(e.to_string()))?;
+        let expressions = sources
+            .into_iter()
+            .map(|(name, source)| Ok((name, Expression::new(&source).map_err(BankError::ParseError)?)))
+            .collect::<Result<HashMap<String, Expression>, BankError>>()?;
+        Ok(ExpressionBank { expressions })
+    }
+}
+
+// Why loading a bank failed, named after the error each variant wraps
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum BankError {
+    IoError(io::Error),
+    ParseError(ParseError),
+    DeserializationError(String),
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BankError::IoError(e) => write!(f, "Error in loading expression bank: {}", e),
+            BankError::ParseError(e) => write!(f, "Error in loading expression bank: {}", e),
+            BankError::DeserializationError(e) => {
+                write!(f, "Error in loading expression bank, invalid JSON: {}", e)
+            }
+        }
+    }
+}
+
+impl error::Error for BankError {}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+    use crate::parsemath::context::EvalContext;
+    use std::path::PathBuf;
+
+    // A file in the temp directory unique to this process and test
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pass_bank_{}_{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_bank_round_trip() {
+        let mut bank = ExpressionBank::new();
+        for (name, source) in [("area", "3*r^2"), ("double", "2*x"), ("answer", "6*7")] {
+            assert!(bank.insert(name, Expression::new(source).unwrap()).is_none());
+        }
+        let path = temp_path("round_trip");
+        bank.save_to_file(&path).unwrap();
+        let loaded = ExpressionBank::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 3);
+        let mut ctx = EvalContext::new();
+        ctx.set_var("r", 2.0);
+        ctx.set_var("x", 5.0);
+        assert_eq!(loaded.get("area").unwrap().eval(&ctx), Ok(12.0));
+        assert_eq!(loaded.get("double").unwrap().eval(&ctx), Ok(10.0));
+        assert_eq!(loaded.get("answer").unwrap().eval(&ctx), Ok(42.0));
+        assert_eq!(loaded.get("double").unwrap().source(), "2*x");
+        assert!(loaded.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_bank_corrupted_file() {
+        let path = temp_path("corrupted");
+        std::fs::write(&path, r#"{"area": "3*r^2""#).unwrap();
+        let result = ExpressionBank::load_from_file(&path);
+        std::fs::write(&path, r#"{"bad": "2*"}"#).unwrap();
+        let unparsable = ExpressionBank::load_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(BankError::DeserializationError(_))));
+        assert!(matches!(unparsable, Err(BankError::ParseError(_))));
+        assert!(matches!(ExpressionBank::load_from_file(&path), Err(BankError::IoError(_))));
+    }
+}
@@ -0,0 +1,316 @@
+/// This converts expression trees to and from a compact binary encoding.
+/// Each node is written as a one byte tag followed by its children in order;
+/// numbers are written as 8 bytes of little-endian f64 and variable names as a
+/// 4 byte little-endian length followed by UTF-8 bytes. A let binding is written as
+/// its tag, the bound name, then the value and body nodes; a function call as its tag,
+/// the function name, a 4 byte little-endian argument count, then the arguments. An n-ary
+/// sum or product is written as its tag, a 4 byte little-endian count, then the operands.
+/// A named constant is its tag and one byte indexing MathConstant::ALL.
+// Internal modules
+use super::ast::{MathConstant, Node};
+use super::parser::ParseError;
+
+// Node type tags
+const TAG_NUMBER: u8 = 0;
+const TAG_NEGATIVE: u8 = 1;
+const TAG_ADD: u8 = 2;
+const TAG_SUBTRACT: u8 = 3;
+const TAG_MULTIPLY: u8 = 4;
+const TAG_DIVIDE: u8 = 5;
+const TAG_CARET: u8 = 6;
+const TAG_AND: u8 = 7;
+const TAG_OR: u8 = 8;
+const TAG_VARIABLE: u8 = 9;
+const TAG_LET: u8 = 10;
+const TAG_FUNCTION: u8 = 11;
+const TAG_NOT: u8 = 12;
+const TAG_FLAT_ADD: u8 = 13;
+const TAG_FLAT_MUL: u8 = 14;
+const TAG_CONSTANT: u8 = 15;
+
+// Encode an AST into bytes
+#[must_use]
+pub fn to_binary_format(node: &Node) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_node(node, &mut data);
+    data
+}
+
+fn write_node(node: &Node, data: &mut Vec<u8>) {
+    use self::Node::*;
+    let (tag, left, right) = match node {
+        Number(n) => {
+            data.push(TAG_NUMBER);
+            data.extend_from_slice(&n.to_le_bytes());
+            return;
+        }
+        Constant(c) => {
+            data.extend_from_slice(&[TAG_CONSTANT, *c as u8]);
+            return;
+        }
+        Variable(name) => {
+            data.push(TAG_VARIABLE);
+            write_name(name, data);
+            return;
+        }
+        Let(name, value, body) => {
+            data.push(TAG_LET);
+            write_name(name, data);
+            write_node(value, data);
+            write_node(body, data);
+            return;
+        }
+        Function(name, args) => {
+            data.push(TAG_FUNCTION);
+            write_name(name, data);
+            data.extend_from_slice(&(args.len() as u32).to_le_bytes());
+            args.iter().for_each(|arg| write_node(arg, data));
+            return;
+        }
+        FlatAdd(items) | FlatMul(items) => {
+            data.push(if matches!(node, FlatAdd(_)) { TAG_FLAT_ADD } else { TAG_FLAT_MUL });
+            data.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            items.iter().for_each(|item| write_node(item, data));
+            return;
+        }
+        Negative(a) => {
+            data.push(TAG_NEGATIVE);
+            write_node(a, data);
+            return;
+        }
+        Not(a) => {
+            data.push(TAG_NOT);
+            write_node(a, data);
+            return;
+        }
+        Add(a, b) => (TAG_ADD, a, b),
+        Subtract(a, b) => (TAG_SUBTRACT, a, b),
+        Multiply(a, b) => (TAG_MULTIPLY, a, b),
+        Divide(a, b) => (TAG_DIVIDE, a, b),
+        Caret(a, b) => (TAG_CARET, a, b),
+        And(a, b) => (TAG_AND, a, b),
+        Or(a, b) => (TAG_OR, a, b),
+    };
+    data.push(tag);
+    write_node(left, data);
+    write_node(right, data);
+}
+
+fn write_name(name: &str, data: &mut Vec<u8>) {
+    data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+}
+
+// Operator nodes still waiting for their children while decoding
+enum Pending {
+    Unary(u8),
+    Binary(u8, Option<Node>),
+    Let(String, Option<Node>),
+    Function(String, usize, Vec<Node>), // Name, argument count and arguments read so far
+    Flat(u8, usize, Vec<Node>),         // Tag, operand count and operands read so far
+}
+
+// Decode bytes produced by `to_binary_format` back into an AST.
+// An explicit stack is used so that malformed or deeply nested input cannot overflow.
+pub fn from_binary_format(data: &[u8]) -> Result<Node, ParseError> {
+    let mut pos = 0;
+    let mut stack: Vec<Pending> = Vec::new();
+    loop {
+        let tag = *data.get(pos).ok_or_else(|| binary_error("unexpected end of data"))?;
+        pos += 1;
+        let mut node = match tag {
+            TAG_NUMBER => {
+                let bytes = data.get(pos..pos + 8).ok_or_else(|| binary_error("truncated number"))?;
+                pos += 8;
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(bytes);
+                Node::Number(f64::from_le_bytes(buf))
+            }
+            TAG_CONSTANT => {
+                let index = *data.get(pos).ok_or_else(|| binary_error("truncated constant"))?;
+                pos += 1;
+                let constant = MathConstant::ALL.get(index as usize);
+                Node::Constant(*constant.ok_or_else(|| binary_error("unknown constant"))?)
+            }
+            TAG_VARIABLE => Node::Variable(read_name(data, &mut pos)?),
+            TAG_LET => {
+                stack.push(Pending::Let(read_name(data, &mut pos)?, None));
+                continue;
+            }
+            TAG_FUNCTION => {
+                let name = read_name(data, &mut pos)?;
+                let count = read_u32(data, &mut pos)? as usize;
+                if count > 0 {
+                    // Reserve lazily, the count comes from untrusted input
+                    stack.push(Pending::Function(name, count, Vec::new()));
+                    continue;
+                }
+                Node::Function(name, Vec::new())
+            }
+            TAG_FLAT_ADD | TAG_FLAT_MUL => {
+                let count = read_u32(data, &mut pos)? as usize;
+                if count > 0 {
+                    stack.push(Pending::Flat(tag, count, Vec::new()));
+                    continue;
+                }
+                make_flat(tag, Vec::new())
+            }
+            TAG_NEGATIVE | TAG_NOT => {
+                stack.push(Pending::Unary(tag));
+                continue;
+            }
+            TAG_ADD..=TAG_OR => {
+                stack.push(Pending::Binary(tag, None));
+                continue;
+            }
+            _ => return Err(binary_error(&format!("unknown node tag {}", tag))),
+        };
+        // A node is complete, attach it to the operators waiting for it
+        loop {
+            match stack.pop() {
+                None => {
+                    if pos != data.len() {
+                        return Err(binary_error("trailing bytes after expression"));
+                    }
+                    return Ok(node);
+                }
+                Some(Pending::Unary(TAG_NOT)) => node = Node::Not(Box::new(node)),
+                Some(Pending::Unary(_)) => node = Node::Negative(Box::new(node)),
+                Some(Pending::Binary(tag, None)) => {
+                    stack.push(Pending::Binary(tag, Some(node)));
+                    break;
+                }
+                Some(Pending::Binary(tag, Some(left))) => node = make_binary(tag, left, node),
+                Some(Pending::Let(name, None)) => {
+                    stack.push(Pending::Let(name, Some(node)));
+                    break;
+                }
+                Some(Pending::Let(name, Some(value))) => node = Node::Let(name, Box::new(value), Box::new(node)),
+                Some(Pending::Function(name, count, mut args)) => {
+                    args.push(node);
+                    if args.len() < count {
+                        stack.push(Pending::Function(name, count, args));
+                        break;
+                    }
+                    node = Node::Function(name, args);
+                }
+                Some(Pending::Flat(tag, count, mut items)) => {
+                    items.push(node);
+                    if items.len() < count {
+                        stack.push(Pending::Flat(tag, count, items));
+                        break;
+                    }
+                    node = make_flat(tag, items);
+                }
+            }
+        }
+    }
+}
+
+// Read a little-endian u32 starting at `pos`
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32, ParseError> {
+    let bytes = data.get(*pos..*pos + 4).ok_or_else(|| binary_error("truncated length"))?;
+    *pos += 4;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Read a length-prefixed UTF-8 name starting at `pos`
+fn read_name(data: &[u8], pos: &mut usize) -> Result<String, ParseError> {
+    let len = read_u32(data, pos)? as usize;
+    let bytes = data
+        .get(*pos..pos.saturating_add(len))
+        .ok_or_else(|| binary_error("truncated name"))?;
+    *pos += len;
+    String::from_utf8(bytes.to_vec()).map_err(|_| binary_error("name is not UTF-8"))
+}
+
+fn make_binary(tag: u8, left: Node, right: Node) -> Node {
+    let (a, b) = (Box::new(left), Box::new(right));
+    match tag {
+        TAG_ADD => Node::Add(a, b),
+        TAG_SUBTRACT => Node::Subtract(a, b),
+        TAG_MULTIPLY => Node::Multiply(a, b),
+        TAG_DIVIDE => Node::Divide(a, b),
+        TAG_CARET => Node::Caret(a, b),
+        TAG_AND => Node::And(a, b),
+        _ => Node::Or(a, b),
+    }
+}
+
+fn make_flat(tag: u8, items: Vec<Node>) -> Node {
+    if tag == TAG_FLAT_ADD {
+        Node::FlatAdd(items)
+    } else {
+        Node::FlatMul(items)
+    }
+}
+
+fn binary_error(msg: &str) -> ParseError {
+    ParseError::UnableToParse(format!("binary format: {}", msg), None)
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+    use crate::parsemath::parser::Parser;
+
+    #[test]
+    fn test_binary_round_trip() {
+        for expr in ["2", "-5", "2+3*4", "(2+3)*4^2", "8/4-1", "6|2&3", "-(1.5--2)", "x*y_2", "let x = 2 in x*x", "max(1, sin(x), f())", "!(a&!b)"] {
+            let node = Parser::new(expr).unwrap().parse().unwrap();
+            assert_eq!(from_binary_format(&to_binary_format(&node)).unwrap(), node);
+        }
+        for constant in MathConstant::ALL {
+            let node = Node::Constant(constant) * Node::Number(2.0);
+            assert_eq!(from_binary_format(&to_binary_format(&node)).unwrap(), node);
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip_flat_nodes() {
+        for expr in ["1+2+3+4", "2*x*y*3", "(1+2+3)*(4*5*6)-max(a+b+c)"] {
+            let node = crate::parsemath::ast::flatten(Parser::new(expr).unwrap().parse().unwrap());
+            assert_eq!(from_binary_format(&to_binary_format(&node)).unwrap(), node);
+        }
+        for node in [Node::FlatAdd(Vec::new()), Node::FlatMul(Vec::new())] {
+            assert_eq!(from_binary_format(&to_binary_format(&node)).unwrap(), node);
+        }
+        assert!(from_binary_format(&[TAG_FLAT_ADD, 2, 0, 0, 0, TAG_NUMBER]).is_err());
+    }
+
+    #[test]
+    fn test_binary_round_trip_deeply_nested() {
+        let mut node = Node::Number(1.0);
+        for i in 0..500 {
+            node = if i % 2 == 0 {
+                Node::Add(Box::new(node), Box::new(Node::Number(i as f64)))
+            } else {
+                Node::Negative(Box::new(Node::Multiply(Box::new(Node::Number(i as f64)), Box::new(node))))
+            };
+        }
+        assert_eq!(from_binary_format(&to_binary_format(&node)).unwrap(), node);
+    }
+
+    #[test]
+    fn test_binary_number_layout() {
+        let data = to_binary_format(&Node::Number(2.5));
+        assert_eq!(data[0], TAG_NUMBER);
+        assert_eq!(&data[1..], &2.5f64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_binary_malformed_input() {
+        let valid = to_binary_format(&Parser::new("2+3").unwrap().parse().unwrap());
+        for data in [&[][..], &[42], &[TAG_ADD], &valid[..valid.len() - 1], &[TAG_NUMBER, 1, 2]] {
+            assert!(matches!(from_binary_format(data), Err(ParseError::UnableToParse(..))));
+        }
+        assert!(from_binary_format(&[TAG_VARIABLE, 5, 0, 0, 0, b'x']).is_err());
+        assert!(from_binary_format(&[TAG_VARIABLE, 1, 0, 0, 0, 0xFF]).is_err());
+        assert!(from_binary_format(&[TAG_CONSTANT]).is_err());
+        assert!(from_binary_format(&[TAG_CONSTANT, 3]).is_err());
+        let mut trailing = valid.clone();
+        trailing.push(TAG_NUMBER);
+        assert!(matches!(from_binary_format(&trailing), Err(ParseError::UnableToParse(..))));
+    }
+}
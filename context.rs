@@ -0,0 +1,309 @@
+/// This holds the variable bindings and user functions used while evaluating an expression.
+// Standard lib
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// A function of one argument that expressions can call by name, see `EvalContext::set_function`
+pub type UserFunction = fn(f64) -> f64;
+
+#[derive(Debug, Clone, Default)]
+pub struct EvalContext {
+    variables: HashMap<String, f64>,
+    // Functions of one argument, called in place of a built-in function of the same name
+    functions: HashMap<String, UserFunction>,
+}
+
+// Saved copy of a context's variable bindings, see `EvalContext::snapshot`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalContextSnapshot {
+    variables: HashMap<String, f64>,
+}
+
+impl EvalContext {
+    // Create an empty context
+    #[must_use]
+    pub fn new() -> Self {
+        EvalContext::default()
+    }
+
+    // Create a context with the usual math functions of one argument registered: sqrt, abs,
+    // floor, ceil, round, ln, log2, log10, exp, sin, cos, tan, asin, acos and atan
+    #[must_use]
+    pub fn with_standard_functions() -> Self {
+        let mut ctx = EvalContext::new();
+        let functions: [(&str, UserFunction); 15] = [
+            ("sqrt", f64::sqrt),
+            ("abs", f64::abs),
+            ("floor", f64::floor),
+            ("ceil", f64::ceil),
+            ("round", f64::round),
+            ("ln", f64::ln),
+            ("log2", f64::log2),
+            ("log10", f64::log10),
+            ("exp", f64::exp),
+            ("sin", f64::sin),
+            ("cos", f64::cos),
+            ("tan", f64::tan),
+            ("asin", f64::asin),
+            ("acos", f64::acos),
+            ("atan", f64::atan),
+        ];
+        for (name, function) in functions {
+            ctx.set_function(name, function);
+        }
+        ctx
+    }
+
+    // Like `with_standard_functions`, but sin, cos and tan take their argument in degrees and
+    // asin, acos and atan return degrees, so `sin(90)` is 1
+    #[must_use]
+    pub fn with_degree_functions() -> Self {
+        let mut ctx = EvalContext::with_standard_functions();
+        let functions: [(&str, UserFunction); 6] = [
+            ("sin", |x| x.to_radians().sin()),
+            ("cos", |x| x.to_radians().cos()),
+            ("tan", |x| x.to_radians().tan()),
+            ("asin", |x| x.asin().to_degrees()),
+            ("acos", |x| x.acos().to_degrees()),
+            ("atan", |x| x.atan().to_degrees()),
+        ];
+        for (name, function) in functions {
+            ctx.set_function(name, function);
+        }
+        ctx
+    }
+
+    // Bind `name` to `value`, replacing any previous binding
+    pub fn set_var(&mut self, name: &str, value: f64) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    // Look up the value bound to `name`
+    #[must_use]
+    #[inline]
+    pub fn get_var(&self, name: &str) -> Option<f64> {
+        self.variables.get(name).copied()
+    }
+
+    // Register `function` as `name(x)`, replacing any previous function of that name
+    pub fn set_function(&mut self, name: &str, function: UserFunction) {
+        self.functions.insert(name.to_string(), function);
+    }
+
+    // Look up the function registered as `name`
+    #[must_use]
+    #[inline]
+    pub fn get_function(&self, name: &str) -> Option<UserFunction> {
+        self.functions.get(name).copied()
+    }
+
+    // Save the current variable bindings so they can be rolled back with `restore`
+    #[must_use]
+    pub fn snapshot(&self) -> EvalContextSnapshot {
+        EvalContextSnapshot {
+            variables: self.variables.clone(),
+        }
+    }
+
+    // Replace all bindings with the ones saved in `snapshot`
+    pub fn restore(&mut self, snapshot: EvalContextSnapshot) {
+        self.variables = snapshot.variables;
+    }
+
+    // Copy every binding and function of `other` into this context, `other` wins on conflict
+    pub fn merge(&mut self, other: &EvalContext) {
+        for (name, value) in &other.variables {
+            self.variables.insert(name.clone(), *value);
+        }
+        for (name, function) in &other.functions {
+            self.functions.insert(name.clone(), *function);
+        }
+    }
+
+    // Variables bound in both contexts to different values, as
+    // `(name, self_value, other_value)` sorted by name
+    #[must_use]
+    pub fn diff(&self, other: &EvalContext) -> Vec<(String, f64, f64)> {
+        let mut changes: Vec<(String, f64, f64)> = self
+            .variables
+            .iter()
+            .filter_map(|(name, &value)| match other.get_var(name) {
+                Some(other_value) if other_value != value => Some((name.clone(), value, other_value)),
+                _ => None,
+            })
+            .collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    // Hash of all bindings and function names, independent of insertion order.
+    // Two equal contexts always hash the same.
+    #[must_use]
+    pub fn values_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Names of the registered functions, sorted
+    fn function_names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        names
+    }
+}
+
+// Contexts are equal when they bind the same values and register functions under the same
+// names. Functions are told apart by name only: the address of a `fn` is not guaranteed to be
+// unique, or the same for one function across codegen units.
+impl PartialEq for EvalContext {
+    fn eq(&self, other: &Self) -> bool {
+        self.variables == other.variables && self.function_names() == other.function_names()
+    }
+}
+
+impl Hash for EvalContext {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut bindings: Vec<(&String, &f64)> = self.variables.iter().collect();
+        bindings.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in bindings {
+            name.hash(state);
+            // -0.0 equals 0.0, so both hash as 0.0
+            let value = if *value == 0.0 { 0.0 } else { *value };
+            value.to_bits().hash(state);
+        }
+        self.function_names().hash(state);
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get_var() {
+        let mut ctx = EvalContext::new();
+        assert_eq!(ctx.get_var("x"), None);
+        ctx.set_var("x", 1.0);
+        ctx.set_var("x", 2.0);
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 1.0);
+        let snapshot = ctx.snapshot();
+        ctx.set_var("x", 2.0);
+        ctx.set_var("y", 3.0);
+        ctx.restore(snapshot);
+        assert_eq!(ctx.get_var("x"), Some(1.0));
+        assert_eq!(ctx.get_var("y"), None);
+    }
+
+    #[test]
+    fn test_snapshots_are_independent() {
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 1.0);
+        let first = ctx.snapshot();
+        ctx.set_var("x", 2.0);
+        let second = ctx.snapshot();
+        ctx.set_var("x", 3.0);
+
+        ctx.restore(first.clone());
+        assert_eq!(ctx.get_var("x"), Some(1.0));
+        ctx.restore(second.clone());
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+        ctx.set_var("x", 4.0);
+        ctx.restore(first);
+        assert_eq!(ctx.get_var("x"), Some(1.0));
+        ctx.restore(second);
+        assert_eq!(ctx.get_var("x"), Some(2.0));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = EvalContext::new();
+        a.set_var("x", 1.0);
+        a.set_var("y", 2.0);
+        let mut b = EvalContext::new();
+        b.set_var("y", 20.0);
+        b.set_var("z", 30.0);
+        a.merge(&b);
+        assert_eq!(a.get_var("x"), Some(1.0));
+        assert_eq!(a.get_var("y"), Some(20.0));
+        assert_eq!(a.get_var("z"), Some(30.0));
+        assert_eq!(b.get_var("x"), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut a = EvalContext::new();
+        a.set_var("x", 1.0);
+        a.set_var("y", 2.0);
+        a.set_var("w", 0.5);
+        assert!(a.diff(&a.clone()).is_empty());
+
+        let mut b = a.clone();
+        b.set_var("y", 5.0);
+        b.set_var("w", -0.5);
+        b.set_var("z", 9.0);
+        assert_eq!(a.diff(&b), vec![("w".to_string(), 0.5, -0.5), ("y".to_string(), 2.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_values_hash() {
+        let mut a = EvalContext::new();
+        a.set_var("x", 1.0);
+        a.set_var("y", 2.0);
+        let mut b = EvalContext::new();
+        b.set_var("y", 2.0);
+        b.set_var("x", 1.0);
+        assert_eq!(a.values_hash(), b.values_hash());
+        b.set_var("x", 1.5);
+        assert_ne!(a.values_hash(), b.values_hash());
+        b.set_var("x", 1.0);
+        b.set_var("y", -0.0);
+        a.set_var("y", 0.0);
+        assert_eq!(a, b);
+        assert_eq!(a.values_hash(), b.values_hash());
+    }
+
+    #[test]
+    fn test_functions_compare_by_name() {
+        let mut a = EvalContext::new();
+        let mut b = EvalContext::new();
+        a.set_function("f", f64::floor);
+        b.set_function("f", f64::ceil);
+        assert_eq!(a, b);
+        assert_eq!(a.values_hash(), b.values_hash());
+        b.set_function("g", f64::floor);
+        assert_ne!(a, b);
+        assert_ne!(a.values_hash(), b.values_hash());
+        assert_eq!(EvalContext::with_standard_functions(), EvalContext::with_standard_functions());
+    }
+
+    #[test]
+    fn test_with_standard_functions() {
+        use crate::parsemath::ast::eval_with_context;
+        use crate::parsemath::parser::Parser;
+        let eval = |expr: &str, ctx: &EvalContext| {
+            eval_with_context(&Parser::new(expr).unwrap().parse().unwrap(), ctx)
+        };
+        let ctx = EvalContext::with_standard_functions();
+        assert_eq!(eval("sqrt(abs(-4))", &ctx), Ok(2.0));
+        assert_eq!(eval("floor(2.7) + ceil(0.2) + round(1.5) + log2(8) + log10(100)", &ctx), Ok(10.0));
+        assert!(eval("floor(2.7)", &EvalContext::new()).is_err());
+
+        let mut first = EvalContext::with_standard_functions();
+        let second = EvalContext::with_standard_functions();
+        first.set_function("floor", f64::ceil);
+        first.set_var("x", 1.0);
+        assert_eq!(eval("floor(2.5)", &first), Ok(3.0));
+        assert_eq!(eval("floor(2.5)", &second), Ok(2.0));
+        assert_eq!(second.get_var("x"), None);
+        assert_ne!(first.values_hash(), second.values_hash());
+    }
+}
@@ -0,0 +1,110 @@
+/// This compares the parsed trees of two versions of an expression, so that an editor can
+/// show which subexpressions were added, removed or replaced by an edit.
+// Standard lib
+use std::mem;
+
+// Internal modules
+use super::ast::Node;
+use super::parser::{ParseError, Parser};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExpressionDiff {
+    // Subtrees only in the new expression
+    pub added: Vec<Node>,
+    // Subtrees only in the old expression
+    pub removed: Vec<Node>,
+    // Pairs of (old, new) subtrees found at the same place in both expressions
+    pub changed: Vec<(Node, Node)>,
+}
+
+impl ExpressionDiff {
+    // Parse `a` and `b` and compare them, `a` being the old expression and `b` the new one.
+    // `2+3*4` to `2+5*4` changes 3 into 5, `2+3` to `2+3+4` adds 4.
+    pub fn diff(a: &str, b: &str) -> Result<ExpressionDiff, ParseError> {
+        let old = Parser::new(a)?.parse()?;
+        let new = Parser::new(b)?.parse()?;
+        let mut diff = ExpressionDiff::default();
+        diff.compare(&old, &new);
+        Ok(diff)
+    }
+
+    // True when both expressions parse to the same tree
+    #[must_use]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    fn compare(&mut self, old: &Node, new: &Node) {
+        if old == new {
+            return;
+        }
+        let (old_children, new_children) = (old.children(), new.children());
+        // One tree wrapping the other whole, e.g. `2+3` becoming `(2+3)+4`
+        if let Some(position) = new_children.iter().position(|child| *child == old) {
+            self.added.extend(except(&new_children, position));
+        } else if let Some(position) = old_children.iter().position(|child| *child == new) {
+            self.removed.extend(except(&old_children, position));
+        } else if same_operator(old, new) && !old_children.is_empty() {
+            for (old_child, new_child) in old_children.iter().zip(&new_children) {
+                self.compare(old_child, new_child);
+            }
+            // Extra arguments of functions and flat sums and products
+            let common = old_children.len().min(new_children.len());
+            self.removed.extend(old_children[common..].iter().map(|&child| child.clone()));
+            self.added.extend(new_children[common..].iter().map(|&child| child.clone()));
+        } else {
+            self.changed.push((old.clone(), new.clone()));
+        }
+    }
+}
+
+// Clones of every node of `nodes` but the one at `skip`
+fn except(nodes: &[&Node], skip: usize) -> Vec<Node> {
+    nodes.iter().enumerate().filter(|(i, _)| *i != skip).map(|(_, &node)| node.clone()).collect()
+}
+
+// True if `a` and `b` apply the same operator, function or binding to their operands
+fn same_operator(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (Node::Function(f, _), Node::Function(g, _)) | (Node::Let(f, ..), Node::Let(g, ..)) => f == g,
+        _ => mem::discriminant(a) == mem::discriminant(b),
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+    use crate::parsemath::ast::Node::*;
+
+    #[test]
+    fn test_diff_changed() {
+        let diff = ExpressionDiff::diff("2+3*4", "2+5*4").unwrap();
+        assert_eq!(diff.changed, [(Number(3.0), Number(5.0))]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        let diff = ExpressionDiff::diff("sin(x) + 1", "cos(x) + y").unwrap();
+        let sin = Function("sin".into(), vec![Variable("x".into())]);
+        let cos = Function("cos".into(), vec![Variable("x".into())]);
+        assert_eq!(diff.changed, [(sin, cos), (Number(1.0), Variable("y".into()))]);
+        assert!(ExpressionDiff::diff("2 + 3", "2+3").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_added_and_removed() {
+        let diff = ExpressionDiff::diff("2+3", "2+3+4").unwrap();
+        assert_eq!(diff.added, [Number(4.0)]);
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+        let diff = ExpressionDiff::diff("2+3+4", "2+3").unwrap();
+        assert_eq!(diff.removed, [Number(4.0)]);
+        let diff = ExpressionDiff::diff("max(1, 2, 3)", "max(1, 5)").unwrap();
+        assert_eq!(diff.changed, [(Number(2.0), Number(5.0))]);
+        assert_eq!(diff.removed, [Number(3.0)]);
+    }
+
+    #[test]
+    fn test_diff_errors() {
+        assert!(ExpressionDiff::diff("2+", "2").is_err());
+        assert!(ExpressionDiff::diff("2", "(2").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+/// This wraps an expression string together with its parsed AST,
+/// so that it can be evaluated many times without re-parsing.
+// Standard lib
+use std::cell::Cell;
+
+// Internal modules
+use super::ast::{self, EvalError, Node};
+use super::context::EvalContext;
+use super::parser::{ParseError, Parser};
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    ast: Node,
+    // Last result, keyed on the hash of the context it was computed with
+    cache: Cell<Option<(u64, f64)>>,
+    cache_hits: Cell<usize>,
+}
+
+impl Expression {
+    // Parse `expr` eagerly
+    pub fn new(expr: &str) -> Result<Self, ParseError> {
+        let ast = Parser::new(expr)?.parse()?;
+        Ok(Expression {
+            source: expr.to_string(),
+            ast,
+            cache: Cell::new(None),
+            cache_hits: Cell::new(0),
+        })
+    }
+
+    // Wrap an already parsed AST, using its infix rendering as the source
+    fn from_ast(ast: Node) -> Self {
+        Expression {
+            source: ast.to_string(),
+            ast,
+            cache: Cell::new(None),
+            cache_hits: Cell::new(0),
+        }
+    }
+
+    // The composition f(g), replacing every free occurrence of `var` in `f` with the tree of `g`
+    pub fn compose(f: Expression, g: Expression, var: &str) -> Result<Expression, ParseError> {
+        Ok(Expression::from_ast(ast::substitute(&f.ast, var, &g.ast)))
+    }
+
+    // The shortest form of `expr` this crate can find: constant subtrees folded, identity
+    // operations removed with `ast::simplify` and only the parentheses the parser needs, so
+    // `(2+3)*1` is `5` and `x*1+0` is `x`. The result parses back to an equivalent expression.
+    pub fn minify(expr: &str) -> Result<String, ParseError> {
+        let mut ast = Parser::new(expr)?.parse()?;
+        // Subtrees that fail to evaluate are left in place, to fail again when evaluated
+        let _ = ast::eval_annotate(&mut ast);
+        ast = ast::simplify(ast);
+        // Simplifying can leave new constant subtrees, e.g. `2 + x^0` gives `2 + 1`
+        let _ = ast::eval_annotate(&mut ast);
+        Ok(ast::to_infix_minimal_parens(&ast))
+    }
+
+    // True when the expression reads no variables, so every context gives the same result
+    #[must_use]
+    pub fn is_constant(&self) -> bool {
+        ast::free_variables(&self.ast).is_empty()
+    }
+
+    // Evaluate with the variables of `ctx`. The result is memoized, so evaluating
+    // again with the same variable values returns it without walking the AST.
+    pub fn eval(&self, ctx: &EvalContext) -> Result<f64, EvalError> {
+        let key = ctx.values_hash();
+        if let Some((cached_key, value)) = self.cache.get() {
+            if cached_key == key {
+                self.cache_hits.set(self.cache_hits.get() + 1);
+                return Ok(value);
+            }
+        }
+        let value = ast::eval_with_context(&self.ast, ctx)?;
+        self.cache.set(Some((key, value)));
+        Ok(value)
+    }
+
+    // The original expression string
+    #[must_use]
+    #[inline]
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // The parsed AST
+    #[must_use]
+    #[inline]
+    pub fn ast(&self) -> &Node {
+        &self.ast
+    }
+
+    // Number of `eval` calls answered from the cache
+    #[must_use]
+    #[inline]
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits.get()
+    }
+}
+
+// Parse and evaluate a value holding expression text, so that `"2^10".eval()?` works as
+// `evaluate` does. Other types only need `eval_with`, usually by forwarding to their text.
+pub trait Evaluatable {
+    // Evaluate with the variables of `ctx`
+    fn eval_with(&self, ctx: &EvalContext) -> Result<f64, ParseError>;
+
+    // Evaluate an expression reading no variables
+    fn eval(&self) -> Result<f64, ParseError> {
+        self.eval_with(&EvalContext::new())
+    }
+}
+
+impl Evaluatable for str {
+    fn eval_with(&self, ctx: &EvalContext) -> Result<f64, ParseError> {
+        let ast = Parser::new(self)?.parse()?;
+        ast::eval_with_context(&ast, ctx).map_err(|e| ParseError::from(e).with_expression(self))
+    }
+}
+
+impl Evaluatable for String {
+    fn eval_with(&self, ctx: &EvalContext) -> Result<f64, ParseError> {
+        self.as_str().eval_with(ctx)
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression_parses_eagerly() {
+        let expr = Expression::new("2*x+1").unwrap();
+        assert_eq!(expr.source(), "2*x+1");
+        assert_eq!(expr.ast(), &Parser::new("2*x+1").unwrap().parse().unwrap());
+        assert!(Expression::new("2*").is_err());
+    }
+
+    #[test]
+    fn test_expression_cache_hits() {
+        let expr = Expression::new("2*x+1").unwrap();
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 4.0);
+        for _ in 0..1000 {
+            assert_eq!(expr.eval(&ctx).unwrap(), 9.0);
+        }
+        assert_eq!(expr.cache_hits(), 999);
+    }
+
+    #[test]
+    fn test_expression_cache_invalidated_by_new_values() {
+        let expr = Expression::new("2*x+1").unwrap();
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 1.0);
+        assert_eq!(expr.eval(&ctx).unwrap(), 3.0);
+        ctx.set_var("x", 2.0);
+        assert_eq!(expr.eval(&ctx).unwrap(), 5.0);
+        assert_eq!(expr.cache_hits(), 0);
+        assert_eq!(expr.eval(&EvalContext::new()), Err(EvalError::UnknownVariable("x".into())));
+    }
+
+    #[test]
+    fn test_compose() {
+        let f = Expression::new("x^2").unwrap();
+        let g = Expression::new("x+1").unwrap();
+        let composed = Expression::compose(f, g, "x").unwrap();
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 3.0);
+        assert_eq!(composed.eval(&ctx), Ok(16.0));
+        let expanded = Expression::new("x^2+2*x+1").unwrap();
+        assert_eq!(composed.eval(&ctx), expanded.eval(&ctx));
+        assert_eq!(Expression::new(composed.source()).unwrap().ast(), composed.ast());
+    }
+
+    #[test]
+    fn test_compose_constant() {
+        let f = Expression::new("x*y + 1").unwrap();
+        assert!(!f.is_constant());
+        let composed = Expression::compose(f, Expression::new("2").unwrap(), "x").unwrap();
+        assert!(!composed.is_constant());
+        let composed = Expression::compose(composed, Expression::new("(4-1)").unwrap(), "y").unwrap();
+        assert!(composed.is_constant());
+        assert_eq!(composed.eval(&EvalContext::new()), Ok(7.0));
+    }
+
+    #[test]
+    fn test_minify() {
+        let cases = [
+            ("(2+3)*1", "5"),
+            ("x*1+0", "x"),
+            ("--x", "x"),
+            ("-(-(x+1))*2", "(x + 1) * 2"),
+            ("(x*(2^3))+(y/1)", "x * 8 + y"),
+            ("2 + x^0", "3"),
+            ("((x))", "x"),
+            ("x - (y - 1)", "x - (y - 1)"),
+            ("1/0 + x", "1 / 0 + x"),
+        ];
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 3.0);
+        ctx.set_var("y", 5.0);
+        for (expr, expected) in cases {
+            let minified = Expression::minify(expr).unwrap();
+            assert_eq!(minified, expected, "{}", expr);
+            let roundtrip = Expression::new(&minified).unwrap();
+            assert_eq!(roundtrip.eval(&ctx), Expression::new(expr).unwrap().eval(&ctx), "{}", expr);
+        }
+        assert!(Expression::minify("2*").is_err());
+    }
+
+    #[test]
+    fn test_evaluatable() -> Result<(), ParseError> {
+        assert_eq!("2^10".eval()?, 1024.0);
+        assert_eq!(String::from("let x = 3 in x*x").eval()?, 9.0);
+        let mut ctx = EvalContext::new();
+        ctx.set_var("r", 2.0);
+        assert_eq!("3*r^2".eval_with(&ctx)?, 12.0);
+        let error = "r".eval().unwrap_err();
+        assert!(matches!(error, ParseError::EvalFailed(EvalError::UnknownVariable(_), _)));
+        assert!("2 +".eval().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluatable_custom_type() {
+        struct Formula(String);
+        impl Evaluatable for Formula {
+            fn eval_with(&self, ctx: &EvalContext) -> Result<f64, ParseError> {
+                self.0.eval_with(ctx)
+            }
+        }
+        let formula = Formula("x + 1".into());
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 41.0);
+        assert_eq!(formula.eval_with(&ctx).unwrap(), 42.0);
+        assert_eq!(Formula("6*7".into()).eval().unwrap(), 42.0);
+    }
+}
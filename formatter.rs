@@ -0,0 +1,646 @@
+/// This renders an AST as text. Each notation implements the `Formatter` trait,
+/// so the output style can be chosen at runtime:
+/// `let fmt: Box<dyn Formatter> = Box::new(LatexFormatter::default()); fmt.format(&node)`
+// Standard lib
+use std::fmt;
+
+// Internal modules
+use super::ast::{binary_chain, MathConstant, Node};
+
+// Options shared by all formatters
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatterConfig {
+    // Put spaces around binary operators (default true)
+    pub spacing: bool,
+    // Maximum digits after the decimal point, trailing zeros are dropped (default 10)
+    pub decimal_precision: usize,
+    // Number style, overrides `decimal_precision` when set (default None)
+    pub numbers: Option<NumberFormatter>,
+}
+
+impl Default for FormatterConfig {
+    fn default() -> Self {
+        FormatterConfig {
+            spacing: true,
+            decimal_precision: 10,
+            numbers: None,
+        }
+    }
+}
+
+impl FormatterConfig {
+    // Render a number using `numbers` if set, otherwise `decimal_precision`
+    #[must_use]
+    pub fn format_number(&self, n: f64) -> String {
+        if let Some(numbers) = &self.numbers {
+            return numbers.to_string(n);
+        }
+        let mut s = format!("{:.*}", self.decimal_precision, n);
+        if s.contains('.') {
+            let trimmed = s.trim_end_matches('0').trim_end_matches('.').len();
+            s.truncate(trimmed);
+        }
+        if s == "-0" {
+            s = "0".to_string();
+        }
+        s
+    }
+
+    fn operator(&self, symbol: &str) -> String {
+        if self.spacing {
+            format!(" {} ", symbol)
+        } else {
+            symbol.to_string()
+        }
+    }
+}
+
+// Layout used by NumberFormatter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notation {
+    // Plain digits such as `1234.5`
+    Fixed,
+    // One digit before the point and a power of ten such as `1.2345e3`
+    Scientific,
+    // Scientific with the exponent a multiple of three such as `1.2345e3` or `12.5e-3`
+    Engineering,
+    // Fixed for magnitudes from 1e-7 up to 1e21 and Scientific outside, as JavaScript prints
+    // numbers, so `1e300` is not written out as 301 digits
+    Auto,
+}
+
+// Controls how Node::Number values are printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumberFormatter {
+    // Digits kept after rounding, at least 1 (default 17). A number is written in the fewest
+    // digits that read back as the same f64 when those are no more than this, so with 17
+    // every f64 is printed exactly and 0.1 is `0.1` rather than `0.10000000000000001`.
+    pub significant_digits: usize,
+    pub notation: Notation,
+    // Drop zeros at the end of the fractional part (default true)
+    pub strip_trailing_zeros: bool,
+    // Separator between groups of three integer digits in Fixed notation (default None)
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for NumberFormatter {
+    fn default() -> Self {
+        NumberFormatter {
+            significant_digits: 17,
+            notation: Notation::Auto,
+            strip_trailing_zeros: true,
+            thousands_separator: None,
+        }
+    }
+}
+
+impl NumberFormatter {
+    // Render a number with these options
+    #[must_use]
+    pub fn to_string(&self, n: f64) -> String {
+        if !n.is_finite() {
+            return n.to_string();
+        }
+        let (negative, mut digits, exponent) = Self::digits(n, self.significant_digits.max(1));
+        let notation = match self.notation {
+            Notation::Auto if (-7..21).contains(&exponent) => Notation::Fixed,
+            Notation::Auto => Notation::Scientific,
+            notation => notation,
+        };
+        let (point, exponent) = match notation {
+            Notation::Fixed => (exponent + 1, None),
+            Notation::Engineering => (exponent.rem_euclid(3) + 1, Some(exponent - exponent.rem_euclid(3))),
+            Notation::Scientific | Notation::Auto => (1, Some(exponent)),
+        };
+        // Place the decimal point `point` digits from the left, padding with zeros either side
+        if point <= 0 {
+            digits.insert_str(0, &format!("0.{}", "0".repeat(point.unsigned_abs() as usize)));
+        } else if point as usize >= digits.len() {
+            digits.push_str(&"0".repeat(point as usize - digits.len()));
+        } else {
+            digits.insert(point as usize, '.');
+        }
+        let separator = if exponent.is_none() { self.thousands_separator } else { None };
+        let mut s = self.finish(digits, separator);
+        if negative && s.bytes().any(|b| matches!(b, b'1'..=b'9')) {
+            s.insert(0, '-');
+        }
+        match exponent {
+            Some(exponent) => format!("{}e{}", s, exponent),
+            None => s,
+        }
+    }
+
+    // Sign, the first `count` significant decimal digits and the power of ten of the first one.
+    // The shortest digits reading back as `n` are used when they fit, padded with zeros, and
+    // `n` rounded to `count` digits otherwise.
+    fn digits(n: f64, count: usize) -> (bool, String, i32) {
+        let shortest = format!("{:e}", n.abs());
+        let scientific = if shortest.split('e').next().map_or(0, |m| m.replace('.', "").len()) <= count {
+            shortest
+        } else {
+            format!("{:.*e}", count - 1, n.abs())
+        };
+        let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+        let mut digits = mantissa.replace('.', "");
+        digits.push_str(&"0".repeat(count.saturating_sub(digits.len())));
+        let exponent = if n == 0.0 { 0 } else { exponent.parse().unwrap_or(0) };
+        (n.is_sign_negative(), digits, exponent)
+    }
+
+    // Apply trailing zero stripping and digit grouping to a plain decimal
+    fn finish(&self, mut s: String, separator: Option<char>) -> String {
+        if self.strip_trailing_zeros && s.contains('.') {
+            let trimmed = s.trim_end_matches('0').trim_end_matches('.').len();
+            s.truncate(trimmed);
+        }
+        let Some(separator) = separator else {
+            return s;
+        };
+        let (sign, rest) = s.split_at(if s.starts_with('-') { 1 } else { 0 });
+        let (int_part, frac_part) = rest.split_at(rest.find('.').unwrap_or(rest.len()));
+        let mut grouped = String::from(sign);
+        for (i, c) in int_part.chars().enumerate() {
+            if i > 0 && (int_part.len() - i) % 3 == 0 {
+                grouped.push(separator);
+            }
+            grouped.push(c);
+        }
+        grouped.push_str(frac_part);
+        grouped
+    }
+}
+
+// A number printed in hexadecimal with `{:x}` or `{:X}`, flags such as `{:#x}` included.
+// Only non-negative integers below 2^64 have a hex form, formatting anything else is an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumberDisplay(pub f64);
+
+impl NumberDisplay {
+    fn as_integer(self) -> Result<u64, fmt::Error> {
+        let NumberDisplay(value) = self;
+        if value.fract() == 0.0 && value >= 0.0 && value < u64::MAX as f64 {
+            Ok(value as u64)
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+impl fmt::LowerHex for NumberDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.as_integer()?, f)
+    }
+}
+
+impl fmt::UpperHex for NumberDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.as_integer()?, f)
+    }
+}
+
+// Renders a Node tree in a particular notation
+pub trait Formatter {
+    fn format_node(&self, node: &Node) -> String;
+
+    // Shorthand for `format_node`
+    fn format(&self, node: &Node) -> String {
+        self.format_node(node)
+    }
+}
+
+// True for binary and n-ary operators, which need parentheses as an operand in infix
+fn is_operator(node: &Node) -> bool {
+    use self::Node::*;
+    match node {
+        Add(..) | Subtract(..) | Multiply(..) | Divide(..) | Caret(..) | And(..) | Or(..) => true,
+        FlatAdd(_) | FlatMul(_) => true,
+        Number(_) | Constant(_) | Variable(_) | Negative(_) | Not(_) | Let(..) | Function(..) => false,
+    }
+}
+
+// Standard infix notation such as `2 + (3 * 4)`.
+// Every nested operator is parenthesised, so the output re-parses to the same tree as long as
+// its numbers do, which rounding them to `decimal_precision` digits can prevent.
+#[derive(Debug, Clone, Default)]
+pub struct InfixFormatter {
+    pub config: FormatterConfig,
+}
+
+impl InfixFormatter {
+    fn operand(&self, node: &Node) -> String {
+        match node {
+            Node::Number(n) if *n < 0.0 => format!("({})", self.format_node(node)),
+            Node::Let(..) => format!("({})", self.format_node(node)),
+            _ if is_operator(node) => {
+                format!("({})", self.format_node(node))
+            }
+            _ => self.format_node(node),
+        }
+    }
+
+    fn binary(&self, symbol: &str, a: &Node, b: &Node) -> String {
+        format!("{}{}{}", self.operand(a), self.config.operator(symbol), self.operand(b))
+    }
+
+    // An n-ary sum or product, its identity when there are no operands
+    fn flat(&self, symbol: &str, identity: f64, items: &[Node]) -> String {
+        if items.is_empty() {
+            return self.config.format_number(identity);
+        }
+        let items: Vec<String> = items.iter().map(|item| self.operand(item)).collect();
+        items.join(&self.config.operator(symbol))
+    }
+}
+
+impl Formatter for InfixFormatter {
+    fn format_node(&self, node: &Node) -> String {
+        match node {
+            Node::Number(n) => self.config.format_number(*n),
+            Node::Constant(c) => c.name().to_string(),
+            Node::Variable(name) => name.clone(),
+            Node::Negative(a) => format!("-{}", self.operand(a)),
+            Node::Not(a) => format!("!{}", self.operand(a)),
+            Node::Let(name, value, body) => {
+                format!("let {} = {} in {}", name, self.format_node(value), self.format_node(body))
+            }
+            Node::Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.format_node(arg)).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            Node::FlatAdd(items) => self.flat("+", 0.0, items),
+            Node::FlatMul(items) => self.flat("*", 1.0, items),
+            Node::Add(a, b) => self.binary("+", a, b),
+            Node::Subtract(a, b) => self.binary("-", a, b),
+            Node::Multiply(a, b) => self.binary("*", a, b),
+            Node::Divide(a, b) => self.binary("/", a, b),
+            Node::Caret(a, b) => self.binary("^", a, b),
+            Node::And(a, b) => self.binary("&", a, b),
+            Node::Or(a, b) => self.binary("|", a, b),
+        }
+    }
+}
+
+// Polish notation with the operator first, such as `+ 2 * 3 4`.
+// Unary minus is written as `neg`, a let binding as `let name value body` and a call as `name args`.
+#[derive(Debug, Clone, Default)]
+pub struct PrefixFormatter {
+    pub config: FormatterConfig,
+}
+
+impl PrefixFormatter {
+    fn binary(&self, symbol: &str, a: &Node, b: &Node) -> String {
+        format!("{} {} {}", symbol, self.format_node(a), self.format_node(b))
+    }
+}
+
+impl Formatter for PrefixFormatter {
+    fn format_node(&self, node: &Node) -> String {
+        match node {
+            Node::Number(n) => self.config.format_number(*n),
+            Node::Constant(c) => c.name().to_string(),
+            Node::Variable(name) => name.clone(),
+            Node::Negative(a) => format!("neg {}", self.format_node(a)),
+            Node::Not(a) => format!("! {}", self.format_node(a)),
+            Node::Let(name, value, body) => {
+                format!("let {} {} {}", name, self.format_node(value), self.format_node(body))
+            }
+            Node::Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.format_node(arg)).collect();
+                format!("{} {}", name, args.join(" "))
+            }
+            // Operators take two operands here, so n-ary nodes are written as their binary chain
+            Node::FlatAdd(_) | Node::FlatMul(_) => self.format_node(&binary_chain(node)),
+            Node::Add(a, b) => self.binary("+", a, b),
+            Node::Subtract(a, b) => self.binary("-", a, b),
+            Node::Multiply(a, b) => self.binary("*", a, b),
+            Node::Divide(a, b) => self.binary("/", a, b),
+            Node::Caret(a, b) => self.binary("^", a, b),
+            Node::And(a, b) => self.binary("&", a, b),
+            Node::Or(a, b) => self.binary("|", a, b),
+        }
+    }
+}
+
+// Reverse Polish notation with the operator last, such as `2 3 4 * +`.
+// Unary minus is written as `neg`, a let binding as `value body name let` and a call as `args name`.
+#[derive(Debug, Clone, Default)]
+pub struct PostfixFormatter {
+    pub config: FormatterConfig,
+}
+
+impl PostfixFormatter {
+    fn binary(&self, symbol: &str, a: &Node, b: &Node) -> String {
+        format!("{} {} {}", self.format_node(a), self.format_node(b), symbol)
+    }
+}
+
+impl Formatter for PostfixFormatter {
+    fn format_node(&self, node: &Node) -> String {
+        match node {
+            Node::Number(n) => self.config.format_number(*n),
+            Node::Constant(c) => c.name().to_string(),
+            Node::Variable(name) => name.clone(),
+            Node::Negative(a) => format!("{} neg", self.format_node(a)),
+            Node::Not(a) => format!("{} !", self.format_node(a)),
+            Node::Let(name, value, body) => {
+                format!("{} {} {} let", self.format_node(value), self.format_node(body), name)
+            }
+            Node::Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.format_node(arg)).collect();
+                format!("{} {}", args.join(" "), name)
+            }
+            // Operators take two operands here, so n-ary nodes are written as their binary chain
+            Node::FlatAdd(_) | Node::FlatMul(_) => self.format_node(&binary_chain(node)),
+            Node::Add(a, b) => self.binary("+", a, b),
+            Node::Subtract(a, b) => self.binary("-", a, b),
+            Node::Multiply(a, b) => self.binary("*", a, b),
+            Node::Divide(a, b) => self.binary("/", a, b),
+            Node::Caret(a, b) => self.binary("^", a, b),
+            Node::And(a, b) => self.binary("&", a, b),
+            Node::Or(a, b) => self.binary("|", a, b),
+        }
+    }
+}
+
+// LaTeX math markup such as `2 + 3 \cdot 4` or `\frac{1}{x}`, with parentheses only where needed
+#[derive(Debug, Clone, Default)]
+pub struct LatexFormatter {
+    pub config: FormatterConfig,
+}
+
+impl LatexFormatter {
+    // Binding strength used to decide where parentheses are required
+    fn precedence(node: &Node) -> u8 {
+        use self::Node::*;
+        match node {
+            Let(..) => 0,
+            And(..) | Or(..) => 1,
+            Add(..) | Subtract(..) => 2,
+            Multiply(..) | Divide(..) => 3,
+            FlatAdd(items) if !items.is_empty() => 2,
+            FlatMul(items) if !items.is_empty() => 3,
+            Caret(..) => 4,
+            Negative(_) | Not(_) => 5,
+            Number(n) if *n < 0.0 => 5,
+            Number(_) | Constant(_) | Variable(_) | Function(..) | FlatAdd(_) | FlatMul(_) => 6,
+        }
+    }
+
+    fn wrap(&self, node: &Node, parens: bool) -> String {
+        if parens {
+            format!("\\left({}\\right)", self.format_node(node))
+        } else {
+            self.format_node(node)
+        }
+    }
+}
+
+impl Formatter for LatexFormatter {
+    fn format_node(&self, node: &Node) -> String {
+        use self::Node::*;
+        let prec = LatexFormatter::precedence(node);
+        let child_prec = LatexFormatter::precedence;
+        let op = |symbol: &str, a: &Node, b: &Node, right_strict: bool| {
+            let right_parens = child_prec(b) < prec || (right_strict && child_prec(b) == prec);
+            format!(
+                "{}{}{}",
+                self.wrap(a, child_prec(a) < prec),
+                self.config.operator(symbol),
+                self.wrap(b, right_parens)
+            )
+        };
+        match node {
+            Number(n) => self.config.format_number(*n),
+            Constant(MathConstant::E) => "e".to_string(),
+            Constant(c) => format!("\\{}", c.name()),
+            Variable(name) => name.clone(),
+            Let(name, value, body) => format!(
+                "\\mathrm{{let}}\\ {} = {}\\ \\mathrm{{in}}\\ {}",
+                name,
+                self.format_node(value),
+                self.format_node(body)
+            ),
+            Function(name, args) if name == "sqrt" && args.len() == 1 => {
+                format!("\\sqrt{{{}}}", self.format_node(&args[0]))
+            }
+            Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.format_node(arg)).collect();
+                let name = match name.as_str() {
+                    "sin" | "cos" | "tan" | "exp" | "ln" | "min" | "max" => format!("\\{}", name),
+                    _ => format!("\\operatorname{{{}}}", name),
+                };
+                format!("{}\\left({}\\right)", name, args.join(", "))
+            }
+            Negative(a) => format!("-{}", self.wrap(a, child_prec(a) < prec)),
+            Not(a) => format!("\\lnot {}", self.wrap(a, child_prec(a) < prec)),
+            Add(a, b) => op("+", a, b, false),
+            Subtract(a, b) => op("-", a, b, true),
+            Multiply(a, b) => op("\\cdot", a, b, false),
+            Divide(a, b) => format!("\\frac{{{}}}{{{}}}", self.format_node(a), self.format_node(b)),
+            Caret(a, b) => {
+                let base = self.wrap(a, child_prec(a) <= prec);
+                format!("{{{}}}^{{{}}}", base, self.format_node(b))
+            }
+            FlatAdd(items) | FlatMul(items) => {
+                let (symbol, identity) = if matches!(node, FlatAdd(_)) { ("+", 0.0) } else { ("\\cdot", 1.0) };
+                if items.is_empty() {
+                    return self.config.format_number(identity);
+                }
+                let symbol = self.config.operator(symbol);
+                let items: Vec<String> =
+                    items.iter().map(|item| self.wrap(item, child_prec(item) < prec)).collect();
+                items.join(&symbol)
+            }
+            And(a, b) => op("\\land", a, b, true),
+            Or(a, b) => op("\\lor", a, b, true),
+        }
+    }
+}
+
+// Display renders a Node with the default InfixFormatter, numbers written by
+// NumberFormatter::default(): exactly, and with an exponent outside 1e-7 to 1e21
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let config = FormatterConfig { numbers: Some(NumberFormatter::default()), ..FormatterConfig::default() };
+        write!(f, "{}", InfixFormatter { config }.format_node(self))
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+    use crate::parsemath::parser::Parser;
+
+    #[test]
+    fn test_number_display_hex() {
+        assert_eq!(format!("{:x}", NumberDisplay(255.0)), "ff");
+        assert_eq!(format!("{:X}", NumberDisplay(255.0)), "FF");
+        assert_eq!(format!("{:#x}", NumberDisplay(15.0)), "0xf");
+        assert_eq!(format!("{:04X}", NumberDisplay(10.0)), "000A");
+        assert_eq!(format!("{:x}", NumberDisplay(0.0)), "0");
+    }
+
+    #[test]
+    fn test_number_display_hex_errors() {
+        use std::fmt::Write;
+        for value in [3.14, -1.0, f64::INFINITY, f64::NAN, 2f64.powi(64)] {
+            assert!(write!(String::new(), "{:x}", NumberDisplay(value)).is_err());
+            assert!(write!(String::new(), "{:X}", NumberDisplay(value)).is_err());
+        }
+    }
+
+    fn parse(expr: &str) -> Node {
+        Parser::new(expr).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn test_formatters_distinct_output() {
+        let node = parse("2+3*4-x/2");
+        let formatters: Vec<Box<dyn Formatter>> = vec![
+            Box::new(InfixFormatter::default()),
+            Box::new(PrefixFormatter::default()),
+            Box::new(PostfixFormatter::default()),
+            Box::new(LatexFormatter::default()),
+        ];
+        let outputs: Vec<String> = formatters.iter().map(|f| f.format(&node)).collect();
+        for (i, out) in outputs.iter().enumerate() {
+            assert!(!out.is_empty());
+            assert!(outputs[i + 1..].iter().all(|other| other != out));
+        }
+        assert_eq!(outputs[0], "(2 + (3 * 4)) - (x / 2)");
+        assert_eq!(outputs[1], "- + 2 * 3 4 / x 2");
+        assert_eq!(outputs[2], "2 3 4 * + x 2 / -");
+        assert_eq!(outputs[3], "2 + 3 \\cdot 4 - \\frac{x}{2}");
+    }
+
+    #[test]
+    fn test_infix_reparses() {
+        let compact = InfixFormatter {
+            config: FormatterConfig { spacing: false, ..FormatterConfig::default() },
+        };
+        for expr in ["2+3*4", "(2+3)*4", "2^3^2", "1-(2-3)", "-(4+x)", "6|2&3", "-2.5*-y"] {
+            let node = parse(expr);
+            assert_eq!(parse(&node.to_string()), node);
+            assert_eq!(parse(&compact.format(&node)), node);
+        }
+        assert_eq!(compact.format(&parse("-(4+x)")), "-(4+x)");
+    }
+
+    #[test]
+    fn test_decimal_precision() {
+        let config = FormatterConfig { decimal_precision: 2, ..FormatterConfig::default() };
+        assert_eq!(config.format_number(3.14159), "3.14");
+        assert_eq!(config.format_number(2.0), "2");
+        assert_eq!(config.format_number(-0.001), "0");
+        assert_eq!(FormatterConfig::default().format_number(0.1), "0.1");
+    }
+
+    #[test]
+    fn test_number_formatter_fixed() {
+        let three = NumberFormatter { significant_digits: 3, ..NumberFormatter::default() };
+        assert_eq!(three.to_string(3.14159), "3.14");
+        assert_eq!(three.to_string(1234567.0), "1230000");
+        assert_eq!(three.to_string(-0.0001), "-0.0001");
+        let grouped = NumberFormatter { thousands_separator: Some(','), ..NumberFormatter::default() };
+        assert_eq!(grouped.to_string(1000000.0), "1,000,000");
+        assert_eq!(grouped.to_string(-12345.5), "-12,345.5");
+        assert_eq!(grouped.to_string(999.0), "999");
+        let padded = NumberFormatter { significant_digits: 4, strip_trailing_zeros: false, ..three };
+        assert_eq!(padded.to_string(2.5), "2.500");
+        assert_eq!(NumberFormatter::default().to_string(std::f64::consts::PI), "3.141592653589793");
+    }
+
+    #[test]
+    fn test_number_formatter_auto_notation() {
+        let auto = NumberFormatter::default();
+        assert_eq!(auto.to_string(1e300), "1e300");
+        assert_eq!(auto.to_string(-2.5e21), "-2.5e21");
+        assert_eq!(auto.to_string(1e-11), "1e-11");
+        assert_eq!(auto.to_string(1e20), "100000000000000000000");
+        assert_eq!(auto.to_string(1e-7), "0.0000001");
+        assert_eq!(auto.to_string(0.1), "0.1");
+        assert_eq!(auto.to_string(-0.0), "0");
+        let fixed = NumberFormatter { notation: Notation::Fixed, ..auto };
+        assert_eq!(fixed.to_string(1e-11), "0.00000000001");
+        assert_eq!(fixed.to_string(1e22).len(), 23);
+    }
+
+    #[test]
+    fn test_display_prints_numbers_exactly() {
+        for value in [0.1 + 0.2, 1e-6, 123456789.125, 7.0, std::f64::consts::E] {
+            let node = Node::Number(value) + Node::Variable("x".into());
+            assert_eq!(parse(&node.to_string()), node, "{}", node);
+        }
+        assert_eq!(Node::Number(1e300).to_string(), "1e300");
+        assert_eq!(Node::Number(1e-11).to_string(), "1e-11");
+    }
+
+    #[test]
+    fn test_number_formatter_scientific_and_engineering() {
+        let sci = NumberFormatter {
+            significant_digits: 4,
+            notation: Notation::Scientific,
+            ..NumberFormatter::default()
+        };
+        assert_eq!(sci.to_string(12346.0), "1.235e4");
+        assert_eq!(sci.to_string(0.00025), "2.5e-4");
+        assert_eq!(sci.to_string(0.0), "0e0");
+        let eng = NumberFormatter { notation: Notation::Engineering, ..sci };
+        assert_eq!(eng.to_string(12346.0), "12.35e3");
+        assert_eq!(eng.to_string(0.00025), "250e-6");
+        assert_eq!(eng.to_string(-1500.0), "-1.5e3");
+    }
+
+    #[test]
+    fn test_number_formatter_in_formatters() {
+        let numbers = NumberFormatter { significant_digits: 3, ..NumberFormatter::default() };
+        let config = FormatterConfig { numbers: Some(numbers), ..FormatterConfig::default() };
+        let node = parse("3.14159*x");
+        assert_eq!(InfixFormatter { config: config.clone() }.format(&node), "3.14 * x");
+        assert_eq!(PostfixFormatter { config }.format(&node), "3.14 x *");
+        assert_eq!(node.to_string(), "3.14159 * x");
+    }
+
+    #[test]
+    fn test_let_formatting() {
+        let node = parse("2 * let x = 1+2 in x^2");
+        assert_eq!(node.to_string(), "2 * (let x = 1 + 2 in x ^ 2)");
+        assert_eq!(parse(&node.to_string()), node);
+        assert_eq!(PrefixFormatter::default().format(&node), "* 2 let x + 1 2 ^ x 2");
+        assert_eq!(PostfixFormatter::default().format(&node), "2 1 2 + x 2 ^ x let *");
+        let latex = LatexFormatter::default().format(&node);
+        assert_eq!(latex, "2 \\cdot \\left(\\mathrm{let}\\ x = 1 + 2\\ \\mathrm{in}\\ {x}^{2}\\right)");
+    }
+
+    #[test]
+    fn test_function_formatting() {
+        let node = parse("max(1, sin(x)) + sqrt(2)");
+        assert_eq!(node.to_string(), "max(1, sin(x)) + sqrt(2)");
+        assert_eq!(parse(&node.to_string()), node);
+        assert_eq!(PrefixFormatter::default().format(&node), "+ max 1 sin x sqrt 2");
+        assert_eq!(PostfixFormatter::default().format(&node), "1 x sin max 2 sqrt +");
+        let latex = LatexFormatter::default().format(&node);
+        assert_eq!(latex, "\\max\\left(1, \\sin\\left(x\\right)\\right) + \\sqrt{2}");
+        assert_eq!(LatexFormatter::default().format(&parse("f(1)")), "\\operatorname{f}\\left(1\\right)");
+    }
+
+    #[test]
+    fn test_latex_parentheses() {
+        let latex = LatexFormatter::default();
+        assert_eq!(latex.format(&parse("(2+3)*4")), "\\left(2 + 3\\right) \\cdot 4");
+        assert_eq!(latex.format(&parse("(x+1)^2")), "{\\left(x + 1\\right)}^{2}");
+        assert_eq!(latex.format(&parse("1-(2-3)")), "1 - \\left(2 - 3\\right)");
+    }
+
+    #[test]
+    fn test_constant_formatting() {
+        let node = parse("2 * 3.141592653589793 + 2.718281828459045").substitute_numbers_with_symbols(10);
+        assert_eq!(node.to_string(), "(2 * pi) + e");
+        assert_eq!(InfixFormatter::default().format(&node), "(2 * pi) + e");
+        assert_eq!(PrefixFormatter::default().format(&node), "+ * 2 pi e");
+        assert_eq!(PostfixFormatter::default().format(&node), "2 pi * e +");
+        assert_eq!(LatexFormatter::default().format(&node), "2 \\cdot \\pi + e");
+    }
+}
@@ -0,0 +1,533 @@
+/// This converts expression trees to and from a JSON encoding such as
+/// `{"op":"add","left":{"num":2},"right":{"num":3}}`. Function calls are written as
+/// `{"fn":"sin","args":[{"var":"x"}]}`, named constants as `{"const":"pi"}`, and n-ary sums
+/// and products as `{"op":"sum","args":[...]}` and `{"op":"product","args":[...]}`.
+// Standard lib
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Internal modules
+use super::ast::{MathConstant, Node};
+use super::parser::{ParseConfig, ParseError};
+
+// Minimal JSON value model, enough to describe an AST
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    // Parse a complete JSON document
+    pub fn parse(input: &str) -> Result<JsonValue, ParseError> {
+        let mut reader = JsonReader {
+            input: input.chars().peekable(),
+            depth: 0,
+            max_depth: ParseConfig::default().max_depth,
+        };
+        let value = reader.parse_value()?;
+        reader.skip_whitespace();
+        match reader.input.next() {
+            None => Ok(value),
+            Some(c) => Err(json_error(format!("trailing character '{}'", c))),
+        }
+    }
+
+    // Look up a key of an object, returns None for other values
+    #[must_use]
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            JsonValue::Number(n) if n.is_finite() => write!(f, "{}", n),
+            // JSON has no infinity or NaN, so they are written as the strings `inf`, `-inf`
+            // and `NaN`
+            JsonValue::Number(n) => write_json_string(f, &n.to_string()),
+            JsonValue::String(s) => write_json_string(f, s),
+            JsonValue::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            JsonValue::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// Write a string with JSON escaping
+fn write_json_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn json_error(msg: String) -> ParseError {
+    ParseError::UnableToParse(format!("JSON: {}", msg), None)
+}
+
+// Recursive descent reader for JSON text
+struct JsonReader<'a> {
+    input: Peekable<Chars<'a>>,
+    depth: usize,     // Arrays and objects open around the current position
+    max_depth: usize, // Deepest nesting accepted, so deep input cannot overflow the stack
+}
+
+impl<'a> JsonReader<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.input.peek() {
+            if c.is_whitespace() {
+                self.input.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        match self.input.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(json_error(format!("expected '{}', got '{}'", expected, c))),
+            None => Err(json_error(format!("expected '{}', got end of input", expected))),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, ParseError> {
+        self.skip_whitespace();
+        match self.input.peek() {
+            Some('{') => self.nested(Self::parse_object),
+            Some('[') => self.nested(Self::parse_array),
+            Some('"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some('-') | Some('0'..='9') => self.parse_number(),
+            Some('t') => self.parse_literal("true", JsonValue::Bool(true)),
+            Some('f') => self.parse_literal("false", JsonValue::Bool(false)),
+            Some('n') => self.parse_literal("null", JsonValue::Null),
+            Some(&c) => Err(json_error(format!("unexpected character '{}'", c))),
+            None => Err(json_error("unexpected end of input".into())),
+        }
+    }
+
+    fn parse_literal(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, ParseError> {
+        for expected in word.chars() {
+            if self.input.next() != Some(expected) {
+                return Err(json_error(format!("invalid literal, expected {}", word)));
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, ParseError> {
+        let mut num_str = String::new();
+        while let Some(&c) = self.input.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                num_str.push(c);
+                self.input.next();
+            } else {
+                break;
+            }
+        }
+        num_str
+            .parse::<f64>()
+            .map(JsonValue::Number)
+            .map_err(|_| json_error(format!("invalid number '{}'", num_str)))
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.input.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.input.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => s.push(self.parse_unicode_escape()?),
+                    _ => return Err(json_error("invalid escape sequence".into())),
+                },
+                Some(c) => s.push(c),
+                None => return Err(json_error("unterminated string".into())),
+            }
+        }
+    }
+
+    // The four hex digits of a `\u` escape, the `\u` already read
+    fn parse_hex_escape(&mut self) -> Result<u32, ParseError> {
+        let hex: String = (0..4).filter_map(|_| self.input.next()).collect();
+        match u32::from_str_radix(&hex, 16) {
+            Ok(code) if hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(code),
+            _ => Err(json_error(format!("invalid escape \\u{}", hex))),
+        }
+    }
+
+    // The character of a `\u` escape, combining a UTF-16 surrogate pair such as
+    // `\ud83d\ude00` into one character. A surrogate without its other half is an error.
+    fn parse_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.parse_hex_escape()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if self.input.next() != Some('\\') || self.input.next() != Some('u') {
+                    return Err(json_error(format!("unpaired surrogate \\u{:04x}", high)));
+                }
+                let low = self.parse_hex_escape()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(json_error(format!("unpaired surrogate \\u{:04x}", high)));
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(json_error(format!("unpaired surrogate \\u{:04x}", high))),
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| json_error(format!("invalid escape \\u{:04x}", code)))
+    }
+
+    // Parse an array or object one level deeper. Both share the one depth counter, so
+    // `[{"a":[` is three levels whichever brackets are used.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<JsonValue, ParseError>,
+    ) -> Result<JsonValue, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(json_error("nesting too deep".into()));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.input.peek() == Some(&']') {
+            self.input.next();
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.input.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(JsonValue::Array(items)),
+                _ => return Err(json_error("expected ',' or ']' in array".into())),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, ParseError> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.input.peek() == Some(&'}') {
+            self.input.next();
+            return Ok(JsonValue::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            let value = self.parse_value()?;
+            fields.push((key, value));
+            self.skip_whitespace();
+            match self.input.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(JsonValue::Object(fields)),
+                _ => return Err(json_error("expected ',' or '}' in object".into())),
+            }
+        }
+    }
+}
+
+// Take a JSON-encoded expression tree and return an AST
+pub fn parse_expr_from_json(json: &str) -> Result<Node, ParseError> {
+    let value = JsonValue::parse(json)?;
+    json_to_node(&value)
+}
+
+// Convert an AST into its JSON encoding
+#[must_use]
+pub fn node_to_json(node: &Node) -> String {
+    node_to_json_value(node).to_string()
+}
+
+fn json_to_node(value: &JsonValue) -> Result<Node, ParseError> {
+    if let Some(var) = value.get("var") {
+        return match var {
+            JsonValue::String(name) => Ok(Node::Variable(name.clone())),
+            _ => Err(json_error("\"var\" must be a string".into())),
+        };
+    }
+    if let Some(name) = value.get("const") {
+        return match name {
+            JsonValue::String(name) => MathConstant::ALL
+                .into_iter()
+                .find(|c| c.name() == name)
+                .map(Node::Constant)
+                .ok_or_else(|| json_error(format!("unknown constant \"{}\"", name))),
+            _ => Err(json_error("\"const\" must be a string".into())),
+        };
+    }
+    if let Some(num) = value.get("num") {
+        return match num {
+            JsonValue::Number(n) => Ok(Node::Number(*n)),
+            // Non-finite numbers, as written by Display
+            JsonValue::String(text) if matches!(text.as_str(), "inf" | "-inf" | "NaN") => {
+                Ok(Node::Number(text.parse().unwrap_or(f64::NAN)))
+            }
+            _ => Err(json_error("\"num\" must be a number".into())),
+        };
+    }
+    if let Some(name) = value.get("fn") {
+        return match (name, value.get("args")) {
+            (JsonValue::String(name), Some(JsonValue::Array(args))) => {
+                let args = args.iter().map(json_to_node).collect::<Result<Vec<Node>, ParseError>>()?;
+                Ok(Node::Function(name.clone(), args))
+            }
+            _ => Err(json_error("\"fn\" needs a string name and an \"args\" array".into())),
+        };
+    }
+    let op = match value.get("op") {
+        Some(JsonValue::String(op)) => op.as_str(),
+        Some(_) => return Err(json_error("\"op\" must be a string".into())),
+        None => return Err(json_error("expected an object with \"num\" or \"op\"".into())),
+    };
+    let child = |key: &str| -> Result<Box<Node>, ParseError> {
+        match value.get(key) {
+            Some(v) => Ok(Box::new(json_to_node(v)?)),
+            None => Err(json_error(format!("\"{}\" node is missing \"{}\"", op, key))),
+        }
+    };
+    match op {
+        "sum" | "product" => match value.get("args") {
+            Some(JsonValue::Array(items)) => {
+                let items = items.iter().map(json_to_node).collect::<Result<Vec<Node>, ParseError>>()?;
+                Ok(if op == "sum" { Node::FlatAdd(items) } else { Node::FlatMul(items) })
+            }
+            _ => Err(json_error(format!("\"{}\" node needs an \"args\" array", op))),
+        },
+        "neg" => Ok(Node::Negative(child("arg")?)),
+        "not" => Ok(Node::Not(child("arg")?)),
+        "let" => match value.get("name") {
+            Some(JsonValue::String(name)) => Ok(Node::Let(name.clone(), child("value")?, child("body")?)),
+            _ => Err(json_error("\"let\" node needs a string \"name\"".into())),
+        },
+        "add" => Ok(Node::Add(child("left")?, child("right")?)),
+        "sub" => Ok(Node::Subtract(child("left")?, child("right")?)),
+        "mul" => Ok(Node::Multiply(child("left")?, child("right")?)),
+        "div" => Ok(Node::Divide(child("left")?, child("right")?)),
+        "pow" => Ok(Node::Caret(child("left")?, child("right")?)),
+        "and" => Ok(Node::And(child("left")?, child("right")?)),
+        "or" => Ok(Node::Or(child("left")?, child("right")?)),
+        _ => Err(json_error(format!("unknown op \"{}\"", op))),
+    }
+}
+
+// The "op" name used for an n-ary sum or product
+fn flat_op(node: &Node) -> &'static str {
+    if matches!(node, Node::FlatAdd(_)) {
+        "sum"
+    } else {
+        "product"
+    }
+}
+
+fn node_to_json_value(node: &Node) -> JsonValue {
+    use self::Node::*;
+    let binary = |op: &str, left: &Node, right: &Node| {
+        JsonValue::Object(vec![
+            ("op".into(), JsonValue::String(op.into())),
+            ("left".into(), node_to_json_value(left)),
+            ("right".into(), node_to_json_value(right)),
+        ])
+    };
+    match node {
+        Number(n) => JsonValue::Object(vec![("num".into(), JsonValue::Number(*n))]),
+        Constant(c) => JsonValue::Object(vec![("const".into(), JsonValue::String(c.name().into()))]),
+        Variable(name) => JsonValue::Object(vec![("var".into(), JsonValue::String(name.clone()))]),
+        Negative(a) => JsonValue::Object(vec![
+            ("op".into(), JsonValue::String("neg".into())),
+            ("arg".into(), node_to_json_value(a)),
+        ]),
+        Not(a) => JsonValue::Object(vec![
+            ("op".into(), JsonValue::String("not".into())),
+            ("arg".into(), node_to_json_value(a)),
+        ]),
+        Let(name, value, body) => JsonValue::Object(vec![
+            ("op".into(), JsonValue::String("let".into())),
+            ("name".into(), JsonValue::String(name.clone())),
+            ("value".into(), node_to_json_value(value)),
+            ("body".into(), node_to_json_value(body)),
+        ]),
+        Function(name, args) => JsonValue::Object(vec![
+            ("fn".into(), JsonValue::String(name.clone())),
+            ("args".into(), JsonValue::Array(args.iter().map(node_to_json_value).collect())),
+        ]),
+        FlatAdd(items) | FlatMul(items) => JsonValue::Object(vec![
+            ("op".into(), JsonValue::String(flat_op(node).into())),
+            ("args".into(), JsonValue::Array(items.iter().map(node_to_json_value).collect())),
+        ]),
+        Add(a, b) => binary("add", a, b),
+        Subtract(a, b) => binary("sub", a, b),
+        Multiply(a, b) => binary("mul", a, b),
+        Divide(a, b) => binary("div", a, b),
+        Caret(a, b) => binary("pow", a, b),
+        And(a, b) => binary("and", a, b),
+        Or(a, b) => binary("or", a, b),
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+    use crate::parsemath::ast::eval;
+    use crate::parsemath::parser::Parser;
+
+    fn parse(expr: &str) -> Node {
+        Parser::new(expr).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn test_json_matches_infix_parse() {
+        let json = r#"{"op":"add","left":{"num":2},"right":{"op":"mul","left":{"num":3},"right":{"num":4}}}"#;
+        assert_eq!(parse_expr_from_json(json).unwrap(), parse("2+3*4"));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for expr in ["2+3*4", "-5", "(2+3)*4", "2^3/4-1", "6|2&3", "1.5*-2", "x*y-1", "let x = 2 in x*x", "max(1, x, f())", "!(a&!b)"] {
+            let node = parse(expr);
+            let decoded = parse_expr_from_json(&node_to_json(&node)).unwrap();
+            assert_eq!(decoded, node);
+            assert_eq!(eval(decoded), eval(parse(expr)));
+        }
+    }
+
+    #[test]
+    fn test_json_flat_nodes() {
+        use crate::parsemath::ast::flatten;
+        for expr in ["1+2+3+4", "2*x*y*3", "(1+2+3)*(4*5*6)"] {
+            let node = flatten(parse(expr));
+            assert_eq!(parse_expr_from_json(&node_to_json(&node)).unwrap(), node);
+        }
+        let json = r#"{"op":"product","args":[]}"#;
+        assert_eq!(parse_expr_from_json(json).unwrap(), Node::FlatMul(Vec::new()));
+        assert!(parse_expr_from_json(r#"{"op":"sum"}"#).is_err());
+    }
+
+    #[test]
+    fn test_json_with_whitespace() {
+        let json = r#" { "op" : "neg", "arg" : { "num" : 7.5 } } "#;
+        assert_eq!(parse_expr_from_json(json).unwrap(), Node::Negative(Box::new(Node::Number(7.5))));
+    }
+
+    #[test]
+    fn test_json_constants() {
+        let node = Node::Constant(MathConstant::Pi) * parse("r^2");
+        let json = node_to_json(&node);
+        assert!(json.contains(r#"{"const":"pi"}"#), "{}", json);
+        assert_eq!(parse_expr_from_json(&json).unwrap(), node);
+        assert!(parse_expr_from_json(r#"{"const":"phi"}"#).is_err());
+        assert!(parse_expr_from_json(r#"{"const":3}"#).is_err());
+    }
+
+    #[test]
+    fn test_json_invalid_input() {
+        assert!(parse_expr_from_json(r#"{"op":"add","left":{"num":2}}"#).is_err());
+        assert!(parse_expr_from_json(r#"{"op":"mod","left":{"num":2},"right":{"num":3}}"#).is_err());
+        assert!(parse_expr_from_json(r#"{"num":"2"}"#).is_err());
+        assert!(parse_expr_from_json(r#"{"num":2"#).is_err());
+        assert!(parse_expr_from_json("").is_err());
+    }
+
+    #[test]
+    fn test_json_nesting_too_deep() {
+        let error = JsonValue::parse(&"[".repeat(1_000_000)).unwrap_err();
+        assert!(error.to_string().contains("nesting too deep"));
+        assert!(JsonValue::parse(&"{\"a\":".repeat(100_000)).is_err());
+        let max_depth = ParseConfig::default().max_depth;
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(JsonValue::parse(&nested(max_depth)).is_ok());
+        assert!(JsonValue::parse(&nested(max_depth + 1)).is_err());
+        let objects = |depth: usize| format!("{}1{}", r#"{"a":"#.repeat(depth), "}".repeat(depth));
+        assert!(JsonValue::parse(&objects(max_depth)).is_ok());
+        assert!(JsonValue::parse(&objects(max_depth + 1)).unwrap_err().to_string().contains("too deep"));
+        let mixed = |depth: usize| format!("{}1{}", r#"[{"a":"#.repeat(depth / 2), "}]".repeat(depth / 2));
+        assert!(JsonValue::parse(&mixed(max_depth)).is_ok());
+        assert!(JsonValue::parse(&format!("[{}]", mixed(max_depth))).is_err());
+    }
+
+    #[test]
+    fn test_json_unicode_escapes() {
+        let string = |json: &str| match JsonValue::parse(json) {
+            Ok(JsonValue::String(s)) => Ok(s),
+            other => Err(format!("{:?}", other)),
+        };
+        assert_eq!(string(r#""\u00e9\u0041""#).unwrap(), "\u{e9}A");
+        assert_eq!(string(r#""\ud83d\ude00""#).unwrap(), "\u{1f600}");
+        assert_eq!(string(r#""x\ud834\udd1ey""#).unwrap(), "x\u{1d11e}y");
+        let invalid = [r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83dA""#, r#""\ude00""#];
+        for json in invalid.into_iter().chain([r#""\ud83dA""#, r#""\u+041""#, r#""\u12""#]) {
+            assert!(string(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_json_non_finite_round_trip() {
+        for value in [f64::INFINITY, f64::NEG_INFINITY] {
+            let json = node_to_json(&Node::Number(value));
+            assert!(JsonValue::parse(&json).is_ok(), "{}", json);
+            assert_eq!(parse_expr_from_json(&json).unwrap(), Node::Number(value));
+        }
+        assert_eq!(node_to_json(&Node::Number(f64::INFINITY)), r#"{"num":"inf"}"#);
+        let json = node_to_json(&Node::Number(f64::NAN));
+        assert!(matches!(parse_expr_from_json(&json).unwrap(), Node::Number(n) if n.is_nan()));
+        assert!(parse_expr_from_json(r#"{"num":"infinity"}"#).is_err());
+    }
+}
@@ -1,47 +1,755 @@
+#[cfg(not(feature = "clap"))]
 use std::env;
-use std::io;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 // Import parser and evaluator
 mod parsemath;
 use parsemath::ast;
-use parsemath::parser::{ParseError, Parser};
+use parsemath::context::EvalContext;
+use parsemath::formatter::NumberDisplay;
+use parsemath::json::JsonValue;
+use parsemath::parser::{ParseConfig, ParseError, Parser};
 
-// Function to evaluate an arithmetic expression
-fn evaluate(expr: &str) -> Result<f64, ParseError> {
-    let expr = expr.split_whitespace().collect::<String>(); // Remove whitespace
-    let mut math_parser = Parser::new(&expr)?;
+// Function to evaluate an arithmetic expression,
+// returns the value and the number of AST nodes visited to compute it
+#[must_use = "evaluation errors are only reported through the returned Result"]
+fn evaluate(expr: &str) -> Result<(f64, usize), ParseError> {
+    let attach = |e: ParseError| e.with_expression(expr); // Report errors against the input as given
+    let mut math_parser = Parser::new(expr).map_err(attach)?; // The tokenizer skips whitespace
+    let ast = math_parser.parse().map_err(attach)?;
+    let mut visited = 0;
+    let value = ast::eval_counted(ast, &mut visited).map_err(|e| attach(e.into()))?;
+    Ok((value, visited))
+}
+
+// The value of an expression in float, integer and exact rational form
+#[derive(Debug, Clone, PartialEq)]
+struct AllModeResults {
+    float: f64,
+    integer: Option<i64>,         // Some if the value is a whole number within i64
+    rational: Option<(i64, i64)>, // Some if every step of the evaluation stays a fraction
+}
+
+// Evaluate an expression in every numeric mode at once
+fn evaluate_all_modes(expr: &str) -> Result<AllModeResults, ParseError> {
+    let ast = Parser::new(expr)?.parse()?;
+    let rational = ast::eval_rational(&ast);
+    let float = ast::eval(ast).map_err(|e| ParseError::from(e).with_expression(expr))?;
+    // 2^63 is the first float past i64::MAX, so the upper bound is exclusive
+    let in_range = float >= i64::MIN as f64 && float < i64::MAX as f64;
+    let integer = if float.fract() == 0.0 && in_range { Some(float as i64) } else { None };
+    Ok(AllModeResults { float, integer, rational })
+}
+
+// Most variables generate_truth_table accepts, giving 2^20 rows
+const MAX_TRUTH_TABLE_VARS: usize = 20;
+
+// Evaluate a Boolean expression for every 0/1 assignment of `vars`, reading 0 as false and
+// anything else as true. Rows count up in binary with the first variable as the high bit.
+// A variable of the expression missing from `vars` is reported as an unknown variable.
+fn generate_truth_table(expr: &str, vars: &[&str]) -> Result<Vec<(Vec<bool>, bool)>, ParseError> {
+    if vars.is_empty() || vars.len() > MAX_TRUTH_TABLE_VARS {
+        let count = vars.len();
+        let message = format!("truth table needs between 1 and {} variables, got {}", MAX_TRUTH_TABLE_VARS, count);
+        return Err(ParseError::UnableToParse(message, Some(expr.to_string())));
+    }
+    let ast = Parser::new(expr)?.parse()?;
+    let mut ctx = EvalContext::new();
+    let rows = 1usize << vars.len();
+    let mut table = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let inputs: Vec<bool> = (0..vars.len()).map(|i| (row >> (vars.len() - 1 - i)) & 1 == 1).collect();
+        for (var, &input) in vars.iter().zip(&inputs) {
+            ctx.set_var(var, if input { 1.0 } else { 0.0 });
+        }
+        let value = ast::eval_with_context(&ast, &ctx);
+        table.push((inputs, value.map_err(|e| ParseError::from(e).with_expression(expr))? != 0.0));
+    }
+    Ok(table)
+}
+
+// Evaluate an arithmetic expression, returning only its value
+fn evaluate_simple(expr: &str) -> Result<f64, ParseError> {
+    evaluate(expr).map(|(value, _)| value)
+}
+
+// Evaluate an arithmetic expression in single precision with `ast::eval_f32`.
+// Every step rounds to f32, so the value can differ from `evaluate_simple`'s.
+fn evaluate_f32(expr: &str) -> Result<f32, ParseError> {
+    let ast = Parser::new(expr)?.parse()?;
+    ast::eval_f32(ast).map_err(|e| ParseError::from(e).with_expression(expr))
+}
+
+// Evaluate an arithmetic expression with trigonometric functions working in degrees,
+// see `EvalContext::with_degree_functions`
+fn evaluate_in_degrees(expr: &str) -> Result<f64, ParseError> {
+    let ast = Parser::new(expr)?.parse()?;
+    let ctx = EvalContext::with_degree_functions();
+    ast::eval_with_context(&ast, &ctx).map_err(|e| ParseError::from(e).with_expression(expr))
+}
+
+// Evaluate an expression, rejecting it if its AST has more than `limit` nodes.
+// The parser stops as soon as the limit is crossed, so oversized input is never fully built.
+fn evaluate_with_node_limit(expr: &str, limit: usize) -> Result<f64, ParseError> {
+    let config = ParseConfig {
+        max_nodes: limit,
+        ..ParseConfig::default()
+    };
+    let mut math_parser = Parser::new_with_config(expr, config)?;
     let ast = math_parser.parse()?;
-    Ok(ast::eval(ast)?)
+    ast::eval(ast).map_err(|e| ParseError::from(e).with_expression(expr))
 }
 
-// Main CLI function
-fn main() {
+// Evaluate an expression on a background thread, giving up after `duration`.
+// On timeout the thread is detached rather than joined, so it may keep running
+// (and outlive this call) until the evaluation finishes on its own.
+fn evaluate_with_timeout(expr: &str, duration: Duration) -> Result<f64, ParseError> {
+    let owned = expr.to_string();
+    run_with_timeout(move || evaluate_simple(&owned), duration).map_err(|e| e.with_expression(expr))
+}
+
+// Run `work` on a background thread, giving up after `duration` as `evaluate_with_timeout` does
+fn run_with_timeout(
+    work: impl FnOnce() -> Result<f64, ParseError> + Send + 'static,
+    duration: Duration,
+) -> Result<f64, ParseError> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver is gone if we already timed out, nothing to report then
+        let _ = sender.send(work());
+    });
+    match receiver.recv_timeout(duration) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(ParseError::Timeout { source: None }),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(ParseError::UnableToParse("Evaluation thread panicked".into(), None))
+        }
+    }
+}
+
+// How `eval` prints its result
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+enum OutputFormat {
+    Plain,  // The computed number is 14
+    Value,  // 14
+    Json,   // {"value":14}
+    Binary, // 0b1110, only for non-negative integer results of bitwise expressions
+}
+
+// Render an evaluation result. `precision` fixes the number of decimals and a `radix`
+// other than 10 prints the value as an integer in that base.
+fn format_value(value: f64, precision: Option<usize>, radix: u32, format: OutputFormat) -> Result<String, String> {
+    let text = if radix == 10 {
+        match precision {
+            Some(digits) => format!("{:.*}", digits, value),
+            None => value.to_string(),
+        }
+    } else if !(2..=36).contains(&radix) {
+        return Err(format!("radix must be between 2 and 36, got {}", radix));
+    } else if radix == 16 {
+        let mut text = String::from(if value < 0.0 { "-" } else { "" });
+        if write!(text, "{:x}", NumberDisplay(value.abs())).is_err() {
+            return Err(format!("{} is not an integer that can be printed in base {}", value, radix));
+        }
+        text
+    } else {
+        if value.fract() != 0.0 || value.abs() >= i64::MAX as f64 {
+            return Err(format!("{} is not an integer that can be printed in base {}", value, radix));
+        }
+        let mut n = (value as i64).unsigned_abs();
+        let mut digits = Vec::new();
+        loop {
+            digits.push(std::char::from_digit((n % radix as u64) as u32, radix).unwrap());
+            n /= radix as u64;
+            if n == 0 {
+                break;
+            }
+        }
+        if value < 0.0 {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    };
+    Ok(match format {
+        OutputFormat::Plain => format!("The computed number is {}\n", text),
+        // The `0b` text of bitwise expressions comes from `ast::eval_symbolic_bitwise`
+        OutputFormat::Value | OutputFormat::Binary => text,
+        OutputFormat::Json if radix == 10 && value.is_finite() => format!("{{\"value\":{}}}", text),
+        OutputFormat::Json => format!("{{\"value\":\"{}\"}}", text),
+    })
+}
+
+// How `batch` prints the result of each line
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+enum BatchFormat {
+    Plain,  // 2: 3*4 = 12
+    Ndjson, // {"line":2,"expr":"3*4","result":12}
+}
+
+// Evaluate every non-blank line of `reader`, writing one human-readable result line each.
+// Returns the number of lines that failed to evaluate.
+fn batch_plain(reader: impl BufRead, mut writer: impl Write) -> io::Result<usize> {
+    let mut failures = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let expr = line.trim();
+        if expr.is_empty() {
+            continue;
+        }
+        match evaluate_simple(expr) {
+            Ok(value) => writeln!(writer, "{}: {} = {}", index + 1, expr, value)?,
+            Err(error) => {
+                failures += 1;
+                writeln!(writer, "{}: {}: {}", index + 1, expr, error.without_expression())?
+            }
+        }
+    }
+    Ok(failures)
+}
+
+// Like `batch_plain`, but each result is a line of JSON (NDJSON), either
+// `{"line":N,"expr":"...","result":1.23}` or `{"line":N,"expr":"...","error":"..."}`.
+// Results that JSON numbers cannot hold, such as infinity, are written as strings.
+fn batch_ndjson(reader: impl BufRead, mut writer: impl Write) -> io::Result<usize> {
+    let mut failures = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let expr = line.trim();
+        if expr.is_empty() {
+            continue;
+        }
+        let outcome = match evaluate_simple(expr) {
+            Ok(value) => ("result", JsonValue::Number(value)),
+            Err(error) => {
+                failures += 1;
+                ("error", JsonValue::String(error.without_expression().to_string()))
+            }
+        };
+        let record = JsonValue::Object(vec![
+            ("line".into(), JsonValue::Number((index + 1) as f64)),
+            ("expr".into(), JsonValue::String(expr.into())),
+            (outcome.0.into(), outcome.1),
+        ]);
+        writeln!(writer, "{}", record)?;
+    }
+    Ok(failures)
+}
+
+fn print_banner() {
     println!("Hello! Welcome to Arithmetic expression evaluator.");
     println!("You can calculate value for expression such as 2*3+(4-5)+2^3/4.");
     println!("Allowed numbers: positive, negative and decimals.");
     println!("Supported operations: Add, Subtract, Multiply, Divide, PowerOf(^).");
     println!("Enter your arithmetic expression below:");
+}
 
-    // Check if an expression is passed as a command-line argument
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let expr = args[1..].join(" ");
-        match evaluate(&expr) {
-            Ok(val) => println!("The computed number is {}\n", val),
-            Err(_) => println!("Error in evaluating expression. Please enter valid expression\n"),
+// `format_value`, except that the Binary format prints bitwise expressions with
+// `ast::eval_symbolic_bitwise` and rejects every other expression
+fn bitwise_or_format_value(
+    expr: &str,
+    value: f64,
+    precision: Option<usize>,
+    radix: u32,
+    format: OutputFormat,
+) -> Result<String, String> {
+    if format != OutputFormat::Binary {
+        return format_value(value, precision, radix, format);
+    }
+    Parser::new(expr)
+        .and_then(|mut parser| parser.parse())
+        .ok()
+        .and_then(|ast| ast::eval_symbolic_bitwise(&ast))
+        .ok_or_else(|| format!("{} is not the non-negative integer result of a bitwise expression", value))
+}
+
+// Evaluate and print one expression. Ok(false) if the value could not be printed
+// in the requested format, the evaluation error if the expression failed.
+fn run_eval(
+    expr: &str,
+    precision: Option<usize>,
+    radix: u32,
+    format: OutputFormat,
+) -> Result<bool, ParseError> {
+    match evaluate(expr) {
+        Ok((val, _)) => match bitwise_or_format_value(expr, val, precision, radix, format) {
+            Ok(text) => {
+                println!("{}", text);
+                Ok(true)
+            }
+            Err(error) => {
+                println!("error: {}", error);
+                Ok(false)
+            }
+        },
+        Err(error) => {
+            println!("Error in evaluating expression. Please enter valid expression\n");
+            Err(error)
         }
-        return; // Exit after evaluation
     }
+}
 
-    // Otherwise, run interactive mode
+// Evaluate every line of `file`, writing the results to `writer` in `format`.
+// False if the file could not be read or any line failed.
+fn run_batch(file: &std::path::Path, format: BatchFormat, writer: impl Write) -> bool {
+    let reader = match std::fs::File::open(file) {
+        Ok(file) => io::BufReader::new(file),
+        Err(error) => {
+            eprintln!("error: cannot open {}: {}", file.display(), error);
+            return false;
+        }
+    };
+    let result = match format {
+        BatchFormat::Plain => batch_plain(reader, writer),
+        BatchFormat::Ndjson => batch_ndjson(reader, writer),
+    };
+    match result {
+        Ok(failed) => failed == 0,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            false
+        }
+    }
+}
+
+// Read `--file PATH [--format plain|ndjson]` from the arguments after the program name.
+// Ok(None) when they do not start with `--file`, so they are an expression instead.
+fn batch_args(args: &[String]) -> Result<Option<(&str, BatchFormat)>, String> {
+    match args {
+        [flag, ..] if flag != "--file" => Ok(None),
+        [] => Ok(None),
+        [_, file] => Ok(Some((file, BatchFormat::Plain))),
+        [_, file, option, format] if option == "--format" => match format.as_str() {
+            "plain" => Ok(Some((file, BatchFormat::Plain))),
+            "ndjson" => Ok(Some((file, BatchFormat::Ndjson))),
+            other => Err(format!("unknown format {}, expected plain or ndjson", other)),
+        },
+        _ => Err("usage: --file PATH [--format plain|ndjson]".to_string()),
+    }
+}
+
+// Read and evaluate expressions from stdin until it is closed
+fn run_repl() {
     loop {
         let mut input = String::new();
         match io::stdin().read_line(&mut input) {
-            Ok(_) => match evaluate(input.trim()) {
-                Ok(val) => println!("The computed number is {}\n", val),
-                Err(_) => println!("Error in evaluating expression. Please enter valid expression\n"),
-            },
+            Ok(0) => return,
+            Ok(_) => {
+                if let Err(error) = run_eval(input.trim(), None, 10, OutputFormat::Plain) {
+                    if !error.is_recoverable() {
+                        println!("Fatal error, exiting");
+                        return;
+                    }
+                    println!("Try again:");
+                }
+            }
             Err(error) => println!("error: {}", error),
         }
     }
 }
+
+// Command line interface, built with the `clap` feature
+#[cfg(feature = "clap")]
+#[derive(clap::Parser)]
+#[command(version, about = "Arithmetic expression evaluator")]
+struct Cli {
+    /// Do not print the welcome banner
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[cfg(feature = "clap")]
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Evaluate a single expression
+    Eval {
+        /// May start with `-`, as in `-2+3`
+        #[arg(allow_hyphen_values = true)]
+        expr: String,
+        /// Number of decimals to print
+        #[arg(long)]
+        precision: Option<usize>,
+        /// Print the (integer) result in this base
+        #[arg(long, default_value_t = 10)]
+        radix: u32,
+        /// How to print the result
+        #[arg(long, value_enum, default_value = "plain")]
+        output_format: OutputFormat,
+    },
+    /// Evaluate expressions read from stdin
+    Repl,
+    /// Check the syntax of an expression without evaluating it, exiting with 1 if it is invalid
+    Check {
+        #[arg(allow_hyphen_values = true)]
+        expr: String,
+    },
+    /// Evaluate every line of a file, exiting with 1 if any line fails
+    Batch {
+        /// File with one expression per line
+        #[arg(long)]
+        file: std::path::PathBuf,
+        /// How to print each result
+        #[arg(long, value_enum, default_value = "plain")]
+        format: BatchFormat,
+    },
+}
+
+// Main CLI function
+#[cfg(feature = "clap")]
+fn main() {
+    use clap::Parser as _;
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Repl);
+    // Machine-readable output goes to other programs, so it is printed without the banner
+    let machine_readable = match &command {
+        Command::Batch { .. } => true,
+        Command::Eval { output_format, .. } => *output_format != OutputFormat::Plain,
+        Command::Repl | Command::Check { .. } => false,
+    };
+    if !cli.quiet && !machine_readable {
+        print_banner();
+    }
+    match command {
+        Command::Eval { expr, precision, radix, output_format } => {
+            if !matches!(run_eval(&expr, precision, radix, output_format), Ok(true)) {
+                std::process::exit(1);
+            }
+        }
+        Command::Repl => run_repl(),
+        Command::Check { expr } => match Parser::new(&expr).and_then(|mut parser| parser.parse()) {
+            Ok(_) => println!("ok"),
+            Err(error) => {
+                println!("{}", error);
+                std::process::exit(1);
+            }
+        },
+        Command::Batch { file, format } => {
+            if !run_batch(&file, format, io::stdout().lock()) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Main CLI function
+#[cfg(not(feature = "clap"))]
+fn main() {
+    // Batch output goes to other programs, so it is printed without the banner
+    let args: Vec<String> = env::args().collect();
+    match batch_args(&args[1..]) {
+        Ok(Some((file, format))) => {
+            if !run_batch(std::path::Path::new(file), format, io::stdout().lock()) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(None) => {}
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    }
+    print_banner();
+
+    // Check if an expression is passed as a command-line argument
+    if args.len() > 1 {
+        let expr = args[1..].join(" ");
+        let _ = run_eval(&expr, None, 10, OutputFormat::Plain);
+        return; // Exit after evaluation
+    }
+
+    // Otherwise, run interactive mode
+    run_repl();
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    // Balanced sum of `terms` ones, which parses to 2 * terms - 1 nodes
+    fn sum_of_ones(terms: usize) -> String {
+        if terms == 1 {
+            return "1".to_string();
+        }
+        format!("({}+{})", sum_of_ones(terms / 2), sum_of_ones(terms - terms / 2))
+    }
+
+    #[test]
+    fn test_evaluate_reports_visited_nodes() {
+        let ast = Parser::new("2+3*4").unwrap().parse().unwrap();
+        assert_eq!(evaluate("2+3*4").unwrap(), (14.0, 5));
+        assert_eq!(ast::count_nodes(&ast), 5);
+        assert_eq!(evaluate_simple("2+3*4").unwrap(), 14.0);
+    }
+
+    #[test]
+    fn test_evaluate_all_modes() {
+        let third = evaluate_all_modes("1/3").unwrap();
+        assert_eq!(third, AllModeResults { float: 1.0 / 3.0, integer: None, rational: Some((1, 3)) });
+        let three = evaluate_all_modes("6/2").unwrap();
+        assert_eq!(three, AllModeResults { float: 3.0, integer: Some(3), rational: Some((3, 1)) });
+        let root = evaluate_all_modes("4^0.5").unwrap();
+        assert_eq!(root, AllModeResults { float: 2.0, integer: Some(2), rational: None });
+        assert_eq!(evaluate_all_modes("2^70").unwrap().integer, None);
+        assert!(evaluate_all_modes("1/0").is_err());
+    }
+
+    #[test]
+    fn test_truth_tables() {
+        let outputs = |expr: &str| -> Vec<bool> {
+            generate_truth_table(expr, &["a", "b"]).unwrap().into_iter().map(|(_, out)| out).collect()
+        };
+        assert_eq!(outputs("a & b"), [false, false, false, true]);
+        assert_eq!(outputs("a | b"), [false, true, true, true]);
+        assert_eq!(outputs("!(a & b)"), [true, true, true, false]);
+        let table = generate_truth_table("!a", &["a"]).unwrap();
+        assert_eq!(table, vec![(vec![false], true), (vec![true], false)]);
+        let table = generate_truth_table("a", &["a", "b"]).unwrap();
+        let inputs: Vec<Vec<bool>> = table.into_iter().map(|(inputs, _)| inputs).collect();
+        assert_eq!(inputs, [[false, false], [false, true], [true, false], [true, true]]);
+    }
+
+    #[test]
+    fn test_truth_table_errors() {
+        assert!(generate_truth_table("1", &[]).is_err());
+        let error = generate_truth_table("a & c", &["a", "b"]).unwrap_err();
+        let unknown = ast::EvalError::UnknownVariable("c".into());
+        assert!(matches!(&error, ParseError::EvalFailed(e, _) if *e == unknown));
+        assert!(generate_truth_table("a", &["a"; 21]).is_err());
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(14.0, None, 10, OutputFormat::Plain).unwrap(), "The computed number is 14\n");
+        assert_eq!(format_value(2.0 / 3.0, Some(3), 10, OutputFormat::Value).unwrap(), "0.667");
+        assert_eq!(format_value(255.0, None, 16, OutputFormat::Value).unwrap(), "ff");
+        assert_eq!(format_value(-255.0, None, 16, OutputFormat::Value).unwrap(), "-ff");
+        assert_eq!(format_value(2f64.powi(63), None, 16, OutputFormat::Value).unwrap(), "8000000000000000");
+        assert_eq!(format_value(-5.0, None, 2, OutputFormat::Value).unwrap(), "-101");
+        assert_eq!(format_value(1.5, None, 10, OutputFormat::Json).unwrap(), "{\"value\":1.5}");
+        assert_eq!(format_value(10.0, None, 2, OutputFormat::Json).unwrap(), "{\"value\":\"1010\"}");
+    }
+
+    #[test]
+    fn test_format_value_errors() {
+        assert!(format_value(1.5, None, 2, OutputFormat::Value).is_err());
+        assert!(format_value(1.5, None, 16, OutputFormat::Value).is_err());
+        assert!(format_value(3.0, None, 1, OutputFormat::Value).is_err());
+        assert!(format_value(3.0, None, 37, OutputFormat::Value).is_err());
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_cli_arguments() {
+        use clap::Parser as _;
+        let cli = Cli::try_parse_from(["pass", "-q", "eval", "2+3", "--radix", "16"]).unwrap();
+        assert!(cli.quiet);
+        assert!(matches!(cli.command, Some(Command::Eval { radix: 16, precision: None, .. })));
+        let cli = Cli::try_parse_from(["pass", "check", "1+"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Check { expr }) if expr == "1+"));
+        assert!(Cli::try_parse_from(["pass", "eval"]).is_err());
+        assert!(Cli::try_parse_from(["pass", "eval", "1", "--output-format", "xml"]).is_err());
+    }
+
+    #[cfg(feature = "clap")]
+    #[test]
+    fn test_cli_negative_leading_operand() {
+        use clap::Parser as _;
+        let cli = Cli::try_parse_from(["pass", "eval", "-2+3"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Eval { expr, .. }) if expr == "-2+3"));
+        let cli = Cli::try_parse_from(["pass", "eval", "-x", "--radix", "2"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Eval { expr, radix: 2, .. }) if expr == "-x"));
+        let cli = Cli::try_parse_from(["pass", "check", "-(1+2)"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Check { expr }) if expr == "-(1+2)"));
+    }
+
+    #[test]
+    fn test_binary_output_format() {
+        let binary = |expr: &str| {
+            bitwise_or_format_value(expr, evaluate_simple(expr).unwrap(), None, 10, OutputFormat::Binary)
+        };
+        assert_eq!(binary("0xFF & 0x0F").unwrap(), "0b1111");
+        assert!(binary("2+3").is_err());
+        assert!(binary("0xFF & (-1)").is_err());
+        assert_eq!(bitwise_or_format_value("2+3", 5.0, None, 10, OutputFormat::Value).unwrap(), "5");
+    }
+
+    #[test]
+    fn test_evaluate_in_degrees() {
+        assert_eq!(evaluate_in_degrees("sin(90)").unwrap(), 1.0);
+        assert_eq!(evaluate_in_degrees("cos(180)").unwrap(), -1.0);
+        assert_eq!(evaluate_in_degrees("asin(1)").unwrap(), 90.0);
+        assert!((evaluate_in_degrees("tan(45) + acos(0.5)").unwrap() - 61.0).abs() < 1e-12);
+        assert_eq!(evaluate_in_degrees("sqrt(4)").unwrap(), 2.0);
+        assert_eq!(evaluate_simple("sin(0) + cos(0)").unwrap(), 1.0);
+        assert!(matches!(evaluate_in_degrees("sin(x)"), Err(ParseError::EvalFailed(..))));
+    }
+
+    #[test]
+    fn test_evaluate_f32() {
+        let single = evaluate_f32("1.0/3.0").unwrap();
+        let double = evaluate_simple("1.0/3.0").unwrap();
+        assert_ne!(single as f64, double);
+        assert!((single as f64 - double).abs() < 1e-7);
+        assert_eq!(single, 1.0f32 / 3.0);
+        assert_eq!(evaluate_f32("let x = 2 in x^10 + max(1, 3)").unwrap(), 1027.0);
+        assert!(matches!(evaluate_f32("1/0"), Err(ParseError::EvalFailed(..))));
+    }
+
+    #[test]
+    fn test_evaluate_with_node_limit() {
+        assert_eq!(evaluate_with_node_limit(&sum_of_ones(5000), 10_000).unwrap(), 5000.0);
+        assert!(matches!(
+            evaluate_with_node_limit(&sum_of_ones(5001), 10_000),
+            Err(ParseError::ExpressionTooLarge { node_count: 10_001, .. })
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_let() {
+        assert_eq!(evaluate_simple("let x = 3 in x^2").unwrap(), 9.0);
+        assert_eq!(evaluate_simple("let x = 2 in let y = x+1 in x*y").unwrap(), 6.0);
+        assert_eq!(evaluate_simple("let x = 2 in let x = x*10 in x+1").unwrap(), 21.0);
+        assert_eq!(evaluate_simple(" 2 *\t3 ").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn test_evaluate_let_does_not_leak() {
+        assert_eq!(evaluate_simple("let x = 5 in x").unwrap(), 5.0);
+        for expr in ["x", "(let x = 5 in x) + x"] {
+            let error = evaluate(expr).unwrap_err();
+            let unknown = ast::EvalError::UnknownVariable("x".into());
+            assert!(matches!(&error, ParseError::EvalFailed(e, _) if *e == unknown));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_empty_expression() {
+        assert!(matches!(evaluate(""), Err(ParseError::EmptyExpression { .. })));
+        assert!(matches!(evaluate("  \t\n"), Err(ParseError::EmptyExpression { .. })));
+    }
+
+    #[test]
+    fn test_evaluate_error_source_expression() {
+        assert_eq!(evaluate("2*/3").unwrap_err().source_expression(), Some("2*/3"));
+        assert_eq!(evaluate(" 2 * / 3").unwrap_err().source_expression(), Some(" 2 * / 3"));
+        let error = evaluate("1/0").unwrap_err();
+        assert_eq!(error.source_expression(), Some("1/0"));
+        assert!(matches!(error, ParseError::EvalFailed(ast::EvalError::DivisionByZero, _)));
+    }
+
+    #[test]
+    fn test_entry_points_attach_source_expression() {
+        for expr in ["", "2*/3", "1/0"] {
+            let source = |error: ParseError| error.source_expression().map(str::to_string);
+            let expected = Some(expr.to_string());
+            assert_eq!(evaluate(expr).map_err(source).unwrap_err(), expected);
+            assert_eq!(evaluate_f32(expr).map_err(source).unwrap_err(), expected);
+            assert_eq!(evaluate_in_degrees(expr).map_err(source).unwrap_err(), expected);
+            assert_eq!(evaluate_all_modes(expr).map_err(source).unwrap_err(), expected);
+            assert_eq!(evaluate_with_node_limit(expr, 100).map_err(source).unwrap_err(), expected);
+            assert_eq!(generate_truth_table(expr, &["a"]).map_err(source).unwrap_err(), expected);
+        }
+        let error = evaluate_with_timeout("1/0", Duration::from_secs(5)).unwrap_err();
+        assert_eq!(error.source_expression(), Some("1/0"));
+    }
+
+    #[test]
+    fn test_evaluate_with_timeout_completes() {
+        assert_eq!(evaluate_with_timeout("2*3+(4-5)", Duration::from_secs(5)).unwrap(), 5.0);
+        assert!(evaluate_with_timeout("2*", Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_with_timeout_fires() {
+        // Work that only finishes once the test lets it, which it never does
+        let (release, blocked) = mpsc::channel::<()>();
+        let work = move || blocked.recv().map(|_| 0.0).map_err(|_| ParseError::Timeout { source: None });
+        let result = run_with_timeout(work, Duration::ZERO);
+        assert!(matches!(result, Err(ParseError::Timeout { .. })));
+        drop(release);
+
+        // Skipping 50 MB of whitespace takes far longer than a millisecond
+        let expr = format!("{}1", " ".repeat(50_000_000));
+        let result = evaluate_with_timeout(&expr, Duration::from_millis(1));
+        assert!(matches!(result, Err(ParseError::Timeout { .. })));
+    }
+
+    #[test]
+    fn test_batch_ndjson() {
+        let path = std::env::temp_dir().join(format!("pass_batch_{}.txt", std::process::id()));
+        std::fs::write(&path, "2+3*4\n\n1/0\n  x \\ \n2^1024\n").unwrap();
+        let reader = io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let mut output = Vec::new();
+        let failures = batch_ndjson(reader, &mut output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(failures, 2);
+
+        let records: Vec<JsonValue> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| JsonValue::parse(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 4);
+        let field = |i: usize, key: &str| records[i].get(key).cloned();
+        assert_eq!(field(0, "line"), Some(JsonValue::Number(1.0)));
+        assert_eq!(field(0, "expr"), Some(JsonValue::String("2+3*4".into())));
+        assert_eq!(field(0, "result"), Some(JsonValue::Number(14.0)));
+        assert_eq!(field(1, "line"), Some(JsonValue::Number(3.0)));
+        assert_eq!(field(1, "result"), None);
+        assert!(matches!(field(1, "error"), Some(JsonValue::String(e)) if e.contains("Division by zero")));
+        assert_eq!(field(2, "expr"), Some(JsonValue::String("x \\".into())));
+        assert!(field(2, "error").is_some());
+        assert_eq!(field(3, "result"), Some(JsonValue::String("inf".into())));
+    }
+
+    #[test]
+    fn test_batch_args() {
+        let args = |line: &str| line.split_whitespace().map(String::from).collect::<Vec<_>>();
+        assert_eq!(batch_args(&args("2 + 3")), Ok(None));
+        assert_eq!(batch_args(&[]), Ok(None));
+        assert_eq!(batch_args(&args("--file a.txt")), Ok(Some(("a.txt", BatchFormat::Plain))));
+        let ndjson = args("--file a.txt --format ndjson");
+        assert_eq!(batch_args(&ndjson), Ok(Some(("a.txt", BatchFormat::Ndjson))));
+        for invalid in ["--file", "--file a.txt --format xml", "--file a.txt ndjson", "--file a b c d"] {
+            assert!(batch_args(&args(invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let path = std::env::temp_dir().join(format!("pass_run_batch_{}.txt", std::process::id()));
+        let mut output = Vec::new();
+        std::fs::write(&path, "1+1\n2*3\n").unwrap();
+        let succeeded = run_batch(&path, BatchFormat::Ndjson, &mut output);
+        std::fs::write(&path, "1+1\n2*\n").unwrap();
+        let failed = run_batch(&path, BatchFormat::Plain, &mut output);
+        std::fs::remove_file(&path).unwrap();
+        assert!(succeeded && !failed);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], r#"{"line":2,"expr":"2*3","result":6}"#);
+        assert_eq!(lines[2], "1: 1+1 = 2");
+        assert!(!run_batch(&path, BatchFormat::Plain, io::sink()));
+    }
+
+    #[test]
+    fn test_batch_plain() {
+        let mut output = Vec::new();
+        let failures = batch_plain("1+1\n2*\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(failures, 1);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "1: 1+1 = 2");
+        assert!(lines[1].starts_with("2: 2*: "));
+    }
+}
@@ -1,5 +1,27 @@
 /// Module Parsemath
+///
+/// The expression grammar and operator associativity are described in `parser`.
+///
+/// Optional features:
+/// - `tracing`: emit `tracing` spans from `Parser` and `ast::eval` for profiling
+/// - `rayon`: `ast::eval_parallel` evaluates independent subtrees concurrently
+/// - `clap`: the `eval`, `repl` and `check` subcommands in the command line interface
+/// - `serde`: `bank::ExpressionBank` saves and loads named expressions as JSON files
+/// - `num`: `symbolic::evaluate_symbolic` gives exact rational and square root results
 pub mod ast;
+#[cfg(feature = "serde")]
+pub mod bank;
+pub mod binary;
+pub mod context;
+pub mod diff;
+pub mod expression;
+pub mod formatter;
+pub mod json;
+pub mod numeric;
 pub mod parser;
+pub mod pool;
+#[cfg(feature = "num")]
+pub mod symbolic;
 pub mod token;
 pub mod tokenizer;
+pub mod trace;
@@ -0,0 +1,178 @@
+/// This contains numerical methods that work on expressions of a single variable,
+/// such as finding a root with Newton's method or bisection, or integrating with Simpson's rule.
+// Internal modules
+use super::ast::{self, EvalError, Node};
+use super::context::EvalContext;
+use super::parser::Parser;
+
+// Newton's method gives up after this many steps
+pub const MAX_NEWTON_ITERATIONS: usize = 100;
+
+// A root is accepted once |f(x)| drops below this
+pub const ROOT_TOLERANCE: f64 = 1e-10;
+
+// Bisection stops halving after this many steps, even if the interval is still wider than asked
+pub const MAX_BISECTION_ITERATIONS: usize = 1000;
+
+// Parse `expr`, reporting parse failures as an evaluation error
+fn parse(expr: &str) -> Result<Node, EvalError> {
+    Parser::new(expr)
+        .and_then(|mut parser| parser.parse())
+        .map_err(|e| EvalError::InvalidExpression(e.to_string()))
+}
+
+// Evaluate `node` with `var` bound to `x`
+fn eval_at(node: &Node, ctx: &mut EvalContext, var: &str, x: f64) -> Result<f64, EvalError> {
+    ctx.set_var(var, x);
+    ast::eval_with_context(node, ctx)
+}
+
+// Find x where `expr` is zero using Newton's method, starting from `initial`.
+// The derivative is taken symbolically with `ast::diff`.
+pub fn find_root(expr: &str, var: &str, initial: f64) -> Result<f64, EvalError> {
+    let function = parse(expr)?;
+    let derivative = ast::diff(&function, var)?;
+    let mut ctx = EvalContext::new();
+    let mut x = initial;
+    for _ in 0..MAX_NEWTON_ITERATIONS {
+        let fx = eval_at(&function, &mut ctx, var, x)?;
+        if fx.abs() < ROOT_TOLERANCE {
+            return Ok(x);
+        }
+        let slope = eval_at(&derivative, &mut ctx, var, x)?;
+        if slope == 0.0 {
+            return Err(EvalError::ZeroDerivative);
+        }
+        x -= fx / slope;
+        if !x.is_finite() {
+            break;
+        }
+    }
+    Err(EvalError::ConvergenceFailed)
+}
+
+// Find x in [lo, hi] where `expr` is zero by repeatedly halving the interval, until it is
+// narrower than `tol`. Unlike `find_root` this needs no derivative, only a sign change:
+// `expr` must be negative at one end and positive at the other.
+pub fn binary_search_root(expr: &str, var: &str, lo: f64, hi: f64, tol: f64) -> Result<f64, EvalError> {
+    let function = parse(expr)?;
+    let mut ctx = EvalContext::new();
+    let (mut lo, mut hi) = (lo, hi);
+    let f_lo = eval_at(&function, &mut ctx, var, lo)?;
+    let f_hi = eval_at(&function, &mut ctx, var, hi)?;
+    if f_lo == 0.0 {
+        return Ok(lo);
+    }
+    if f_hi == 0.0 {
+        return Ok(hi);
+    }
+    if f_lo.signum() == f_hi.signum() || f_lo.is_nan() || f_hi.is_nan() {
+        return Err(EvalError::NoSignChange);
+    }
+    let lo_negative = f_lo < 0.0;
+    for _ in 0..MAX_BISECTION_ITERATIONS {
+        if (hi - lo).abs() < tol {
+            break;
+        }
+        let mid = lo + (hi - lo) / 2.0;
+        let f_mid = eval_at(&function, &mut ctx, var, mid)?;
+        if f_mid == 0.0 {
+            return Ok(mid);
+        }
+        if (f_mid < 0.0) == lo_negative {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(lo + (hi - lo) / 2.0)
+}
+
+// Integrate `expr` over `var` from `a` to `b` with Simpson's rule on `n` intervals,
+// sampling the expression at n + 1 evenly spaced points. `n` must be even and non-zero.
+pub fn integrate(expr: &str, var: &str, a: f64, b: f64, n: usize) -> Result<f64, EvalError> {
+    if n == 0 || n % 2 == 1 {
+        return Err(EvalError::InvalidArgument(format!(
+            "Simpson's rule needs a positive even number of intervals, got {}",
+            n
+        )));
+    }
+    let function = parse(expr)?;
+    let mut ctx = EvalContext::new();
+    let step = (b - a) / n as f64;
+    let mut sum = 0.0;
+    for i in 0..=n {
+        let weight = if i == 0 || i == n {
+            1.0
+        } else if i % 2 == 1 {
+            4.0
+        } else {
+            2.0
+        };
+        sum += weight * eval_at(&function, &mut ctx, var, a + step * i as f64)?;
+    }
+    Ok(sum * step / 3.0)
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_root_sqrt_two() {
+        let root = find_root("x^2 - 2", "x", 1.0).unwrap();
+        assert!((root - 2f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_find_root_sin() {
+        let root = find_root("sin(x)", "x", 3.0).unwrap();
+        assert!((root - std::f64::consts::PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_find_root_failures() {
+        assert_eq!(find_root("x^2 + 1", "x", 0.5), Err(EvalError::ConvergenceFailed));
+        assert_eq!(find_root("x^2 + 1", "x", 0.0), Err(EvalError::ZeroDerivative));
+        assert!(matches!(find_root("x^", "x", 1.0), Err(EvalError::InvalidExpression(_))));
+        assert_eq!(find_root("x + y", "x", 1.0), Err(EvalError::UnknownVariable("y".into())));
+    }
+
+    #[test]
+    fn test_binary_search_root() {
+        // The real root of x^3 - x - 2
+        let expected = 1.521_379_706_804_567_6;
+        let root = binary_search_root("x^3 - x - 2", "x", 1.0, 2.0, 1e-10).unwrap();
+        assert!((root - expected).abs() < 1e-10);
+        // Reversed bounds and a function that decreases through its root
+        let root = binary_search_root("2 - x^2", "x", 2.0, 0.0, 1e-6).unwrap();
+        assert!((root - 2f64.sqrt()).abs() < 1e-6);
+        assert_eq!(binary_search_root("x - 1", "x", 1.0, 5.0, 1e-9), Ok(1.0));
+        let root = binary_search_root("abs(x - 0.3) - 0.1", "x", 0.0, 0.3, 1e-12).unwrap();
+        assert!((root - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_binary_search_root_no_sign_change() {
+        assert_eq!(binary_search_root("x^2 + 1", "x", 0.0, 1.0, 1e-10), Err(EvalError::NoSignChange));
+        assert_eq!(binary_search_root("x^2 - 4", "x", -3.0, 3.0, 1e-10), Err(EvalError::NoSignChange));
+        assert_eq!(binary_search_root("1/x", "x", 0.0, 1.0, 1e-10), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_integrate() {
+        assert!((integrate("x^2", "x", 0.0, 1.0, 100).unwrap() - 1.0 / 3.0).abs() < 1e-8);
+        let pi = std::f64::consts::PI;
+        assert!((integrate("sin(x)", "x", 0.0, pi, 1000).unwrap() - 2.0).abs() < 1e-6);
+        assert!((integrate("2*t + 1", "t", 1.0, 0.0, 2).unwrap() + 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_integrate_invalid_intervals() {
+        for n in [0, 3] {
+            assert!(matches!(integrate("x", "x", 0.0, 1.0, n), Err(EvalError::InvalidArgument(_))));
+        }
+        assert_eq!(integrate("1/x", "x", 0.0, 1.0, 2), Err(EvalError::DivisionByZero));
+    }
+}
@@ -1,208 +1,1903 @@
 /// This program reads tokens returned by Tokenizer and converts them into AST.
+///
+/// Grammar (EBNF), operators listed from loosest to tightest binding:
+///
+/// ```text
+/// expr    = sum { ( "&" | "|" ) sum } ;            (* left-associative *)
+/// sum     = product { ( "+" | "-" ) product } ;    (* left-associative *)
+/// product = power { [ mulop ] power } ;            (* left-associative, see below *)
+/// mulop   = "*" | "/" | "%" | "mod" | "rem" ;
+/// power   = unary [ "^" power ] ;                  (* right-associative *)
+/// unary   = ( "-" | "!" ) unary | postfix ;      (* prefix "!" is logical not *)
+/// postfix = primary { "!" } ;                      (* postfix "!" is factorial *)
+/// primary = number | ident | call | "(" expr ")" | let ;
+/// call    = ident "(" [ expr { "," expr } ] ")" ;
+/// let     = "let" ident "=" expr "in" expr ;       (* the body extends as far as possible *)
+/// number  = digit { digit | "." } | "0" ( "x" | "X" ) hexdigit { hexdigit } ;
+/// ident   = ( letter | "_" ) { letter | digit | "_" } ;
+/// ```
+///
+/// Postfix operators bind tighter than prefix ones, so `-3!` is `-(3!)` and `2^3!` is
+/// `2^(3!)`. `n!` is parsed as a call of the built-in function `factorial(n)`.
+///
+/// A `*` may be left out before a parenthesised operand, or between `)` and a number written
+/// right after it: `2(3+4)` is `2*(3+4)` and `(1+2)(3+4)2` is `(1+2)*(3+4)*2`. Two bare
+/// numbers such as `2 3` are still an error, as is `(2) 3`, and `f(x)` is a function call
+/// rather than a product.
+///
+/// `a % b` and `a mod b` are the Euclidean remainder, which is never negative, while `a rem b`
+/// truncates the quotient so the result takes the sign of `a`: `(-7) mod 3` is 2 but
+/// `(-7) rem 3` is -1, while for positive operands both agree. They are parsed as calls of the
+/// built-in functions `mod(a, b)` and `rem(a, b)`, which can also be called directly. The words
+/// `mod` and `rem` are operators only where an operator is expected, elsewhere they are names.
+///
+/// Spaces, tabs and newlines between tokens are ignored. The associativity of each
+/// operator can be changed through `ParseConfig::operator_associativity`.
 // Standard lib
+use std::collections::HashSet;
+use std::error;
 use std::fmt;
+use std::time::{Duration, Instant};
 
 // Internal modules
-use super::ast::Node;
-use super::token::{OperPrec, Token};
+use super::ast::{free_variables, EvalError, Node, SpannedNode};
+use super::token::{OperPrec, Span, Token};
 use super::tokenizer::Tokenizer;
 
-// Parser struct
+// Associativity of a binary operator
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Associativity {
+    Left,  // 1-2-3 is (1-2)-3
+    Right, // 2^3^2 is 2^(3^2)
+}
+
+// How the Parser handles binary operators. Both build the same AST for every input.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ParseAlgorithm {
+    // `generate_ast`: each operand loops while the next operator binds tighter than its level
+    #[default]
+    Pratt,
+    // `parse_precedence_climbing`: the classic loop over operators of at least a minimum level
+    PrecedenceClimbing,
+}
+
+// Options controlling how the Parser builds the AST.
+// `ParseConfig::default()` gives the standard behaviour, so callers can
+// override only what they need: `ParseConfig { max_depth: 64, ..ParseConfig::default() }`
+#[derive(Debug, Clone)]
+pub struct ParseConfig {
+    // Deepest nesting of sub-expressions before giving up (default 512)
+    pub max_depth: usize,
+    // Largest number of AST nodes an expression may produce (default 10_000)
+    pub max_nodes: usize,
+    // Treat a NaN result as an error instead of returning it (default false)
+    pub strict_nan: bool,
+    // Reject integer literals beyond 2^53, where f64 loses precision (default false)
+    pub strict_integer_precision: bool,
+    // Associativity per binary operator; operators not listed are left-associative.
+    // Default: `^` is right-associative, everything else left-associative.
+    pub operator_associativity: Vec<(Token, Associativity)>,
+    // Algorithm used for binary operators (default ParseAlgorithm::Pratt)
+    pub algorithm: ParseAlgorithm,
+    // Decimal point of number literals (default `.`). With `,` function arguments are
+    // separated by `;` instead, so `max(1,5; 2)` is 2 as European locales write it. Operators,
+    // parentheses, digits, letters, `_` and whitespace are rejected, see `check`.
+    pub decimal_separator: char,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        use self::Associativity::*;
+        ParseConfig {
+            max_depth: 512,
+            max_nodes: 10_000,
+            strict_nan: false,
+            strict_integer_precision: false,
+            operator_associativity: vec![
+                (Token::And, Left),
+                (Token::Or, Left),
+                (Token::Add, Left),
+                (Token::Subtract, Left),
+                (Token::Multiply, Left),
+                (Token::Divide, Left),
+                (Token::Modulo, Left),
+                (Token::Remainder, Left),
+                (Token::Caret, Right),
+            ],
+            algorithm: ParseAlgorithm::Pratt,
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl ParseConfig {
+    // Reject options the tokenizer could not tell apart from the expression: a decimal
+    // separator that already means something, such as `+`, would make `1+5` ambiguous
+    pub fn check(&self) -> Result<(), ParseError> {
+        let separator = self.decimal_separator;
+        let taken = separator != ',' && Token::from_char(separator).is_some();
+        if taken || separator.is_alphanumeric() || separator == '_' || separator.is_whitespace() {
+            let message = format!("{:?} cannot be the decimal separator", separator);
+            return Err(ParseError::UnableToParse(message, None));
+        }
+        Ok(())
+    }
+
+    // Look up the associativity of a binary operator token
+    #[must_use]
+    #[inline]
+    pub fn associativity(&self, token: &Token) -> Associativity {
+        self.operator_associativity
+            .iter()
+            .find(|(t, _)| t == token)
+            .map(|(_, assoc)| *assoc)
+            .unwrap_or(Associativity::Left)
+    }
+}
+
+// Where a Parser reads its tokens from
+enum TokenSource<'a> {
+    Text(Tokenizer<'a>),
+    // Tokens produced earlier, with the index of the next one and of the last one returned.
+    // Positions count tokens rather than bytes.
+    Tokens(&'a [Token], usize, usize),
+}
+
+impl<'a> TokenSource<'a> {
+    fn next(&mut self) -> Option<Token> {
+        match self {
+            TokenSource::Text(tokenizer) => tokenizer.next(),
+            TokenSource::Tokens(tokens, next, last) => {
+                // Whitespace has no meaning to the parser, e.g. from a WhitespaceTokenizer
+                while let Some(Token::Whitespace(_)) = tokens.get(*next) {
+                    *next += 1;
+                }
+                *last = *next;
+                // Running off the end reads as EOF, so the slice need not end with one
+                let token = tokens.get(*next).cloned().unwrap_or(Token::EOF);
+                *next = (*next + 1).min(tokens.len());
+                Some(token)
+            }
+        }
+    }
+
+    // Position just past the last token returned
+    fn offset(&self) -> usize {
+        match self {
+            TokenSource::Text(tokenizer) => tokenizer.offset(),
+            TokenSource::Tokens(_, next, _) => *next,
+        }
+    }
+
+    // Position where the last token returned begins
+    fn token_start(&self) -> usize {
+        match self {
+            TokenSource::Text(tokenizer) => tokenizer.token_start(),
+            TokenSource::Tokens(_, _, last) => *last,
+        }
+    }
+
+    // The source text, empty for pre-tokenized input
+    fn source(&self) -> &'a str {
+        match self {
+            TokenSource::Text(tokenizer) => tokenizer.source(),
+            TokenSource::Tokens(..) => "",
+        }
+    }
+
+    // Whether the token ending at position `end` is a `)` written right before it, with no space
+    fn closes_paren_at(&self, end: usize) -> bool {
+        match self {
+            TokenSource::Text(tokenizer) => end > 0 && tokenizer.source().as_bytes()[end - 1] == b')',
+            TokenSource::Tokens(tokens, ..) => end > 0 && tokens[end - 1] == Token::RightParen,
+        }
+    }
+
+    // Attach the source text to `error`, when there is one
+    fn locate(&self, error: ParseError) -> ParseError {
+        match self {
+            TokenSource::Text(tokenizer) => error.with_expression(tokenizer.source()),
+            TokenSource::Tokens(..) => error,
+        }
+    }
+}
+
+// Parser struct. It holds only owned data and the borrowed expression, so like Tokenizer it
+// is Send and Sync, but `'a` keeps it from outliving the text; OwnedParser owns its text.
 pub struct Parser<'a> {
-    tokenizer: Tokenizer<'a>,
+    tokenizer: TokenSource<'a>,
     current_token: Token,
+    current_start: usize, // Byte offset where current_token begins
+    current_end: usize,   // Byte offset just past current_token
+    next_start: usize,    // Byte offset where next_token begins
+    prev_end: usize,      // Byte offset just past the last consumed token
+    next_token: Option<Token>, // One token of lookahead, None after an invalid character
+    config: ParseConfig,
+    depth: usize,
+    node_count: usize,
+    spans: Option<Vec<Span>>, // Span of every node built so far in post-order, when requested
+    open_parens: Vec<usize>,  // Byte offsets of the `(` not closed yet, innermost last
+    profile: Option<ParseProfile>, // Counters of the parse so far, when requested
+    consumed: Option<Vec<Token>>,  // Tokens moved past so far, when requested
+}
+
+// Shows where parsing is rather than every internal field
+impl<'a> fmt::Debug for Parser<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Parser")
+            .field("current_token", &self.current_token)
+            .field("position", &self.current_end)
+            .field("depth", &self.depth)
+            .finish()
+    }
 }
 
 // Public methods of Parser
 impl<'a> Parser<'a> {
     // Create a new instance of Parser
     pub fn new(expr: &'a str) -> Result<Self, ParseError> {
-        let mut lexer = Tokenizer::new(expr);
-        let cur_token = match lexer.next() {
-            Some(token) => token,
-            None => return Err(ParseError::InvalidOperator("Invalid character".into())),
-        };
-        Ok(Parser {
-            tokenizer: lexer,
-            current_token: cur_token,
-        })
+        Parser::new_with_config(expr, ParseConfig::default())
+    }
+
+    // Create a new instance of Parser with custom options
+    pub fn new_with_config(expr: &'a str, config: ParseConfig) -> Result<Self, ParseError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("Parser::new", expr_len = expr.len()).entered();
+        config.check().map_err(|e| e.with_expression(expr))?;
+        if Tokenizer::is_empty_or_whitespace(expr) {
+            return Err(ParseError::EmptyExpression { source: Some(expr.to_string()) });
+        }
+        let tokenizer = Tokenizer::new_with_decimal_separator(expr, config.decimal_separator);
+        Parser::from_source(TokenSource::Text(tokenizer), config)
     }
 
-    // Take an arithmetic expression as input and return an AST
+    // Create a Parser over tokens produced earlier, e.g. by collecting a Tokenizer, so an
+    // expression parsed many times is tokenized only once. A missing trailing EOF is implied
+    // and Token::Whitespace is skipped.
+    // Spacing is known only from Token::Whitespace: with the tokens of a WhitespaceTokenizer
+    // `(2+3) 4` is an error as in the text, without them a number after `)` is always an
+    // implicit product. Spans count tokens rather than bytes and errors carry no expression.
+    pub fn from_tokens(tokens: &'a [Token]) -> Result<Self, ParseError> {
+        Parser::from_tokens_with_config(tokens, ParseConfig::default())
+    }
+
+    // Create a Parser over tokens produced earlier with custom options, see `from_tokens`.
+    // The decimal separator is the tokenizer's business, so it has no effect here.
+    pub fn from_tokens_with_config(tokens: &'a [Token], config: ParseConfig) -> Result<Self, ParseError> {
+        let first = tokens.iter().find(|token| !matches!(token, Token::Whitespace(_)));
+        if first.is_none_or(|token| *token == Token::EOF) {
+            return Err(ParseError::EmptyExpression { source: None });
+        }
+        Parser::from_source(TokenSource::Tokens(tokens, 0, 0), config)
+    }
+
+    // Take an arithmetic expression as input and return an AST.
+    // With the default config `&`, `|`, `+`, `-`, `*` and `/` group from the left, so
+    // `8-4-2` is `(8-4)-2`, while `^` groups from the right, so `2^3^2` is `2^(3^2)`.
+    // Unary minus binds tighter than every binary operator: `-2^2` is `(-2)^2`.
+    #[must_use = "parse errors are only reported through the returned Result"]
     pub fn parse(&mut self) -> Result<Node, ParseError> {
-        let ast = self.generate_ast(OperPrec::DefaultZero)?;
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("Parser::parse", depth = tracing::field::Empty).entered();
+        let ast = self
+            .generate_ast(OperPrec::DefaultZero, Associativity::Left)
+            .and_then(|ast| self.reject_trailing().map(|_| ast))
+            .map_err(|e| self.tokenizer.locate(e))?;
+        #[cfg(feature = "tracing")]
+        span.record("depth", super::ast::depth(&ast));
         Ok(ast)
     }
+
+    // Like `parse`, but every node of the result carries the byte range of source it
+    // was parsed from. Parentheses around a subexpression are part of its span.
+    #[must_use = "parse errors are only reported through the returned Result"]
+    pub fn parse_spanned(&mut self) -> Result<SpannedNode<'a>, ParseError> {
+        self.spans = Some(Vec::new());
+        let node = self.parse()?;
+        let mut spans = self.spans.take().unwrap_or_default();
+        Ok(SpannedNode::from_post_order(node, &mut spans, self.tokenizer.source()))
+    }
+
+    // Like `parse`, also returning the tokens read on the way, ending with Token::EOF, so the
+    // expression is tokenized once. They are the tokens as the parser read them: `7 mod 3`
+    // gives Token::Modulo where the tokenizer alone gives Token::Ident("mod").
+    #[must_use = "parse errors are only reported through the returned Result"]
+    pub fn parse_with_tokens(&mut self) -> Result<(Node, Vec<Token>), ParseError> {
+        self.consumed = Some(Vec::new());
+        let node = self.parse()?;
+        let mut tokens = self.consumed.take().unwrap_or_default();
+        tokens.push(Token::EOF);
+        Ok((node, tokens))
+    }
+
+    // Parse an expression in prefix (Polish) notation, where each operator comes before
+    // its operands: `+ 2 * 3 4` is `2 + 3 * 4` and `^ 2 + 1 2` is `2^(1+2)`. Every operator,
+    // `-` included, takes two operands, except `!` which takes one. No parentheses are needed.
+    pub fn parse_prefix(expr: &str) -> Result<Node, ParseError> {
+        if Tokenizer::is_empty_or_whitespace(expr) {
+            return Err(ParseError::EmptyExpression { source: Some(expr.to_string()) });
+        }
+        let mut tokens = Tokenizer::new(expr);
+        let node = Parser::prefix_node(&mut tokens, 1).and_then(|node| match tokens.next() {
+            Some(Token::EOF) => Ok(node),
+            Some(token) => {
+                Err(ParseError::UnableToParse(format!("Unexpected {:?} after expression", token), None))
+            }
+            None => Err(ParseError::InvalidOperator("Invalid character".into(), None)),
+        });
+        node.map_err(|e| e.with_expression(expr))
+    }
+
+    // Parse an equation `lhs = rhs` into its two sides, each parsed as an independent expression.
+    // Only an `=` outside parentheses separates the sides; the `=` of a let binding does not.
+    pub fn parse_equation(expr: &str) -> Result<(Node, Node), ParseError> {
+        let mut tokens = Tokenizer::new(expr);
+        let mut separator = None;
+        let mut depth = 0usize;
+        let mut recent: [Option<Token>; 2] = [None, None]; // The two tokens before the current one
+        loop {
+            let invalid = || ParseError::InvalidOperator("Invalid character".into(), Some(expr.to_string()));
+            let token = tokens.next().ok_or_else(invalid)?;
+            match token {
+                Token::EOF => break,
+                Token::LeftParen => depth += 1,
+                Token::RightParen => depth = depth.saturating_sub(1),
+                Token::Assign if depth == 0 => {
+                    let binds_let = matches!(&recent, [Some(Token::Ident(kw)), Some(Token::Ident(_))] if kw == "let");
+                    if !binds_let {
+                        if separator.is_some() {
+                            return Err(ParseError::AmbiguousEquation { source: Some(expr.to_string()) });
+                        }
+                        separator = Some(tokens.token_start());
+                    }
+                }
+                _ => {}
+            }
+            recent = [recent[1].take(), Some(token)];
+        }
+        let at = separator.ok_or_else(|| ParseError::NotAnEquation { source: Some(expr.to_string()) })?;
+        let lhs = Parser::new(&expr[..at])?.parse()?;
+        let rhs = Parser::new(&expr[at + 1..])?.parse()?;
+        Ok((lhs, rhs))
+    }
+
+    // Parse one or more expressions separated by `sep`, e.g. `1,2+3` with Token::Comma.
+    // Stops at EOF or at the first token that is neither an operator nor `sep`.
+    pub fn parse_list(&mut self, sep: Token) -> Result<Vec<Node>, ParseError> {
+        self.list(sep).map_err(|e| self.tokenizer.locate(e))
+    }
+
+    // Parse exactly `n` comma-separated expressions, e.g. the arguments of a function taking
+    // `n`. The list must end at EOF or at a `)`, which is left for the caller to consume.
+    // Any other number of expressions is ParseError::WrongArgCount; leading and trailing
+    // commas are errors as in `parse_list`.
+    pub fn parse_expression_list(&mut self, n: usize) -> Result<Vec<Node>, ParseError> {
+        let at_end = |token: &Token| matches!(token, Token::RightParen | Token::EOF);
+        let items = if at_end(&self.current_token) { Vec::new() } else { self.parse_list(Token::Comma)? };
+        if !at_end(&self.current_token) {
+            let error = ParseError::UnableToParse(format!("Unexpected token {:?}", self.current_token), None);
+            return Err(self.tokenizer.locate(error));
+        }
+        if items.len() != n {
+            let error = ParseError::WrongArgCount { expected: n, got: items.len(), source: None };
+            return Err(self.tokenizer.locate(error));
+        }
+        Ok(items)
+    }
+
+    // The source from the token the parser will look at next to the end, e.g. `) * 2` once
+    // `(1 + 2` of `(1 + 2) * 2` is consumed. Empty for a Parser built from tokens.
+    #[must_use]
+    pub fn remaining_unparsed(&self) -> &'a str {
+        self.tokenizer.source().get(self.current_start..).unwrap_or("")
+    }
+
+    // The token the parser will look at next, without consuming it
+    #[must_use]
+    #[inline]
+    pub fn peek_token(&self) -> &Token {
+        &self.current_token
+    }
+
+    // The token after `peek_token`, None if it is not a valid token
+    #[must_use]
+    #[inline]
+    pub fn peek_second_token(&self) -> Option<&Token> {
+        self.next_token.as_ref()
+    }
+}
+
+// A Parser that owns its expression, so it can be stored or moved to another thread
+// without borrowing. Each call of `parse` parses the whole expression again.
+#[derive(Debug, Clone)]
+pub struct OwnedParser {
+    expr: String,
+    config: ParseConfig,
+}
+
+impl OwnedParser {
+    // Take ownership of `expr`, failing as Parser::new does on empty input or an invalid
+    // first token
+    pub fn new(expr: String) -> Result<Self, ParseError> {
+        OwnedParser::new_with_config(expr, ParseConfig::default())
+    }
+
+    pub fn new_with_config(expr: String, config: ParseConfig) -> Result<Self, ParseError> {
+        Parser::new_with_config(&expr, config.clone())?;
+        Ok(OwnedParser { expr, config })
+    }
+
+    // Parse the expression into an AST, as Parser::parse does
+    #[must_use = "parse errors are only reported through the returned Result"]
+    pub fn parse(&mut self) -> Result<Node, ParseError> {
+        Parser::new_with_config(&self.expr, self.config.clone())?.parse()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn source(&self) -> &str {
+        &self.expr
+    }
+}
+
+// Counters collected by `parse_and_profile`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ParseProfile {
+    // Tokens the parser moved past, EOF excluded, so `2+3` counts 3
+    pub token_count: usize,
+    // Entries into the recursive parsing method, one per level of operands parsed: `2+3`
+    // counts 2, the whole sum and the `3` to the right of `+`
+    pub parse_calls: usize,
+    pub max_depth: usize, // Deepest nesting of the parsing method, 1 for the outermost entry
+    pub parse_duration: Duration, // Time to tokenize and parse
+}
+
+// Parse `expr` like `Parser::parse`, counting the work done on the way
+pub fn parse_and_profile(expr: &str) -> Result<(Node, ParseProfile), ParseError> {
+    let start = Instant::now();
+    let mut parser = Parser::new(expr)?;
+    parser.profile = Some(ParseProfile::default());
+    let node = parser.parse()?;
+    let profile = ParseProfile {
+        parse_duration: start.elapsed(),
+        ..parser.profile.unwrap_or_default()
+    };
+    Ok((node, profile))
+}
+
+// A `)` with no `(` to close gives Err((its byte offset, 1)); unclosed `(` give
+// Err((byte offset of the innermost one, number left open)).
+pub fn validate_parentheses(expr: &str) -> Result<(), (usize, usize)> {
+    let mut open = Vec::new();
+    for (position, c) in expr.char_indices() {
+        match c {
+            '(' => open.push(position),
+            ')' if open.pop().is_none() => return Err((position, 1)),
+            _ => {}
+        }
+    }
+    match open.last() {
+        Some(&innermost) => Err((innermost, open.len())),
+        None => Ok(()),
+    }
+}
+
+// Parse `expr` and check that every free variable it reads is in `known_vars`, reporting
+// each unknown one as ParseError::UnknownVariable in sorted order. A syntax error is
+// returned on its own, as the only element.
+pub fn parse_and_validate_vars(expr: &str, known_vars: &HashSet<&str>) -> Result<(), Vec<ParseError>> {
+    let node = Parser::new(expr).and_then(|mut parser| parser.parse()).map_err(|e| vec![e])?;
+    let errors: Vec<ParseError> = free_variables(&node)
+        .into_iter()
+        .filter(|name| !known_vars.contains(name.as_str()))
+        .map(|name| ParseError::UnknownVariable(name, Some(expr.to_string())))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 // Private methods of Parser
 impl<'a> Parser<'a> {
+    // Prime a Parser with the first two tokens of `lexer`
+    fn from_source(mut lexer: TokenSource<'a>, config: ParseConfig) -> Result<Self, ParseError> {
+        let cur_token = match lexer.next() {
+            Some(token) => token,
+            None => return Err(lexer.locate(ParseError::InvalidOperator("Invalid character".into(), None))),
+        };
+        let (current_start, current_end) = (lexer.token_start(), lexer.offset());
+        let next_token = lexer.next();
+        let next_start = lexer.token_start();
+        Ok(Parser {
+            tokenizer: lexer,
+            current_token: cur_token,
+            current_start,
+            current_end,
+            next_start,
+            prev_end: 0,
+            next_token,
+            config,
+            depth: 0,
+            node_count: 0,
+            spans: None,
+            open_parens: Vec::new(),
+            profile: None,
+            consumed: None,
+        })
+    }
+
     // Retrieve the next token from arithmetic expression and set it to current_token field in Parser struct
     fn get_next_token(&mut self) -> Result<(), ParseError> {
-        self.current_token = match self.tokenizer.next() {
+        if let Some(profile) = &mut self.profile {
+            profile.token_count += usize::from(self.current_token != Token::EOF);
+        }
+        if let Some(consumed) = &mut self.consumed {
+            consumed.push(self.current_token.clone());
+        }
+        self.current_token = match self.next_token.take() {
             Some(token) => token,
-            None => return Err(ParseError::InvalidOperator("Unexpected end of input".into())),
+            None => return Err(ParseError::InvalidOperator("Unexpected end of input".into(), None)),
         };
+        self.prev_end = self.current_end;
+        self.current_start = self.next_start;
+        self.current_end = self.tokenizer.offset();
+        self.next_token = self.tokenizer.next();
+        self.next_start = self.tokenizer.token_start();
         Ok(())
     }
 
-    // Main workhorse method that is called recursively
-    fn generate_ast(&mut self, oper_prec: OperPrec) -> Result<Node, ParseError> {
+    // Read one prefix-notation node and its operands from `tokens`, `depth` levels down
+    fn prefix_node(tokens: &mut Tokenizer, depth: usize) -> Result<Node, ParseError> {
+        let max_depth = ParseConfig::default().max_depth;
+        if depth > max_depth {
+            return Err(ParseError::ExpressionTooDeep { depth: max_depth, source: None });
+        }
+        let binary: fn(Box<Node>, Box<Node>) -> Node = match tokens.next() {
+            Some(Token::Num(n)) => return Ok(Node::Number(n)),
+            Some(Token::Ident(name)) => return Ok(Node::Variable(name)),
+            Some(Token::Bang) => return Ok(Node::Not(Box::new(Parser::prefix_node(tokens, depth + 1)?))),
+            Some(Token::Add) => Node::Add,
+            Some(Token::Subtract) => Node::Subtract,
+            Some(Token::Multiply) => Node::Multiply,
+            Some(Token::Divide) => Node::Divide,
+            Some(Token::Caret) => Node::Caret,
+            Some(Token::Modulo) => |a, b| Node::Function("mod".into(), vec![*a, *b]),
+            Some(Token::Remainder) => |a, b| Node::Function("rem".into(), vec![*a, *b]),
+            Some(Token::And) => Node::And,
+            Some(Token::Or) => Node::Or,
+            Some(Token::EOF) => return Err(ParseError::UnableToParse("Missing operand".into(), None)),
+            Some(token) => return Err(ParseError::UnableToParse(format!("Unexpected {:?}", token), None)),
+            None => return Err(ParseError::InvalidOperator("Invalid character".into(), None)),
+        };
+        let left = Parser::prefix_node(tokens, depth + 1)?;
+        let right = Parser::prefix_node(tokens, depth + 1)?;
+        Ok(binary(Box::new(left), Box::new(right)))
+    }
+
+    // Parse a whole expression down to the loosest operator, with the configured algorithm
+    fn expression(&mut self) -> Result<Node, ParseError> {
+        match self.config.algorithm {
+            ParseAlgorithm::Pratt => self.generate_ast(OperPrec::DefaultZero, Associativity::Left),
+            ParseAlgorithm::PrecedenceClimbing => self.parse_precedence_climbing(0),
+        }
+    }
+
+    // `parse_list` before the expression is attached to its errors
+    fn list(&mut self, sep: Token) -> Result<Vec<Node>, ParseError> {
+        let mut items = vec![self.expression()?];
+        while self.current_token == sep {
+            self.get_next_token()?;
+            items.push(self.expression()?);
+        }
+        Ok(items)
+    }
+
+    // Main workhorse method that is called recursively.
+    // A right-associative operand also absorbs operators of its own precedence level.
+    fn generate_ast(&mut self, oper_prec: OperPrec, assoc: Associativity) -> Result<Node, ParseError> {
+        self.depth += 1;
+        if self.depth > self.config.max_depth {
+            return Err(ParseError::ExpressionTooDeep {
+                depth: self.config.max_depth,
+                source: None,
+            });
+        }
+        self.record_level();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("generate_ast", level = self.depth).entered();
+        let start = self.current_start;
         let mut left_expr = self.parse_number()?;
 
-        while oper_prec < self.current_token.get_oper_prec() {
-            if self.current_token == Token::EOF {
+        loop {
+            self.keyword_operator();
+            let implicit = self.implicit_multiply();
+            let next_prec = if implicit { OperPrec::MulDiv } else { self.current_token.get_oper_prec() };
+            let binds = oper_prec < next_prec
+                || (assoc == Associativity::Right
+                    && oper_prec == next_prec
+                    && next_prec != OperPrec::DefaultZero);
+            if !binds || self.current_token == Token::EOF {
                 break;
             }
-            let right_expr = self.convert_token_to_node(left_expr.clone())?;
+            // Binary operators sit above the level being parsed and below the prefix operators
+            debug_assert!(
+                next_prec.between(&oper_prec, &OperPrec::Unary)
+                    || (assoc == Associativity::Right && next_prec == oper_prec),
+                "{:?} operator parsed at level {:?}",
+                next_prec,
+                oper_prec
+            );
+            let right_expr = if implicit {
+                self.implicit_product(left_expr)?
+            } else {
+                self.convert_token_to_node(left_expr)?
+            };
+            // The right operand takes every operator binding tighter than the one just parsed
+            debug_assert!(
+                !self.current_token.get_oper_prec().between(&next_prec, &OperPrec::Unary),
+                "{:?} left behind after a {:?} operand",
+                self.current_token,
+                next_prec
+            );
+            self.record_span(start);
             left_expr = right_expr;
         }
+        self.depth -= 1;
+        Ok(left_expr)
+    }
+
+    // Parse an operand followed by every binary operator whose OperPrec level, as a number,
+    // is at least `min_prec`. The right operand of each takes only tighter operators, or
+    // operators of the same level too when it is right-associative: `2^3^2` is `2^(3^2)`.
+    fn parse_precedence_climbing(&mut self, min_prec: u8) -> Result<Node, ParseError> {
+        self.depth += 1;
+        if self.depth > self.config.max_depth {
+            return Err(ParseError::ExpressionTooDeep {
+                depth: self.config.max_depth,
+                source: None,
+            });
+        }
+        self.record_level();
+        let start = self.current_start;
+        let mut left_expr = self.parse_number()?;
+        loop {
+            self.keyword_operator();
+            let implicit = self.implicit_multiply();
+            let operator = if implicit { Token::Multiply } else { self.current_token.clone() };
+            let prec = operator.get_oper_prec();
+            if prec == OperPrec::DefaultZero || (prec as u8) < min_prec {
+                break;
+            }
+            self.count_node()?;
+            if !implicit {
+                self.get_next_token()?;
+            }
+            let next_min = match self.config.associativity(&operator) {
+                Associativity::Left => prec as u8 + 1,
+                Associativity::Right => prec as u8,
+            };
+            let right_expr = self.parse_precedence_climbing(next_min)?;
+            left_expr = binary_node(&operator, left_expr, right_expr)?;
+            self.record_span(start);
+        }
+        self.depth -= 1;
         Ok(left_expr)
     }
 
+    // After an operand the words `mod` and `rem` are operators, turn them into their tokens
+    fn keyword_operator(&mut self) {
+        if let Token::Ident(name) = &self.current_token {
+            match name.as_str() {
+                "mod" => self.current_token = Token::Modulo,
+                "rem" => self.current_token = Token::Remainder,
+                _ => {}
+            }
+        }
+    }
+
+    // Whether current_token starts the right operand of a product written without `*`:
+    // a `(` after any operand, or a number right after a closing parenthesis with no space
+    fn implicit_multiply(&self) -> bool {
+        match self.current_token {
+            Token::LeftParen => true,
+            Token::Num(_) => {
+                self.current_start == self.prev_end && self.tokenizer.closes_paren_at(self.prev_end)
+            }
+            _ => false,
+        }
+    }
+
+    // Multiply `left_expr` by the operand at current_token, as if a `*` preceded it
+    fn implicit_product(&mut self, left_expr: Node) -> Result<Node, ParseError> {
+        self.count_node()?;
+        let assoc = self.config.associativity(&Token::Multiply);
+        let right_expr = self.generate_ast(OperPrec::MulDiv, assoc)?;
+        Ok(Node::Multiply(Box::new(left_expr), Box::new(right_expr)))
+    }
+
+    // Account for one more AST node, aborting once the configured limit is exceeded
+    fn count_node(&mut self) -> Result<(), ParseError> {
+        self.node_count += 1;
+        if self.node_count > self.config.max_nodes {
+            return Err(ParseError::ExpressionTooLarge {
+                node_count: self.node_count,
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
     // Construct AST node for numbers, handling negative prefixes and parentheses
     fn parse_number(&mut self) -> Result<Node, ParseError> {
+        let start = self.current_start;
+        let parenthesised = self.current_token == Token::LeftParen;
+        let mut node = self.parse_operand()?;
+        if parenthesised {
+            // The inner expression already has a span; widen it to take in the parentheses
+            if let Some(spans) = &mut self.spans {
+                spans.pop();
+            }
+        }
+        self.record_span(start);
+        // Postfix operators apply to the operand right away, before any binary operator
+        while self.current_token.get_postfix_prec().is_some() {
+            self.count_node()?;
+            self.get_next_token()?;
+            node = Node::Function("factorial".into(), vec![node]);
+            self.record_span(start);
+        }
+        Ok(node)
+    }
+
+    // Count an entry into a parsing level and its depth, when profiling
+    fn record_level(&mut self) {
+        if let Some(profile) = &mut self.profile {
+            profile.parse_calls += 1;
+            profile.max_depth = profile.max_depth.max(self.depth);
+        }
+    }
+
+    // Remember the span from `start` to the end of the last consumed token, when spans are wanted
+    fn record_span(&mut self, start: usize) {
+        if let Some(spans) = &mut self.spans {
+            spans.push(Span { start, end: self.prev_end });
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Node, ParseError> {
         let token = self.current_token.clone();
+        if token != Token::LeftParen {
+            self.count_node()?;
+        }
         match token {
             Token::Subtract => {
                 self.get_next_token()?;
-                let expr = self.generate_ast(OperPrec::Negative)?;
+                let expr = self.generate_ast(OperPrec::Unary, Associativity::Left)?;
                 Ok(Node::Negative(Box::new(expr)))
             }
+            Token::Bang => {
+                self.get_next_token()?;
+                let expr = self.generate_ast(OperPrec::Unary, Associativity::Left)?;
+                Ok(Node::Not(Box::new(expr)))
+            }
             Token::Num(i) => {
                 self.get_next_token()?;
                 Ok(Node::Number(i))
             }
+            Token::Ident(name) if name == "let" && matches!(self.next_token, Some(Token::Ident(_))) => {
+                self.parse_let()
+            }
+            Token::Ident(name) if self.next_token == Some(Token::LeftParen) => self.parse_call(name),
+            Token::Ident(name) => {
+                self.get_next_token()?;
+                Ok(Node::Variable(name))
+            }
             Token::LeftParen => {
+                self.open_parens.push(self.current_start);
                 self.get_next_token()?;
-                let expr = self.generate_ast(OperPrec::DefaultZero)?;
+                let expr = self.expression()?;
                 self.check_paren(Token::RightParen)?;
                 Ok(expr)
             }
-            _ => Err(ParseError::UnableToParse("Unexpected token".to_string())),
+            Token::EOF if !self.open_parens.is_empty() => Err(self.unmatched_paren()),
+            _ => Err(ParseError::UnableToParse("Unexpected token".to_string(), None)),
         }
     }
 
+    // Parse the argument list of a function call, with current_token on the function name
+    fn parse_call(&mut self, name: String) -> Result<Node, ParseError> {
+        self.get_next_token()?;
+        self.open_parens.push(self.current_start);
+        self.get_next_token()?;
+        let mut args = Vec::new();
+        if self.current_token == Token::RightParen {
+            self.open_parens.pop();
+            self.get_next_token()?;
+            return Ok(Node::Function(name, args));
+        }
+        loop {
+            args.push(self.expression()?);
+            if self.current_token != Token::Comma {
+                break;
+            }
+            self.get_next_token()?;
+        }
+        self.check_paren(Token::RightParen)?;
+        Ok(Node::Function(name, args))
+    }
+
+    // Parse `let name = value in body`, with current_token on `let`
+    fn parse_let(&mut self) -> Result<Node, ParseError> {
+        self.get_next_token()?;
+        let name = match &self.current_token {
+            Token::Ident(name) => name.clone(),
+            other => {
+                let message = format!("Expected a name after let, got {:?}", other);
+                return Err(ParseError::InvalidOperator(message, None));
+            }
+        };
+        self.get_next_token()?;
+        self.check_paren(Token::Assign)?;
+        let value = self.expression()?;
+        if self.current_token != Token::Ident("in".into()) {
+            return Err(ParseError::InvalidOperator(format!(
+                "Expected in after let value, got {:?}",
+                self.current_token
+            ), None));
+        }
+        self.get_next_token()?;
+        let body = self.expression()?;
+        Ok(Node::Let(name, Box::new(value), Box::new(body)))
+    }
+
+    // Commas only separate list items, so one left over after an expression is an error
+    fn reject_comma(&self) -> Result<(), ParseError> {
+        if self.current_token == Token::Comma {
+            return Err(ParseError::UnexpectedComma { at: self.current_end - 1, source: None });
+        }
+        Ok(())
+    }
+
+    // A complete expression must use up the whole input
+    fn reject_trailing(&self) -> Result<(), ParseError> {
+        self.reject_comma()?;
+        if self.current_token != Token::EOF {
+            return Err(ParseError::UnableToParse(format!(
+                "Unexpected {:?} after expression",
+                self.current_token
+            ), None));
+        }
+        Ok(())
+    }
+
     // Check for balancing parentheses
     fn check_paren(&mut self, expected: Token) -> Result<(), ParseError> {
+        self.reject_comma()?;
         if self.current_token == expected {
+            if expected == Token::RightParen {
+                self.open_parens.pop();
+            }
             self.get_next_token()?;
             Ok(())
+        } else if expected == Token::RightParen && self.current_token == Token::EOF {
+            Err(self.unmatched_paren())
         } else {
             Err(ParseError::InvalidOperator(format!(
                 "Expected {:?}, got {:?}",
                 expected, self.current_token
-            )))
+            ), None))
+        }
+    }
+
+    // The input ended while the innermost `(` was still open
+    fn unmatched_paren(&self) -> ParseError {
+        ParseError::UnmatchedParen {
+            opened_at: self.open_parens.last().copied().unwrap_or_default(),
+            source: None,
         }
     }
 
     // Construct Operator AST nodes
     fn convert_token_to_node(&mut self, left_expr: Node) -> Result<Node, ParseError> {
-        match self.current_token {
-            Token::Add => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::AddSub)?;
-                Ok(Node::Add(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::Subtract => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::AddSub)?;
-                Ok(Node::Subtract(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::Multiply => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::MulDiv)?;
-                Ok(Node::Multiply(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::Divide => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::MulDiv)?;
-                Ok(Node::Divide(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::Caret => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::Exponent)?;
-                Ok(Node::Caret(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::And => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::Bitwise)?;
-                Ok(Node::And(Box::new(left_expr), Box::new(right_expr)))
-            }
-            Token::Or => {
-                self.get_next_token()?;
-                let right_expr = self.generate_ast(OperPrec::Bitwise)?;
-                Ok(Node::Or(Box::new(left_expr), Box::new(right_expr)))
-            }
-            _ => Err(ParseError::InvalidOperator(format!(
-                "Unexpected operator {:?}",
-                self.current_token
-            ))),
+        self.count_node()?;
+        let operator = self.current_token.clone();
+        let prec = operator.get_oper_prec();
+        if prec == OperPrec::DefaultZero {
+            return Err(ParseError::InvalidOperator(format!("Unexpected operator {:?}", operator), None));
         }
+        let assoc = self.config.associativity(&operator);
+        self.get_next_token()?;
+        let right_expr = self.generate_ast(prec, assoc)?;
+        binary_node(&operator, left_expr, right_expr)
     }
 }
 
-// Custom error handler for Parser
+// The node applying the binary operator `operator` to its two operands
+fn binary_node(operator: &Token, left_expr: Node, right_expr: Node) -> Result<Node, ParseError> {
+    let (a, b) = (Box::new(left_expr), Box::new(right_expr));
+    Ok(match operator {
+        Token::Add => Node::Add(a, b),
+        Token::Subtract => Node::Subtract(a, b),
+        Token::Multiply => Node::Multiply(a, b),
+        Token::Divide => Node::Divide(a, b),
+        Token::Caret => Node::Caret(a, b),
+        Token::Modulo => Node::Function("mod".into(), vec![*a, *b]),
+        Token::Remainder => Node::Function("rem".into(), vec![*a, *b]),
+        Token::And => Node::And(a, b),
+        Token::Or => Node::Or(a, b),
+        _ => return Err(ParseError::InvalidOperator(format!("Unexpected operator {:?}", operator), None)),
+    })
+}
+
+// Custom error handler for Parser. Every variant ends with the expression it was raised for,
+// None until one of the entry points attaches it with `with_expression`.
 #[derive(Debug)]
 pub enum ParseError {
-    UnableToParse(String),
-    InvalidOperator(String),
+    UnableToParse(String, Option<String>),
+    EvalFailed(EvalError, Option<String>),
+    InvalidOperator(String, Option<String>),
+    ExpressionTooDeep { depth: usize, source: Option<String> },
+    Timeout { source: Option<String> },
+    ExpressionTooLarge { node_count: usize, source: Option<String> },
+    EmptyExpression { source: Option<String> },
+    UnexpectedComma { at: usize, source: Option<String> },
+    // Input ended before the `(` at this byte offset was closed
+    UnmatchedParen { opened_at: usize, source: Option<String> },
+    NotAnEquation { source: Option<String> },     // parse_equation found no top-level `=`
+    AmbiguousEquation { source: Option<String> }, // parse_equation found more than one top-level `=`
+    UnknownVariable(String, Option<String>), // parse_and_validate_vars found a name outside the known set
+    // parse_expression_list found another count
+    WrongArgCount { expected: usize, got: usize, source: Option<String> },
+}
+
+impl ParseError {
+    // Attach the expression this error was raised for, replacing any attached earlier
+    #[must_use]
+    pub fn with_expression(mut self, expr: &str) -> ParseError {
+        *self.source_mut() = Some(expr.to_string());
+        self
+    }
+
+    // The same error with the attached expression dropped, for callers that show it already
+    #[must_use]
+    pub fn without_expression(mut self) -> ParseError {
+        *self.source_mut() = None;
+        self
+    }
+
+    // The expression this error was raised for, if one was attached
+    #[must_use]
+    pub fn source_expression(&self) -> Option<&str> {
+        match self {
+            ParseError::UnableToParse(_, source)
+            | ParseError::EvalFailed(_, source)
+            | ParseError::InvalidOperator(_, source)
+            | ParseError::UnknownVariable(_, source)
+            | ParseError::ExpressionTooDeep { source, .. }
+            | ParseError::Timeout { source }
+            | ParseError::ExpressionTooLarge { source, .. }
+            | ParseError::EmptyExpression { source }
+            | ParseError::UnexpectedComma { source, .. }
+            | ParseError::UnmatchedParen { source, .. }
+            | ParseError::NotAnEquation { source }
+            | ParseError::AmbiguousEquation { source }
+            | ParseError::WrongArgCount { source, .. } => source.as_deref(),
+        }
+    }
+
+    fn source_mut(&mut self) -> &mut Option<String> {
+        match self {
+            ParseError::UnableToParse(_, source)
+            | ParseError::EvalFailed(_, source)
+            | ParseError::InvalidOperator(_, source)
+            | ParseError::UnknownVariable(_, source)
+            | ParseError::ExpressionTooDeep { source, .. }
+            | ParseError::Timeout { source }
+            | ParseError::ExpressionTooLarge { source, .. }
+            | ParseError::EmptyExpression { source }
+            | ParseError::UnexpectedComma { source, .. }
+            | ParseError::UnmatchedParen { source, .. }
+            | ParseError::NotAnEquation { source }
+            | ParseError::AmbiguousEquation { source }
+            | ParseError::WrongArgCount { source, .. } => source,
+        }
+    }
+
+    // False for errors caused by hitting a resource limit rather than by the input's syntax or
+    // meaning. Correcting the input fixes a recoverable error, so an interactive caller can
+    // ask again, while retrying the same work after a limit was hit is pointless.
+    // The match lists every variant so that a new one must be classified here.
+    #[must_use]
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            ParseError::Timeout { .. }
+            | ParseError::ExpressionTooLarge { .. }
+            | ParseError::ExpressionTooDeep { .. } => false,
+            ParseError::UnableToParse(..)
+            | ParseError::EvalFailed(..)
+            | ParseError::InvalidOperator(..)
+            | ParseError::EmptyExpression { .. }
+            | ParseError::UnexpectedComma { .. }
+            | ParseError::UnmatchedParen { .. }
+            | ParseError::NotAnEquation { .. }
+            | ParseError::AmbiguousEquation { .. }
+            | ParseError::UnknownVariable(..)
+            | ParseError::WrongArgCount { .. } => true,
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
-            ParseError::UnableToParse(e) => write!(f, "Error in evaluating {}", e),
-            ParseError::InvalidOperator(e) => write!(f, "Error in evaluating {}", e),
+            ParseError::UnableToParse(e, _) => write!(f, "Error in evaluating {}", e),
+            ParseError::EvalFailed(e, _) => write!(f, "Error in evaluating {}", e),
+            ParseError::InvalidOperator(e, _) => write!(f, "Error in evaluating {}", e),
+            ParseError::ExpressionTooDeep { depth, .. } => {
+                write!(f, "Error in evaluating expression nested deeper than {} levels", depth)
+            }
+            ParseError::Timeout { .. } => write!(f, "Error in evaluating expression, timed out"),
+            ParseError::ExpressionTooLarge { node_count, .. } => {
+                write!(f, "Error in evaluating expression with {} or more nodes", node_count)
+            }
+            ParseError::EmptyExpression { .. } => write!(f, "Error in evaluating an empty expression"),
+            ParseError::UnexpectedComma { at, .. } => {
+                write!(f, "Error in evaluating expression, unexpected comma at offset {}", at)
+            }
+            ParseError::UnmatchedParen { opened_at, .. } => {
+                write!(f, "Error in evaluating expression, parenthesis at offset {} is never closed", opened_at)
+            }
+            ParseError::NotAnEquation { .. } => {
+                write!(f, "Error in evaluating equation, no `=` separates the sides")
+            }
+            ParseError::AmbiguousEquation { .. } => {
+                write!(f, "Error in evaluating equation, more than one `=` separates the sides")
+            }
+            ParseError::UnknownVariable(name, _) => {
+                write!(f, "Error in evaluating expression, unknown variable {}", name)
+            }
+            ParseError::WrongArgCount { expected, got, .. } => {
+                write!(f, "Error in evaluating expression, expected {} arguments but got {}", expected, got)
+            }
+        }?;
+        match self.source_expression() {
+            Some(expr) => write!(f, " (in `{}`)", expr),
+            None => Ok(()),
         }
     }
 }
 
 // Handle error thrown from AST module
-impl From<Box<dyn std::error::Error>> for ParseError {
-    fn from(_evalerr: Box<dyn std::error::Error>) -> Self {
-        ParseError::UnableToParse("Parsing error occurred".into())
+impl From<EvalError> for ParseError {
+    fn from(evalerr: EvalError) -> Self {
+        ParseError::EvalFailed(evalerr, None)
+    }
+}
+
+// Expose the wrapped error, so error chains reach the underlying EvalError
+impl error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ParseError::EvalFailed(e, _) => Some(e),
+            _ => None,
+        }
     }
 }
 
 #[cfg(test)]
+#[deny(unused_must_use)]
 mod tests {
     use super::*;
     use crate::parsemath::ast::Node::{Add, Multiply, Caret, Or, Number};
+    use crate::parsemath::ast::{eval, eval_with_context};
+    use crate::parsemath::context::EvalContext;
+
+    // `config` with each ParseAlgorithm in turn
+    fn with_each_algorithm(config: ParseConfig) -> [ParseConfig; 2] {
+        [ParseAlgorithm::Pratt, ParseAlgorithm::PrecedenceClimbing]
+            .map(|algorithm| ParseConfig { algorithm, ..config.clone() })
+    }
+
+    // Parse `expr` with `config` under both algorithms, failing the test unless they agree, so
+    // every test parsing through it covers both. Returns the Pratt result.
+    fn parse_with(expr: &str, config: ParseConfig) -> Result<Node, ParseError> {
+        let [pratt, climbing] = with_each_algorithm(config)
+            .map(|config| Parser::new_with_config(expr, config).and_then(|mut parser| parser.parse()));
+        match (&pratt, &climbing) {
+            (Ok(a), Ok(b)) => assert_eq!(a, b, "{}", expr),
+            (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string(), "{}", expr),
+            _ => panic!("{}: {:?} and {:?}", expr, pratt, climbing),
+        }
+        pratt
+    }
+
+    fn parse(expr: &str) -> Result<Node, ParseError> {
+        parse_with(expr, ParseConfig::default())
+    }
 
     #[test]
     fn test_parse_exponentiation() {
-        let mut parser = Parser::new("2^3").unwrap();
         let expected = Caret(Box::new(Number(2.0)), Box::new(Number(3.0)));
-        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parse("2^3").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_validate_parentheses() {
+        assert_eq!(validate_parentheses(""), Ok(()));
+        assert_eq!(validate_parentheses("(1+2)*3"), Ok(()));
+        assert_eq!(validate_parentheses("((1)+(2*(3)))"), Ok(()));
+        assert_eq!(validate_parentheses("1+2)"), Err((3, 1)));
+        assert_eq!(validate_parentheses("(1))+(2"), Err((3, 1)));
+        assert_eq!(validate_parentheses("((1+2"), Err((1, 2)));
+        assert_eq!(validate_parentheses("(1+(2)*(3"), Err((7, 2)));
+        assert_eq!(validate_parentheses("é)"), Err((2, 1)));
+    }
+
+    #[test]
+    fn test_owned_parser_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Parser<'static>>();
+        assert_send_sync::<Tokenizer<'static>>();
+        assert_send_sync::<OwnedParser>();
+
+        let mut parser = OwnedParser::new(String::from("2+3*4")).unwrap();
+        let handle = std::thread::spawn(move || (parser.parse(), parser.source().to_string()));
+        let (node, source) = handle.join().unwrap();
+        assert_eq!(node.unwrap(), parse("2+3*4").unwrap());
+        assert_eq!(source, "2+3*4");
+    }
+
+    #[test]
+    fn test_owned_parser_errors() {
+        assert!(matches!(OwnedParser::new(String::new()), Err(ParseError::EmptyExpression { .. })));
+        assert!(OwnedParser::new("$".into()).is_err());
+        let mut parser = OwnedParser::new("2 +".into()).unwrap();
+        assert!(parser.parse().is_err());
+        let config = ParseConfig { max_depth: 2, ..ParseConfig::default() };
+        let mut parser = OwnedParser::new_with_config("((((1))))".into(), config).unwrap();
+        assert!(parser.parse().is_err());
     }
 
     #[test]
     fn test_parse_complex_expression() {
-        let mut parser = Parser::new("3+2*4").unwrap();
         let expected = Add(Box::new(Number(3.0)), Box::new(Multiply(Box::new(Number(2.0)), Box::new(Number(4.0)))));
-        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parse("3+2*4").unwrap(), expected);
     }
 
     #[test]
     fn test_parse_bitwise_or() {
-        let mut parser = Parser::new("6|2").unwrap();
         let expected = Or(Box::new(Number(6.0)), Box::new(Number(2.0)));
-        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parse("6|2").unwrap(), expected);
     }
 
     #[test]
     fn test_parse_negative_number() {
-        let mut parser = Parser::new("-5").unwrap();
         let expected = Node::Negative(Box::new(Number(5.0))); // Fix: Expect `Negative` instead of `Subtract(0, 5)`
-        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parse("-5").unwrap(), expected);
     }
     
 
     #[test]
     fn test_parse_parentheses() {
-        let mut parser = Parser::new("(2+3)").unwrap();
         let expected = Add(Box::new(Number(2.0)), Box::new(Number(3.0)));
-        assert_eq!(parser.parse().unwrap(), expected);
+        assert_eq!(parse("(2+3)").unwrap(), expected);
+    }
+
+    // Parse `expr`, expecting it to stop at an unclosed parenthesis, and return its offset
+    fn unmatched_paren_at(expr: &str) -> usize {
+        let error = parse(expr).unwrap_err();
+        match error {
+            ParseError::UnmatchedParen { opened_at, .. } => opened_at,
+            other => panic!("{}: expected UnmatchedParen, got {:?}", expr, other),
+        }
+    }
+
+    #[test]
+    fn test_unmatched_paren_alone() {
+        assert_eq!(unmatched_paren_at("("), 0);
+        assert_eq!(unmatched_paren_at("  ( "), 2);
+    }
+
+    #[test]
+    fn test_unmatched_paren_after_number() {
+        assert_eq!(unmatched_paren_at("(2"), 0);
+        assert_eq!(unmatched_paren_at("1+(2"), 2);
+    }
+
+    #[test]
+    fn test_unmatched_paren_after_operator() {
+        assert_eq!(unmatched_paren_at("(2+"), 0);
+        assert_eq!(unmatched_paren_at("max(1, (2*"), 7);
+    }
+
+    #[test]
+    fn test_unmatched_paren_after_operand() {
+        assert_eq!(unmatched_paren_at("(2+3"), 0);
+        assert_eq!(unmatched_paren_at("((2+3)"), 0);
+        assert_eq!(unmatched_paren_at("(1)*(2+3"), 4);
+        assert_eq!(unmatched_paren_at("sin(x"), 3);
+        let error = parse("(1)+2)").unwrap_err();
+        assert!(matches!(error, ParseError::UnableToParse(..)));
+    }
+
+    #[test]
+    fn test_postfix_factorial() {
+        let value = |expr: &str| eval(parse(expr).unwrap());
+        assert_eq!(value("3! + 1"), Ok(7.0));
+        assert_eq!(value("2^3!"), Ok(64.0));
+        assert_eq!(value("-3!"), Ok(-6.0));
+        assert_eq!(value("(1+2)!!"), Ok(720.0));
+        assert_eq!(value("0! * 2 & !0"), Ok(0.0));
+        assert_eq!(value("factorial(5)"), Ok(120.0));
+        assert_eq!(value("171!"), Ok(f64::INFINITY));
+        let spanned = Parser::new("2*x!").unwrap().parse_spanned().unwrap();
+        assert_eq!(spanned.children[1].text(), "x!");
+        assert_eq!(spanned.children[1].children[0].text(), "x");
+    }
+
+    #[test]
+    fn test_factorial_domain() {
+        for expr in ["(0-1)!", "2.5!"] {
+            let result = eval(parse(expr).unwrap());
+            assert!(matches!(result, Err(EvalError::InvalidArgument(_))), "{}", expr);
+        }
+        assert!(parse("!").is_err());
+        assert!(parse("3 !4").is_err());
+    }
+
+    #[test]
+    fn test_parse_variables() {
+        let expected = Add(
+            Box::new(Multiply(Box::new(Number(2.0)), Box::new(Node::Variable("x".into())))),
+            Box::new(Node::Variable("y".into())),
+        );
+        assert_eq!(parse("2*x+y").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let mut parser = Parser::new("1,2+3,4*5").unwrap();
+        let expected = vec![
+            Number(1.0),
+            Add(Box::new(Number(2.0)), Box::new(Number(3.0))),
+            Multiply(Box::new(Number(4.0)), Box::new(Number(5.0))),
+        ];
+        assert_eq!(parser.parse_list(Token::Comma).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_list_single_and_stop() {
+        let mut parser = Parser::new("7").unwrap();
+        assert_eq!(parser.parse_list(Token::Comma).unwrap(), vec![Number(7.0)]);
+        let mut parser = Parser::new("1,2)").unwrap();
+        assert_eq!(parser.parse_list(Token::Comma).unwrap(), vec![Number(1.0), Number(2.0)]);
+    }
+
+    #[test]
+    fn test_parse_and_profile() {
+        let (node, profile) = parse_and_profile("2+3").unwrap();
+        assert_eq!(node, Add(Box::new(Number(2.0)), Box::new(Number(3.0))));
+        assert_eq!((profile.token_count, profile.parse_calls, profile.max_depth), (3, 2, 2));
+        assert!(profile.parse_duration > Duration::ZERO);
+        let (_, profile) = parse_and_profile("7").unwrap();
+        assert_eq!((profile.token_count, profile.parse_calls, profile.max_depth), (1, 1, 1));
+        let (_, profile) = parse_and_profile("((1))").unwrap();
+        assert_eq!((profile.token_count, profile.parse_calls, profile.max_depth), (5, 3, 3));
+        // The whole product, the right operand of each `*`, the inside of `(3)` and the `4` after `-`
+        let (_, profile) = parse_and_profile("2*(3)*-4").unwrap();
+        assert_eq!((profile.token_count, profile.parse_calls, profile.max_depth), (8, 5, 3));
+    }
+
+    #[test]
+    fn test_parse_and_profile_matches_parse() {
+        for expr in ["2+3*4", "-(1-x)^2", "max(1, 2, 3)!", "let y = 2 in y*y"] {
+            let (node, profile) = parse_and_profile(expr).unwrap();
+            assert_eq!(node, parse(expr).unwrap());
+            assert!(profile.max_depth >= 2 && profile.parse_calls >= profile.max_depth, "{}", expr);
+        }
+        assert!(parse_and_profile("2+").is_err());
+        assert!(parse_and_profile("(1").is_err());
+    }
+
+    #[test]
+    fn test_parse_expression_list() {
+        let items = Parser::new("1, 2+3, 4*5").unwrap().parse_expression_list(3).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1], Add(Box::new(Number(2.0)), Box::new(Number(3.0))));
+        let mut parser = Parser::new("1, 2) * 3").unwrap();
+        assert_eq!(parser.parse_expression_list(2).unwrap(), vec![Number(1.0), Number(2.0)]);
+        assert_eq!(parser.peek_token(), &Token::RightParen);
+        assert!(Parser::new(")").unwrap().parse_expression_list(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_expression_list_errors() {
+        let error = Parser::new("1, 2+3, 4*5").unwrap().parse_expression_list(2).unwrap_err();
+        assert!(matches!(error, ParseError::WrongArgCount { expected: 2, got: 3, .. }));
+        assert_eq!(error.source_expression(), Some("1, 2+3, 4*5"));
+        let message = "Error in evaluating expression, expected 2 arguments but got 3";
+        assert_eq!(error.without_expression().to_string(), message);
+        let error = Parser::new(")").unwrap().parse_expression_list(1).unwrap_err();
+        assert!(matches!(error, ParseError::WrongArgCount { expected: 1, got: 0, .. }));
+        for expr in ["1, 2,", ", 1, 2", "1,, 2"] {
+            let error = Parser::new(expr).unwrap().parse_expression_list(2).unwrap_err();
+            assert!(!matches!(error, ParseError::WrongArgCount { .. }), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parse_list_trailing_comma() {
+        let mut parser = Parser::new("1,2,").unwrap();
+        assert!(parser.parse_list(Token::Comma).is_err());
+    }
+
+    #[test]
+    fn test_peek_tokens() {
+        let parser = Parser::new("2+3").unwrap();
+        assert_eq!(parser.peek_token(), &Token::Num(2.0));
+        assert_eq!(parser.peek_second_token(), Some(&Token::Add));
+        let parser = Parser::new("2$").unwrap();
+        assert_eq!(parser.peek_second_token(), None);
+        let parser = Parser::new("2").unwrap();
+        assert_eq!(parser.peek_second_token(), Some(&Token::EOF));
+    }
+
+    #[test]
+    fn test_peek_does_not_consume() {
+        for expr in ["3+2*4", "(1-2)^2", "-5&3"] {
+            let mut peeked = Parser::new(expr).unwrap();
+            let first = peeked.peek_token().clone();
+            let second = peeked.peek_second_token().cloned();
+            assert_eq!(peeked.peek_token(), &first);
+            assert_eq!(peeked.peek_second_token().cloned(), second);
+            assert_eq!(peeked.parse().unwrap(), parse(expr).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_caret_chain_right_associative() {
+        let node = |expr: &str| parse(expr).unwrap();
+        let expected = Caret(Box::new(Number(2.0)), Box::new(Caret(Box::new(Number(3.0)), Box::new(Number(2.0)))));
+        assert_eq!(node("2^3^2"), expected);
+        assert_eq!(node("2^3^2"), node("2^(3^2)"));
+        assert_eq!(node("2^1^2^3"), node("2^(1^(2^3))"));
+        assert_eq!(crate::parsemath::ast::eval(node("2^3^2")).unwrap(), 512.0);
+        assert_eq!(crate::parsemath::ast::eval(node("(2^3)^2")).unwrap(), 64.0);
+    }
+
+    #[test]
+    fn test_unary_prec_ordering() {
+        assert!(OperPrec::Exponent < OperPrec::Unary);
+        assert!(OperPrec::MulDiv < OperPrec::Unary);
+        assert_eq!(OperPrec::Negative, OperPrec::Unary);
+    }
+
+    #[test]
+    fn test_token_and_prec_defaults() {
+        assert_eq!(Token::default(), Token::EOF);
+        assert_eq!(OperPrec::default(), OperPrec::DefaultZero);
+        assert_eq!(Token::default().get_oper_prec(), OperPrec::default());
+    }
+
+    #[test]
+    fn test_default_token_field_matches_new() {
+        for expr in ["2+3*4", "-(1-x)", "7"] {
+            let mut lexer = Tokenizer::new(expr);
+            let next_token = lexer.next();
+            let next_start = lexer.token_start();
+            let mut primed = Parser {
+                tokenizer: TokenSource::Text(lexer),
+                current_token: Token::default(),
+                current_start: 0,
+                current_end: 0,
+                next_start,
+                prev_end: 0,
+                next_token,
+                config: ParseConfig::default(),
+                depth: 0,
+                node_count: 0,
+                spans: None,
+                open_parens: Vec::new(),
+                profile: None,
+                consumed: None,
+            };
+            primed.get_next_token().unwrap();
+            let explicit = Parser::new(expr).unwrap();
+            assert_eq!(primed.peek_token(), explicit.peek_token());
+            assert_eq!(primed.peek_second_token(), explicit.peek_second_token());
+            assert_eq!(primed.parse().unwrap(), parse(expr).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_from_tokens_matches_new() {
+        for expr in ["2+3*4", "-(1-x)^2", "let y = 2 in y*y", "max(1, 2, 3)!", "2(3+4)", "(1+2)3", "7 mod 3"] {
+            let tokens: Vec<Token> = Tokenizer::new(expr).take_while(|token| *token != Token::EOF).collect();
+            let expected = parse(expr).unwrap();
+            assert_eq!(Parser::from_tokens(&tokens).unwrap().parse().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_from_tokens_skips_whitespace() {
+        for expr in [" 2 + 3*4 ", "max( 1,\t2 )\n", "let y = 2 in y * y"] {
+            let tokens: Vec<Token> =
+                Tokenizer::new_with_whitespace(expr).take_while(|t| *t != Token::EOF).collect();
+            let expected = parse(expr).unwrap();
+            assert_eq!(Parser::from_tokens(&tokens).unwrap().parse().unwrap(), expected);
+        }
+        let spaced: Vec<Token> =
+            Tokenizer::new_with_whitespace("(1+2) 3").take_while(|t| *t != Token::EOF).collect();
+        assert!(Parser::from_tokens(&spaced).unwrap().parse().is_err());
+        let blank = [Token::Whitespace(" ".into()), Token::EOF];
+        assert!(matches!(Parser::from_tokens(&blank), Err(ParseError::EmptyExpression { .. })));
+    }
+
+    #[test]
+    fn test_parse_and_validate_vars() {
+        let known = HashSet::from(["x"]);
+        let errors = parse_and_validate_vars("x + y + z", &known).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ParseError::UnknownVariable(name, _) if name == "y"));
+        assert!(matches!(&errors[1], ParseError::UnknownVariable(name, _) if name == "z"));
+        assert!(parse_and_validate_vars("x * 2 + let t = 3 in t * x", &known).is_ok());
+        assert!(parse_and_validate_vars("1 + 2", &HashSet::new()).is_ok());
+        let errors = parse_and_validate_vars("x +", &known).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(!matches!(errors[0], ParseError::UnknownVariable(..)));
+    }
+
+    #[test]
+    fn test_remaining_unparsed() {
+        let mut parser = Parser::new("2+3 , 4").unwrap();
+        assert_eq!(parser.remaining_unparsed(), "2+3 , 4");
+        parser.generate_ast(OperPrec::DefaultZero, Associativity::Left).unwrap();
+        assert_eq!(parser.remaining_unparsed(), ", 4");
+        let tokens = [Token::Num(1.0), Token::Comma];
+        assert_eq!(Parser::from_tokens(&tokens).unwrap().remaining_unparsed(), "");
+    }
+
+    #[test]
+    fn test_from_tokens_errors() {
+        assert!(matches!(Parser::from_tokens(&[]), Err(ParseError::EmptyExpression { .. })));
+        assert!(matches!(Parser::from_tokens(&[Token::EOF]), Err(ParseError::EmptyExpression { .. })));
+        let tokens = [Token::Num(2.0), Token::Add];
+        let error = Parser::from_tokens(&tokens).unwrap().parse().unwrap_err();
+        assert!(error.source_expression().is_none());
+        let tokens = [Token::LeftParen, Token::Num(1.0)];
+        let error = Parser::from_tokens(&tokens).unwrap().parse().unwrap_err();
+        assert!(matches!(error, ParseError::UnmatchedParen { opened_at: 0, .. }));
+    }
+
+    #[test]
+    fn test_default_config_matches_new() {
+        for expr in ["2+3*4", "2^3^2", "-5+2", "(1-2)-3", "8/4/2", "6|2&3", "((2))"] {
+            let plain = Parser::new(expr).unwrap().parse().unwrap();
+            let configured = parse_with(expr, ParseConfig::default()).unwrap();
+            assert_eq!(plain, configured);
+        }
+    }
+
+    #[test]
+    fn test_config_left_assoc_caret() {
+        let config = ParseConfig {
+            operator_associativity: vec![(Token::Caret, Associativity::Left)],
+            ..ParseConfig::default()
+        };
+        let expected = Caret(Box::new(Caret(Box::new(Number(2.0)), Box::new(Number(3.0)))), Box::new(Number(2.0)));
+        assert_eq!(parse_with("2^3^2", config).unwrap(), expected);
+    }
+
+    // Expressions from the parser tests, valid and invalid, for comparing the two algorithms
+    const ALGORITHM_CASES: &[&str] = &[
+        "2^3", "3+2*4", "2 + 3 * 4", "8-4-2", "2^3^2", "-2^2", "-3!", "2^3!", "(1)+2)", "(1", "2*/3",
+        "6|2", "x & !y", "1 | 2 & 3 + 4", "2(3+4)", "(1+2)(3+4)2", "(1+2) 3", "x(y+1)", "2 3",
+        "7 mod 3 rem 2 * 4", "(-7) % 3", "let a = 1 in a+2", "let x = 2 in let y = x^2 in y - x",
+        "max(1, 2+3, 4*5)!", "mod(5)", " (1 + x) * -max(2, y) ", "3 !4", "1,2", "2$", "2 +", "f()",
+        "1 - -1 - (2 - 3) / 4 / 5 ^ 2 ^ -0.5", "!0 | !1 & 2 - 3 * 4 ^ 5 !",
+    ];
+
+    #[test]
+    fn test_precedence_climbing_matches_pratt() {
+        let climbing = ParseConfig {
+            algorithm: ParseAlgorithm::PrecedenceClimbing,
+            ..ParseConfig::default()
+        };
+        for expr in ALGORITHM_CASES {
+            let pratt = Parser::new(expr).and_then(|mut parser| parser.parse());
+            let other = Parser::new_with_config(expr, climbing.clone()).and_then(|mut parser| parser.parse());
+            match (pratt, other) {
+                (Ok(a), Ok(b)) => assert_eq!(a, b, "{}", expr),
+                (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string(), "{}", expr),
+                (a, b) => panic!("{}: {:?} and {:?}", expr, a, b),
+            }
+            let spans = |config: ParseConfig| {
+                Parser::new_with_config(expr, config).and_then(|mut parser| parser.parse_spanned()).ok()
+            };
+            assert_eq!(spans(ParseConfig::default()), spans(climbing.clone()), "{}", expr);
+        }
+        let left_caret = |algorithm| ParseConfig {
+            operator_associativity: vec![(Token::Caret, Associativity::Left)],
+            algorithm,
+            ..ParseConfig::default()
+        };
+        let parse = |config| Parser::new_with_config("2^3^2", config).unwrap().parse().unwrap();
+        let expected = parse(left_caret(ParseAlgorithm::Pratt));
+        assert_eq!(parse(left_caret(ParseAlgorithm::PrecedenceClimbing)), expected);
+    }
+
+    #[test]
+    fn test_parse_precedence_climbing_min_prec() {
+        let mut parser = Parser::new("2*3+4").unwrap();
+        let product = parser.parse_precedence_climbing(OperPrec::MulDiv as u8).unwrap();
+        assert_eq!(product, Multiply(Box::new(Number(2.0)), Box::new(Number(3.0))));
+        assert_eq!(parser.peek_token(), &Token::Add);
+        let config = ParseConfig {
+            max_depth: 3,
+            algorithm: ParseAlgorithm::PrecedenceClimbing,
+            ..ParseConfig::default()
+        };
+        let error = parse_with("((((2))))", config).unwrap_err();
+        assert!(matches!(error, ParseError::ExpressionTooDeep { depth: 3, .. }));
+    }
+
+    #[test]
+    fn test_config_decimal_separator() {
+        let config = ParseConfig { decimal_separator: ',', ..ParseConfig::default() };
+        let european = |expr: &str| parse_with(expr, config.clone());
+        assert_eq!(european("3,14").unwrap(), Number(3.14));
+        assert_eq!(eval(european("max(1;2)").unwrap()).unwrap(), 2.0);
+        assert_eq!(eval(european("max(1,5; 0,25) * 2").unwrap()).unwrap(), 3.0);
+        assert!(european("max(1, 2)").is_err());
+        assert!(european("1.5").is_err());
+        assert!(parse("max(1;2)").is_err());
+    }
+
+    #[test]
+    fn test_config_rejects_taken_decimal_separator() {
+        for separator in ['+', '-', '^', '(', '!', '=', '5', 'x', '_', ' '] {
+            let config = ParseConfig { decimal_separator: separator, ..ParseConfig::default() };
+            assert!(config.check().is_err(), "{:?}", separator);
+            let error = Parser::new_with_config("1+5", config).err().unwrap();
+            assert!(matches!(error, ParseError::UnableToParse(..)), "{:?}", separator);
+            assert_eq!(error.source_expression(), Some("1+5"));
+        }
+        for separator in ['.', ',', '\'', '·'] {
+            let config = ParseConfig { decimal_separator: separator, ..ParseConfig::default() };
+            assert!(config.check().is_ok(), "{:?}", separator);
+        }
+        let config = ParseConfig { decimal_separator: '\'', ..ParseConfig::default() };
+        assert_eq!(parse_with("1'5 * 2", config).unwrap(), parse("1.5 * 2").unwrap());
+    }
+
+    #[test]
+    fn test_config_max_depth() {
+        let config = ParseConfig { max_depth: 3, ..ParseConfig::default() };
+        assert!(parse_with("((2))", config.clone()).is_ok());
+        let error = parse_with("(((2)))", config).unwrap_err();
+        assert!(matches!(error, ParseError::ExpressionTooDeep { depth: 3, .. }));
+    }
+
+    #[test]
+    fn test_config_max_nodes() {
+        let config = ParseConfig { max_nodes: 5, ..ParseConfig::default() };
+        assert!(parse_with("2+3*4", config.clone()).is_ok());
+        let error = parse_with("2+3*4-1", config).unwrap_err();
+        assert!(matches!(error, ParseError::ExpressionTooLarge { node_count: 6, .. }));
+    }
+
+    #[test]
+    fn test_eval_failed_source() {
+        use std::error::Error;
+        let error = ParseError::EvalFailed(EvalError::DivisionByZero, None);
+        let source = error.source().expect("EvalFailed has a source");
+        assert_eq!(source.downcast_ref::<EvalError>(), Some(&EvalError::DivisionByZero));
+        assert_eq!(error.to_string(), "Error in evaluating Division by zero");
+        assert!(ParseError::UnableToParse("x".into(), None).source().is_none());
+    }
+
+    #[test]
+    fn test_eval_error_chain_through_expression() {
+        use std::error::Error;
+        let error = ParseError::from(EvalError::NaNResult).with_expression("0/0");
+        assert_eq!(error.source().unwrap().downcast_ref::<EvalError>(), Some(&EvalError::NaNResult));
+    }
+
+    #[test]
+    fn test_parse_let() {
+        let node = |expr: &str| parse(expr).unwrap();
+        let expected = Node::Let(
+            "x".into(),
+            Box::new(Add(Box::new(Number(2.0)), Box::new(Number(3.0)))),
+            Box::new(Multiply(Box::new(Node::Variable("x".into())), Box::new(Node::Variable("x".into())))),
+        );
+        assert_eq!(node("let x = 2+3 in x*x"), expected);
+        assert_eq!(node("let x=2+3 in x*x"), expected);
+        assert!(matches!(node("1 + let y = 2 in y"), Add(_, b) if matches!(*b, Node::Let(..))));
+        assert_eq!(node("let+1"), Add(Box::new(Node::Variable("let".into())), Box::new(Number(1.0))));
+    }
+
+    #[test]
+    fn test_implicit_multiplication() {
+        let value = |expr: &str| eval(parse(expr).unwrap());
+        assert_eq!(value("2(3+4)"), Ok(14.0));
+        assert_eq!(value("(2+3)(4+5)"), Ok(45.0));
+        assert_eq!(value("(2+3)4"), Ok(20.0));
+        assert_eq!(value("1+2(3)^2"), Ok(19.0));
+        assert_eq!(value("12/2(3)"), Ok(18.0));
+        assert_eq!(value("max(2, 3)(4)"), Ok(12.0));
+        assert_eq!(
+            parse("x(y+1)").unwrap(),
+            Node::Function("x".into(), vec![parse("y+1").unwrap()])
+        );
+    }
+
+    #[test]
+    fn test_implicit_multiplication_needs_parens() {
+        for expr in ["2 3", "2(3) 4 5", "(2)3 4", "(2+3) 4"] {
+            let error = parse(expr).unwrap_err();
+            assert!(matches!(error, ParseError::UnableToParse(..)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parser_debug() {
+        let mut parser = Parser::new("2 * x").unwrap();
+        assert_eq!(format!("{:?}", parser), "Parser { current_token: Num(2.0), position: 1, depth: 0 }");
+        parser.get_next_token().unwrap();
+        let debug = format!("{:?}", parser);
+        assert!(debug.contains(&format!("{:?}", Token::Multiply)) && debug.contains("position: 3"), "{}", debug);
+
+        #[derive(Debug)]
+        struct Holder<'a> {
+            parser: Parser<'a>,
+        }
+        let holder = Holder { parser: Parser::new("y").unwrap() };
+        assert!(format!("{:?}", holder).contains(r#"Ident("y")"#));
+    }
+
+    #[test]
+    fn test_is_recoverable() {
+        let recoverable = [
+            ParseError::UnableToParse("x".into(), None),
+            ParseError::EvalFailed(EvalError::UnknownVariable("x".into()), None),
+            ParseError::InvalidOperator("x".into(), None),
+            ParseError::EmptyExpression { source: None },
+            ParseError::UnexpectedComma { at: 1, source: None },
+            ParseError::UnmatchedParen { opened_at: 0, source: None },
+            ParseError::NotAnEquation { source: None },
+            ParseError::AmbiguousEquation { source: None },
+            ParseError::EmptyExpression { source: None }.with_expression(""),
+        ];
+        let fatal = [
+            ParseError::Timeout { source: None },
+            ParseError::ExpressionTooLarge { node_count: 1, source: None },
+            ParseError::ExpressionTooDeep { depth: 1, source: None },
+            ParseError::Timeout { source: None }.with_expression("1"),
+        ];
+        assert!(recoverable.iter().all(ParseError::is_recoverable));
+        assert!(!fatal.iter().any(ParseError::is_recoverable));
+        assert!(parse("(1").unwrap_err().is_recoverable());
+    }
+
+    #[test]
+    fn test_parse_equation() {
+        let (lhs, rhs) = Parser::parse_equation("x^2 + 1 = 2*x").unwrap();
+        assert_eq!(lhs, parse("x^2 + 1").unwrap());
+        assert_eq!(rhs, parse("2*x").unwrap());
+        let mut ctx = EvalContext::new();
+        ctx.set_var("x", 3.0);
+        assert_eq!(eval_with_context(&lhs, &ctx), Ok(10.0));
+        assert_eq!(eval_with_context(&rhs, &ctx), Ok(6.0));
+        let (lhs, rhs) = Parser::parse_equation("let y = 2 in y*y = 4").unwrap();
+        assert_eq!((eval(lhs), eval(rhs)), (Ok(4.0), Ok(4.0)));
+    }
+
+    #[test]
+    fn test_parse_equation_errors() {
+        for expr in ["x + 1", "(x = 1) + 2"] {
+            assert!(matches!(Parser::parse_equation(expr), Err(ParseError::NotAnEquation { .. })));
+        }
+        let error = Parser::parse_equation("x = 1 = y").unwrap_err();
+        assert!(matches!(error, ParseError::AmbiguousEquation { .. }));
+        assert_eq!(error.source_expression(), Some("x = 1 = y"));
+        assert!(matches!(Parser::parse_equation(" = 2"), Err(ParseError::EmptyExpression { .. })));
+        assert!(Parser::parse_equation("1 = 2 +").unwrap_err().to_string().contains("(in ` 2 +`)"));
+    }
+
+    #[test]
+    fn test_modulo_and_remainder() {
+        let value = |expr: &str| eval(parse(expr).unwrap());
+        assert_eq!(value("(-7) mod 3"), Ok(2.0));
+        assert_eq!(value("(-7) % 3"), Ok(2.0));
+        assert_eq!(value("(-7) rem 3"), Ok(-1.0));
+        assert_eq!(value("7 mod -3"), Ok(1.0));
+        assert_eq!(value("rem(-7, 3) + mod(-7, 3)"), Ok(1.0));
+        assert_eq!(value("2 + 10 % 4 * 3"), Ok(8.0));
+        assert_eq!(value("let mod = 5 in mod mod 3"), Ok(2.0));
+        assert_eq!(eval(Parser::parse_prefix("% - 0 7 3").unwrap()), Ok(2.0));
+    }
+
+    #[test]
+    fn test_modulo_by_zero() {
+        for expr in ["5 mod 0", "5 % 0", "5 rem 0", "rem(5, 0)"] {
+            let node = parse(expr).unwrap();
+            assert_eq!(eval(node), Err(EvalError::DivisionByZero), "{}", expr);
+        }
+        let node = parse("mod(5)").unwrap();
+        assert!(matches!(eval(node), Err(EvalError::WrongArgumentCount { expected: 2, found: 1, .. })));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let node = Parser::parse_prefix("+ 2 * 3 4").unwrap();
+        assert_eq!(node, parse("2 + 3 * 4").unwrap());
+        assert_eq!(eval(node), Ok(14.0));
+        assert_eq!(eval(Parser::parse_prefix("^ 2 + 1 2").unwrap()), Ok(8.0));
+        assert_eq!(eval(Parser::parse_prefix("- 10 / 6 3").unwrap()), Ok(8.0));
+        assert_eq!(Parser::parse_prefix("& x ! y").unwrap(), parse("x & !y").unwrap());
+    }
+
+    #[test]
+    fn test_parse_prefix_errors() {
+        for expr in ["+ 2", "* 3 4 5", "+ ( 1 2"] {
+            let error = Parser::parse_prefix(expr).unwrap_err();
+            assert!(matches!(error, ParseError::UnableToParse(..)), "{}", expr);
+            assert_eq!(error.source_expression(), Some(expr));
+        }
+        assert!(matches!(Parser::parse_prefix("  "), Err(ParseError::EmptyExpression { .. })));
+        let deep = format!("{}1", "! ".repeat(1000));
+        assert!(matches!(Parser::parse_prefix(&deep), Err(ParseError::ExpressionTooDeep { .. })));
+    }
+
+    #[test]
+    fn test_parse_spanned() {
+        let spanned = Parser::new("2+3*4").unwrap().parse_spanned().unwrap();
+        assert_eq!(spanned.node, parse("2+3*4").unwrap());
+        assert_eq!(spanned.span, Span { start: 0, end: 5 });
+        let multiply = &spanned.children[1];
+        assert!(matches!(multiply.node, Multiply(..)));
+        assert_eq!(multiply.span, Span { start: 2, end: 5 });
+        assert_eq!(multiply.text(), "3*4");
+        assert_eq!(spanned.children[0].text(), "2");
+    }
+
+    #[test]
+    fn test_parse_spanned_parens_and_calls() {
+        let spanned = Parser::new(" (1 + x) * -max(2, y) ").unwrap().parse_spanned().unwrap();
+        assert_eq!(spanned.text(), "(1 + x) * -max(2, y)");
+        assert_eq!(spanned.children[0].text(), "(1 + x)");
+        assert_eq!(spanned.children[0].children[1].text(), "x");
+        let negative = &spanned.children[1];
+        assert_eq!(negative.text(), "-max(2, y)");
+        assert_eq!(negative.children[0].text(), "max(2, y)");
+        let args: Vec<&str> = negative.children[0].children.iter().map(|c| c.text()).collect();
+        assert_eq!(args, ["2", "y"]);
+        let spanned = Parser::new("let a = 1 in a+2").unwrap().parse_spanned().unwrap();
+        assert_eq!(spanned.span, Span { start: 0, end: 16 });
+        assert_eq!(spanned.children[1].text(), "a+2");
+    }
+
+    #[test]
+    fn test_parse_logical_not() {
+        let node = |expr: &str| parse(expr).unwrap();
+        let a = || Box::new(Node::Variable("a".into()));
+        assert_eq!(node("!a"), Node::Not(a()));
+        assert_eq!(node("!a&b"), Node::And(Box::new(Node::Not(a())), Box::new(Node::Variable("b".into()))));
+        assert_eq!(node("!!a"), Node::Not(Box::new(Node::Not(a()))));
+        assert!(matches!(node("!(a&b)"), Node::Not(inner) if matches!(*inner, Node::And(..))));
+    }
+
+    #[test]
+    fn test_parse_function_calls() {
+        let node = |expr: &str| parse(expr).unwrap();
+        assert_eq!(node("sin(x)"), Node::Function("sin".into(), vec![Node::Variable("x".into())]));
+        assert_eq!(node("max(1, 2+3)"), Node::Function("max".into(), vec![Number(1.0), node("2+3")]));
+        assert_eq!(node("f()"), Node::Function("f".into(), vec![]));
+        assert!(matches!(node("2*sin(x)^2"), Multiply(_, b) if matches!(*b, Caret(..))));
+        for expr in ["max(1,", "max(1 2)", "max(,1)"] {
+            assert!(parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parse_let_errors() {
+        for expr in ["let x 2 in x", "let x = 2 x", "let x = 2 in", "let x = in x"] {
+            assert!(parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_trailing_tokens() {
+        for expr in ["1 2", "(1+2))", "x y"] {
+            let error = parse(expr).unwrap_err();
+            assert!(matches!(error, ParseError::UnableToParse(..)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_unexpected_comma() {
+        for (expr, at) in [("1,2", 1), ("(1,2)", 2), ("2*(3, 4)+1", 4), ("1+2 ,", 4)] {
+            let error = parse(expr).unwrap_err();
+            assert!(matches!(&error, ParseError::UnexpectedComma { at: a, .. } if *a == at), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_comma_still_separates_lists() {
+        let items = Parser::new("1, (2+3)").unwrap().parse_list(Token::Comma).unwrap();
+        assert_eq!(items.len(), 2);
+        let error = Parser::new("1,(2,3)").unwrap().parse_list(Token::Comma).unwrap_err();
+        assert!(matches!(error, ParseError::UnexpectedComma { at: 4, .. }));
+    }
+
+    #[test]
+    fn test_empty_expression() {
+        for expr in ["", " ", "\t\n  "] {
+            assert!(matches!(Parser::new(expr), Err(ParseError::EmptyExpression { .. })));
+        }
+        assert!(Parser::new(" 7 ").is_ok());
+    }
+
+    #[test]
+    fn test_parse_error_source_expression() {
+        let error = parse("2*/3").unwrap_err();
+        assert_eq!(error.source_expression(), Some("2*/3"));
+        assert!(matches!(error, ParseError::UnableToParse(..)));
+        assert_eq!(error.to_string(), "Error in evaluating Unexpected token (in `2*/3`)");
+        let error = Parser::new("$").err().unwrap();
+        assert_eq!(error.source_expression(), Some("$"));
+        assert_eq!(ParseError::Timeout { source: None }.source_expression(), None);
+    }
+
+    #[test]
+    fn test_with_expression_replaces_earlier_expression() {
+        let error = ParseError::Timeout { source: None }.with_expression("a").with_expression("b");
+        assert!(matches!(&error, ParseError::Timeout { source: Some(expr) } if expr == "b"));
+        assert_eq!(error.to_string(), "Error in evaluating expression, timed out (in `b`)");
+        assert_eq!(error.without_expression().source_expression(), None);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        use std::sync::{Arc, Mutex};
+        use tracing::span::{Attributes, Id};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::Layer;
+
+        // Records the names of opened spans and counts closed ones
+        #[derive(Clone, Default)]
+        struct SpanRecorder {
+            opened: Arc<Mutex<Vec<&'static str>>>,
+            closed: Arc<Mutex<usize>>,
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for SpanRecorder {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+                self.opened.lock().unwrap().push(attrs.metadata().name());
+            }
+
+            fn on_close(&self, _id: Id, _ctx: Context<'_, S>) {
+                *self.closed.lock().unwrap() += 1;
+            }
+        }
+
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let ast = Parser::new("2+3*4").unwrap().parse().unwrap();
+            assert_eq!(crate::parsemath::ast::eval(ast).unwrap(), 14.0);
+        });
+
+        // One generate_ast span for the whole expression and one per operand of `+` and `*`
+        let opened = recorder.opened.lock().unwrap().clone();
+        assert_eq!(
+            opened,
+            vec!["Parser::new", "Parser::parse", "generate_ast", "generate_ast", "generate_ast", "eval"]
+        );
+        assert_eq!(*recorder.closed.lock().unwrap(), opened.len());
     }
 }
\ No newline at end of file
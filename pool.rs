@@ -0,0 +1,177 @@
+/// This tokenizes a set of expressions once up front, so that evaluating them many
+/// times with different variable bindings skips the tokenizer on every pass.
+// Internal modules
+use super::ast::{self, EvalError};
+use super::context::EvalContext;
+use super::parser::{ParseConfig, ParseError, Parser};
+use super::token::Token;
+use super::tokenizer::Tokenizer;
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenPool {
+    // Tokens of each expression including whitespace, so spacing means what it does in the
+    // text, ending with Token::EOF
+    tokens: Vec<Vec<Token>>,
+    config: ParseConfig,
+}
+
+impl TokenPool {
+    // Tokenize every expression of `exprs`, failing on the first invalid character
+    pub fn new(exprs: &[&str]) -> Result<Self, ParseError> {
+        TokenPool::new_with_config(exprs, ParseConfig::default())
+    }
+
+    // Tokenize every expression of `exprs` to be parsed with custom options, failing on the
+    // first invalid character or if `config.check()` rejects the options
+    pub fn new_with_config(exprs: &[&str], config: ParseConfig) -> Result<Self, ParseError> {
+        config.check()?;
+        let tokens = exprs
+            .iter()
+            .map(|expr| {
+                let lexer = Tokenizer::new_with_decimal_separator(expr, config.decimal_separator);
+                collect_tokens(expr, lexer.with_whitespace())
+            })
+            .collect::<Result<Vec<_>, ParseError>>()?;
+        Ok(TokenPool { tokens, config })
+    }
+
+    // Number of expressions in the pool
+    #[must_use]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // Parse and evaluate every expression with the variables of `ctx`, in pool order.
+    // A syntax error is reported as EvalError::InvalidExpression for that expression.
+    #[must_use]
+    pub fn evaluate_all(&self, ctx: &EvalContext) -> Vec<Result<f64, EvalError>> {
+        self.tokens
+            .iter()
+            .map(|tokens| evaluate(Parser::from_tokens_with_config(tokens, self.config.clone()), ctx))
+            .collect()
+    }
+}
+
+// Parse and evaluate every expression of `exprs` with the variables of `ctx`, tokenizing each
+// one again. Results are in the same order, with syntax errors as EvalError::InvalidExpression.
+#[must_use]
+pub fn evaluate_many(exprs: &[&str], ctx: &EvalContext) -> Vec<Result<f64, EvalError>> {
+    exprs.iter().map(|expr| evaluate(Parser::new(expr), ctx)).collect()
+}
+
+// Tokens of `expr` up to and including EOF
+pub(crate) fn tokenize(expr: &str) -> Result<Vec<Token>, ParseError> {
+    collect_tokens(expr, Tokenizer::new(expr))
+}
+
+// The tokens `lexer` reads from `expr` up to and including EOF
+fn collect_tokens(expr: &str, mut lexer: impl Iterator<Item = Token>) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    loop {
+        match lexer.next() {
+            Some(Token::EOF) => break,
+            Some(token) => tokens.push(token),
+            None => {
+                return Err(ParseError::InvalidOperator("Invalid character".into(), Some(expr.to_string())))
+            }
+        }
+    }
+    tokens.push(Token::EOF);
+    Ok(tokens)
+}
+
+fn evaluate(parser: Result<Parser, ParseError>, ctx: &EvalContext) -> Result<f64, EvalError> {
+    let node = parser
+        .and_then(|mut parser| parser.parse())
+        .map_err(|e| EvalError::InvalidExpression(e.to_string()))?;
+    ast::eval_with_context(&node, ctx)
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_all_matches_evaluate_many() {
+        let exprs = ["2+3*4", "x^2 - 1", "(x+1)(x-1)", "let y = x*2 in y/4", "max(x, 3)!", "7 mod 3"];
+        let pool = TokenPool::new(&exprs).unwrap();
+        assert_eq!(pool.len(), exprs.len());
+        let mut ctx = EvalContext::new();
+        for x in [-2.0, 0.0, 1.5, 4.0] {
+            ctx.set_var("x", x);
+            assert_eq!(pool.evaluate_all(&ctx), evaluate_many(&exprs, &ctx));
+        }
+        ctx.set_var("x", 3.0);
+        assert_eq!(pool.evaluate_all(&ctx)[1], Ok(8.0));
+    }
+
+    #[test]
+    fn test_evaluate_all_errors() {
+        let pool = TokenPool::new(&["1/0", "2+", "(1+2", "z"]).unwrap();
+        let results = pool.evaluate_all(&EvalContext::new());
+        assert_eq!(results[0], Err(EvalError::DivisionByZero));
+        assert!(matches!(results[1], Err(EvalError::InvalidExpression(_))));
+        assert!(matches!(results[2], Err(EvalError::InvalidExpression(_))));
+        assert_eq!(results[3], Err(EvalError::UnknownVariable("z".into())));
+        assert!(TokenPool::new(&["1+1", "2$3"]).is_err());
+        assert!(TokenPool::new(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_all_spacing_and_config() {
+        let exprs = ["(2+3)4", "(2+3) 4", "2 (3)", " 1 + 2 "];
+        let pool = TokenPool::new(&exprs).unwrap();
+        let ctx = EvalContext::new();
+        let values = |results: Vec<Result<f64, EvalError>>| -> Vec<Option<f64>> {
+            results.into_iter().map(Result::ok).collect()
+        };
+        assert_eq!(values(pool.evaluate_all(&ctx)), [Some(20.0), None, Some(6.0), Some(3.0)]);
+        assert_eq!(values(evaluate_many(&exprs, &ctx)), values(pool.evaluate_all(&ctx)));
+        assert!(matches!(pool.evaluate_all(&ctx)[1], Err(EvalError::InvalidExpression(_))));
+
+        let config = ParseConfig { decimal_separator: ',', max_depth: 2, ..ParseConfig::default() };
+        let pool = TokenPool::new_with_config(&["1,5 * 2", "max(1,5; 2)", "((1))"], config).unwrap();
+        let results = pool.evaluate_all(&ctx);
+        assert_eq!(results[..2], [Ok(3.0), Ok(2.0)]);
+        assert!(matches!(results[2], Err(EvalError::InvalidExpression(_))));
+    }
+
+    // Timing sensitive, run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_token_pool_speedup() {
+        use std::time::Instant;
+        let exprs = ["x*x + 2*x - 1"];
+        let pool = TokenPool::new(&exprs).unwrap();
+        let mut ctx = EvalContext::new();
+
+        let start = Instant::now();
+        for i in 0..10_000 {
+            ctx.set_var("x", f64::from(i));
+            assert!(evaluate_many(&exprs, &ctx)[0].is_ok());
+        }
+        let retokenized_time = start.elapsed();
+
+        let start = Instant::now();
+        for i in 0..10_000 {
+            ctx.set_var("x", f64::from(i));
+            assert!(pool.evaluate_all(&ctx)[0].is_ok());
+        }
+        let pooled_time = start.elapsed();
+
+        assert!(
+            retokenized_time.as_secs_f64() >= 1.1 * pooled_time.as_secs_f64(),
+            "re-tokenized {:?}, pooled {:?}",
+            retokenized_time,
+            pooled_time
+        );
+    }
+}
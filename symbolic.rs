@@ -0,0 +1,284 @@
+/// This evaluates an expression exactly where it can: rational arithmetic gives a
+/// `num::Rational64` and square roots of rationals that are not perfect squares are kept
+/// as a simplified surd such as `3*sqrt(2)/2`. Anything else is evaluated as f64.
+// Standard lib
+use std::fmt;
+
+// External crates
+use num::integer::Roots;
+use num::traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, Zero};
+use num::Rational64;
+
+// Internal modules
+use super::ast::{self, Node};
+use super::parser::{ParseError, Parser};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SymbolicResult {
+    Rational(Rational64),
+    // Exact value that is not rational, written as an expression the parser accepts
+    Irrational(String),
+    Float(f64),
+}
+
+impl fmt::Display for SymbolicResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolicResult::Rational(r) => write!(f, "{}", r),
+            SymbolicResult::Irrational(text) => write!(f, "{}", text),
+            SymbolicResult::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// Parse and evaluate `expr`, exactly when every step stays within rationals and square roots
+// of them, e.g. `1/3 + 1/6` is Rational(1/2) and `sqrt(8)` is Irrational("2*sqrt(2)").
+// Steps that leave them, such as `sin(1)` or `sqrt(2) + 1`, make the whole result a Float.
+pub fn evaluate_symbolic(expr: &str) -> Result<SymbolicResult, ParseError> {
+    let node = Parser::new(expr)?.parse()?;
+    Ok(match exact(&node, &mut Vec::new()) {
+        Some(Exact::Rational(r)) => SymbolicResult::Rational(r),
+        Some(Exact::Surd(coefficient, radicand)) => {
+            SymbolicResult::Irrational(surd_text(coefficient, radicand))
+        }
+        None => {
+            let value = ast::eval(node).map_err(|e| ParseError::from(e).with_expression(expr))?;
+            SymbolicResult::Float(value)
+        }
+    })
+}
+
+// An exact value: a rational, or a rational coefficient times the square root of a
+// square-free integer greater than 1
+#[derive(Debug, Clone, Copy)]
+enum Exact {
+    Rational(Rational64),
+    Surd(Rational64, i64),
+}
+
+// Largest integer exponent evaluated exactly, larger ones fall back to f64
+const MAX_EXACT_EXPONENT: u32 = 1 << 16;
+
+// Largest divisor tried when taking square factors out of a radicand
+const MAX_TRIAL_DIVISOR: i64 = 1 << 16;
+
+// Square root of `r` as an Exact, None if `r` is negative or factoring it is out of reach
+fn sqrt(r: Rational64) -> Option<Exact> {
+    if r < Rational64::zero() {
+        return None;
+    }
+    // sqrt(n/d) = sqrt(n*d)/d, so only an integer needs its square factors taken out
+    let mut rest = r.numer().checked_mul(r.denom())?;
+    let mut root = 1i64;
+    let mut divisor = 2i64;
+    while divisor <= MAX_TRIAL_DIVISOR && divisor * divisor <= rest {
+        while rest % (divisor * divisor) == 0 {
+            rest /= divisor * divisor;
+            root = root.checked_mul(divisor)?;
+        }
+        divisor += 1;
+    }
+    let whole = rest.sqrt();
+    if whole * whole == rest {
+        (root, rest) = (root.checked_mul(whole)?, 1);
+    } else if rest > MAX_TRIAL_DIVISOR * MAX_TRIAL_DIVISOR {
+        // A square of a prime bigger than every divisor tried may still divide `rest`
+        return None;
+    }
+    let coefficient = Rational64::new(root, *r.denom());
+    Some(surd(coefficient, rest))
+}
+
+// coefficient * sqrt(radicand), collapsed to a rational when the root goes away
+fn surd(coefficient: Rational64, radicand: i64) -> Exact {
+    if radicand == 1 || coefficient.is_zero() {
+        Exact::Rational(coefficient)
+    } else {
+        Exact::Surd(coefficient, radicand)
+    }
+}
+
+fn add(a: Exact, b: Exact, subtract: bool) -> Option<Exact> {
+    let combine = |x: &Rational64, y: &Rational64| {
+        if subtract {
+            x.checked_sub(y)
+        } else {
+            x.checked_add(y)
+        }
+    };
+    match (a, b) {
+        (Exact::Rational(x), Exact::Rational(y)) => Some(Exact::Rational(combine(&x, &y)?)),
+        (Exact::Surd(x, f), Exact::Surd(y, g)) if f == g => Some(surd(combine(&x, &y)?, f)),
+        (other, Exact::Rational(y)) if y.is_zero() => Some(other),
+        (Exact::Rational(x), other) if x.is_zero() && subtract => {
+            multiply(other, Exact::Rational(Rational64::from_integer(-1)))
+        }
+        (Exact::Rational(x), other) if x.is_zero() => Some(other),
+        _ => None,
+    }
+}
+
+fn multiply(a: Exact, b: Exact) -> Option<Exact> {
+    match (a, b) {
+        (Exact::Rational(x), Exact::Rational(y)) => Some(Exact::Rational(x.checked_mul(&y)?)),
+        (Exact::Rational(x), Exact::Surd(y, f)) | (Exact::Surd(y, f), Exact::Rational(x)) => {
+            Some(surd(x.checked_mul(&y)?, f))
+        }
+        (Exact::Surd(x, f), Exact::Surd(y, g)) => {
+            let coefficient = x.checked_mul(&y)?;
+            multiply(Exact::Rational(coefficient), sqrt(Rational64::from_integer(f.checked_mul(g)?))?)
+        }
+    }
+}
+
+// 1/a, with the root moved into the numerator: 1/(c*sqrt(f)) = sqrt(f)/(c*f)
+fn reciprocal(a: Exact) -> Option<Exact> {
+    let one = Rational64::from_integer(1);
+    match a {
+        Exact::Rational(x) => Some(Exact::Rational(one.checked_div(&x)?)),
+        Exact::Surd(x, f) => {
+            let scaled = x.checked_mul(&Rational64::from_integer(f))?;
+            Some(surd(one.checked_div(&scaled)?, f))
+        }
+    }
+}
+
+fn power(base: Exact, exponent: Exact) -> Option<Exact> {
+    let exponent = match exponent {
+        Exact::Rational(e) => e,
+        Exact::Surd(..) => return None,
+    };
+    match base {
+        Exact::Rational(b) if exponent == Rational64::new(1, 2) => sqrt(b),
+        _ if exponent.is_integer() => {
+            let mut times = u32::try_from(exponent.numer().unsigned_abs()).ok()?;
+            if times > MAX_EXACT_EXPONENT {
+                return None;
+            }
+            // Exponentiation by squaring, every step checked for overflow
+            let (mut square, mut result) = (base, Exact::Rational(Rational64::from_integer(1)));
+            while times > 0 {
+                if times & 1 == 1 {
+                    result = multiply(result, square)?;
+                }
+                times >>= 1;
+                if times > 0 {
+                    square = multiply(square, square)?;
+                }
+            }
+            if exponent < Rational64::zero() {
+                reciprocal(result)
+            } else {
+                Some(result)
+            }
+        }
+        _ => None,
+    }
+}
+
+// Exact value of `node`, None when a step leaves the rationals and their square roots.
+// `scope` holds the let bindings in force, innermost last.
+fn exact<'a>(node: &'a Node, scope: &mut Vec<(&'a str, Exact)>) -> Option<Exact> {
+    use self::Node::*;
+    match node {
+        Number(_) => {
+            let (num, den) = ast::eval_rational(node)?;
+            Some(Exact::Rational(Rational64::new(num, den)))
+        }
+        // Irrational, so left to the float path
+        Constant(_) => None,
+        Variable(name) => scope.iter().rev().find(|(bound, _)| bound == name).map(|(_, v)| *v),
+        Negative(a) => multiply(Exact::Rational(Rational64::from_integer(-1)), exact(a, scope)?),
+        Add(a, b) => add(exact(a, scope)?, exact(b, scope)?, false),
+        Subtract(a, b) => add(exact(a, scope)?, exact(b, scope)?, true),
+        Multiply(a, b) => multiply(exact(a, scope)?, exact(b, scope)?),
+        Divide(a, b) => multiply(exact(a, scope)?, reciprocal(exact(b, scope)?)?),
+        Caret(a, b) => power(exact(a, scope)?, exact(b, scope)?),
+        FlatAdd(items) => {
+            let zero = Exact::Rational(Rational64::zero());
+            items.iter().try_fold(zero, |sum, item| add(sum, exact(item, scope)?, false))
+        }
+        FlatMul(items) => {
+            let one = Exact::Rational(Rational64::from_integer(1));
+            items.iter().try_fold(one, |product, item| multiply(product, exact(item, scope)?))
+        }
+        Function(name, args) if name == "sqrt" && args.len() == 1 => match exact(&args[0], scope)? {
+            Exact::Rational(r) => sqrt(r),
+            Exact::Surd(..) => None,
+        },
+        Let(name, value, body) => {
+            let value = exact(value, scope)?;
+            scope.push((name, value));
+            let result = exact(body, scope);
+            scope.pop();
+            result
+        }
+        And(..) | Or(..) | Not(_) | Function(..) => None,
+    }
+}
+
+// Render coefficient * sqrt(radicand) as `sqrt(2)`, `-3*sqrt(2)` or `sqrt(6)/3`
+fn surd_text(coefficient: Rational64, radicand: i64) -> String {
+    let numerator = match *coefficient.numer() {
+        1 => format!("sqrt({})", radicand),
+        -1 => format!("-sqrt({})", radicand),
+        n => format!("{}*sqrt({})", n, radicand),
+    };
+    match *coefficient.denom() {
+        1 => numerator,
+        d => format!("{}/{}", numerator, d),
+    }
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_symbolic_rational() {
+        let result = |expr: &str| evaluate_symbolic(expr).unwrap();
+        assert_eq!(result("1/3 + 1/6"), SymbolicResult::Rational(Rational64::new(1, 2)));
+        assert_eq!(result("sqrt(4)"), SymbolicResult::Rational(Rational64::from_integer(2)));
+        assert_eq!(result("0.1 + 0.2"), SymbolicResult::Rational(Rational64::new(3, 10)));
+        assert_eq!(result("(2/3)^-2"), SymbolicResult::Rational(Rational64::new(9, 4)));
+        assert_eq!(result("sqrt(2) * sqrt(8)"), SymbolicResult::Rational(Rational64::from_integer(4)));
+        assert_eq!(result("let h = 1/2 in h*h"), SymbolicResult::Rational(Rational64::new(1, 4)));
+        assert_eq!(result("sqrt(9/4)"), SymbolicResult::Rational(Rational64::new(3, 2)));
+        assert_eq!(result("(-1)^65535"), SymbolicResult::Rational(Rational64::from_integer(-1)));
+        assert_eq!(result("2^62"), SymbolicResult::Rational(Rational64::from_integer(1 << 62)));
+        assert_eq!(result("sqrt(2)^-3"), SymbolicResult::Irrational("sqrt(2)/4".into()));
+    }
+
+    #[test]
+    fn test_evaluate_symbolic_irrational() {
+        let text = |expr: &str| match evaluate_symbolic(expr).unwrap() {
+            SymbolicResult::Irrational(text) => text,
+            other => panic!("{} gave {:?}", expr, other),
+        };
+        assert_eq!(text("sqrt(2)"), "sqrt(2)");
+        assert_eq!(text("sqrt(8)"), "2*sqrt(2)");
+        assert_eq!(text("-sqrt(12)/4"), "-sqrt(3)/2");
+        assert_eq!(text("1/sqrt(2)"), "sqrt(2)/2");
+        assert_eq!(text("sqrt(2) + sqrt(2)"), "2*sqrt(2)");
+        assert_eq!(text("sqrt(2/3)"), "sqrt(6)/3");
+        assert_eq!(text("3^(1/2)"), "sqrt(3)");
+        assert_eq!(text("0 - sqrt(5)"), "-sqrt(5)");
+        for expr in ["sqrt(8)", "-sqrt(12)/4", "sqrt(2/3)"] {
+            let value = ast::eval(Parser::new(&text(expr)).unwrap().parse().unwrap()).unwrap();
+            assert!((value - ast::eval(Parser::new(expr).unwrap().parse().unwrap()).unwrap()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_symbolic_float_fallback() {
+        assert_eq!(evaluate_symbolic("sqrt(2) + 1").unwrap(), SymbolicResult::Float(2f64.sqrt() + 1.0));
+        assert_eq!(evaluate_symbolic("sin(0)").unwrap(), SymbolicResult::Float(0.0));
+        // Past MAX_EXACT_EXPONENT or i64, evaluated as f64 at once
+        assert_eq!(evaluate_symbolic("1^4000000000").unwrap(), SymbolicResult::Float(1.0));
+        assert_eq!(evaluate_symbolic("2^64").unwrap(), SymbolicResult::Float(2f64.powi(64)));
+        assert!(matches!(evaluate_symbolic("1/0"), Err(ParseError::EvalFailed(..))));
+        assert!(evaluate_symbolic("x + 1").is_err());
+        assert!(evaluate_symbolic("2 +").is_err());
+    }
+}
@@ -1,8 +1,10 @@
 /// This contains the enum for the list of Tokens and handles Operator precedence rules.
+// Standard lib
+use std::fmt;
 
 // List of valid tokens that can be constructed from an arithmetic expression by the Tokenizer.
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum Token {
     And,        // &
     Or,         // |
@@ -10,38 +12,252 @@ pub enum Token {
     Subtract,   // -
     Multiply,   // *
     Divide,     // /
+    Modulo,     // % or mod, Euclidean remainder
+    Remainder,  // rem, truncating remainder
     Caret,      // ^
     LeftParen,  // (
     RightParen, // )
+    Comma,      // ,
+    Assign,     // =
+    Bang,       // !
     Num(f64),   // 12.34
-    EOF,        // End of input
+    Ident(String), // x
+    Whitespace(String), // Run of spaces, tabs or newlines, only from a WhitespaceTokenizer
+    #[default]
+    EOF,        // End of input, also the default token
+}
+
+// Byte range `start..end` of a piece of source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 // Order of operators as per operator precedence rules (low to high)
 
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Default)]
 /// Defines all the `OperPrec` levels, from lowest to highest.
 pub enum OperPrec {
+    #[default]
     DefaultZero, // Default level (e.g., numbers)
     Bitwise,     // & and |
     AddSub,      // + and -
-    MulDiv,      // * and /
+    MulDiv,      // *, /, % (mod) and rem
     Exponent,    // ^
-    Negative,    // Unary minus (-x)
+    Unary,       // Prefix operators such as unary minus (-x), tighter than every binary operator
+    PostfixUnary, // Postfix operators such as factorial (3!), tighter than prefix operators
+}
+
+impl OperPrec {
+    // Former name of the Unary level, kept so existing callers keep compiling
+    #[allow(non_upper_case_globals)]
+    pub const Negative: OperPrec = OperPrec::Unary;
+
+    // True if this level binds strictly tighter than `low` and strictly looser than `high`
+    #[must_use]
+    #[inline]
+    pub fn between(&self, low: &OperPrec, high: &OperPrec) -> bool {
+        low < self && self < high
+    }
+
+    // Precedence of `t`, the same as `t.get_oper_prec()`
+    #[must_use]
+    #[inline]
+    pub fn for_token(t: &Token) -> OperPrec {
+        t.get_oper_prec()
+    }
+
+    // Precedence of the binary operator written `op`, the same as `precedence_of(op)`
+    #[must_use]
+    #[inline]
+    pub fn of_string(op: &str) -> Option<OperPrec> {
+        precedence_of(op)
+    }
+}
+
+// The variant name with its position from the lowest level, e.g. `MulDiv (level 3)`
+impl fmt::Display for OperPrec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} (level {})", self, *self as u8)
+    }
+}
+
+// Every binary operator with its precedence, as `Token::get_oper_prec` gives it.
+// Prefix `-` and `!` and postfix `!` bind tighter, see OperPrec::Unary and PostfixUnary.
+pub const PRECEDENCE_TABLE: &[(&str, OperPrec)] = &[
+    ("&", OperPrec::Bitwise),
+    ("|", OperPrec::Bitwise),
+    ("+", OperPrec::AddSub),
+    ("-", OperPrec::AddSub),
+    ("*", OperPrec::MulDiv),
+    ("/", OperPrec::MulDiv),
+    ("%", OperPrec::MulDiv),
+    ("mod", OperPrec::MulDiv),
+    ("rem", OperPrec::MulDiv),
+    ("^", OperPrec::Exponent),
+];
+
+// Precedence of the binary operator written `op`, looked up in PRECEDENCE_TABLE
+#[must_use]
+pub fn precedence_of(op: &str) -> Option<OperPrec> {
+    PRECEDENCE_TABLE.iter().find(|(symbol, _)| *symbol == op).map(|(_, prec)| *prec)
 }
 
 // This contains methods to retrieve operator precedence for a given arithmetic operator
 
 impl Token {
+    #[must_use]
+    #[inline]
     pub fn get_oper_prec(&self) -> OperPrec {
         use self::OperPrec::*;
         use self::Token::*;
         match *self {
             And | Or => Bitwise, // Bitwise operations have the lowest precedence
             Add | Subtract => AddSub,
-            Multiply | Divide => MulDiv,
+            Multiply | Divide | Modulo | Remainder => MulDiv,
             Caret => Exponent,
             _ => DefaultZero, // Default case (numbers, EOF)
         }
     }
-}
\ No newline at end of file
+
+    // Token for an operator or punctuation symbol such as `+`, `(` or `mod`, None for
+    // anything else, including numbers and identifiers
+    #[must_use]
+    pub fn from_symbol(symbol: &str) -> Option<Token> {
+        use self::Token::*;
+        Some(match symbol {
+            "&" => And,
+            "|" => Or,
+            "+" => Add,
+            "-" => Subtract,
+            "*" => Multiply,
+            "/" => Divide,
+            "%" | "mod" => Modulo,
+            "rem" => Remainder,
+            "^" => Caret,
+            "(" => LeftParen,
+            ")" => RightParen,
+            "," => Comma,
+            "=" => Assign,
+            "!" => Bang,
+            _ => return None,
+        })
+    }
+
+    // Token written as the single character `c`, None for characters that only appear in
+    // longer tokens such as numbers and names
+    #[must_use]
+    pub fn from_char(c: char) -> Option<Token> {
+        let mut buffer = [0; 4];
+        Token::from_symbol(c.encode_utf8(&mut buffer))
+    }
+
+    // The single character this token is written as, None for tokens that take more
+    #[must_use]
+    pub fn to_char(&self) -> Option<char> {
+        use self::Token::*;
+        match self {
+            And => Some('&'),
+            Or => Some('|'),
+            Add => Some('+'),
+            Subtract => Some('-'),
+            Multiply => Some('*'),
+            Divide => Some('/'),
+            Modulo => Some('%'),
+            Caret => Some('^'),
+            LeftParen => Some('('),
+            RightParen => Some(')'),
+            Comma => Some(','),
+            Assign => Some('='),
+            Bang => Some('!'),
+            Remainder | Num(_) | Ident(_) | Whitespace(_) | EOF => None,
+        }
+    }
+
+    // Text this token is written as: numbers in their shortest form (`2`, not `2.0`), names as
+    // they are and nothing for EOF
+    #[must_use]
+    pub fn display_symbol(&self) -> String {
+        match self {
+            Token::Num(n) => n.to_string(),
+            Token::Ident(text) | Token::Whitespace(text) => text.clone(),
+            Token::Remainder => "rem".to_string(),
+            Token::EOF => String::new(),
+            symbol => symbol.to_char().map(String::from).unwrap_or_default(),
+        }
+    }
+
+    // Precedence of this token when it follows an operand as a postfix operator
+    #[must_use]
+    #[inline]
+    pub fn get_postfix_prec(&self) -> Option<OperPrec> {
+        match *self {
+            Token::Bang => Some(OperPrec::PostfixUnary),
+            _ => None,
+        }
+    }
+
+    // True if both tokens are the same operator, one `arity` counts as taking operands.
+    // Numbers, names, parentheses and other punctuation are never the same operator, even
+    // as two equal tokens.
+    #[must_use]
+    #[inline]
+    pub fn same_operator(&self, other: &Self) -> bool {
+        self.arity().is_some() && self == other
+    }
+
+    // Number of operands this operator takes, None for tokens that are not operators.
+    // `!` takes one both as prefix not and as postfix factorial; `-` is counted as binary,
+    // since the token alone does not tell a negation from a subtraction.
+    #[must_use]
+    #[inline]
+    pub fn arity(&self) -> Option<u8> {
+        use self::Token::*;
+        match *self {
+            And | Or | Add | Subtract | Multiply | Divide | Modulo | Remainder | Caret => Some(2),
+            Bang => Some(1),
+            LeftParen | RightParen | Comma | Assign | Num(_) | Ident(_) | Whitespace(_) | EOF => None,
+        }
+    }
+}
+
+// Rendering of a token sequence back to an expression, e.g. to check tokenizer output
+pub trait TokensExt {
+    // The tokens joined by their display symbols, with a space only where two of them would
+    // otherwise run together (`7 rem 3`). None if an operator lacks an operand, as in `2+*3`,
+    // `(*2)` or `2+`. Parentheses are not checked for balance.
+    fn to_infix_string(&self) -> Option<String>;
+}
+
+impl TokensExt for [Token] {
+    fn to_infix_string(&self) -> Option<String> {
+        use self::Token::*;
+        let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '.' || c == '_');
+        let mut text = String::new();
+        let mut expect_operand = true;
+        for token in self {
+            match token {
+                Num(_) | Ident(_) => expect_operand = false,
+                LeftParen => expect_operand = true,
+                // Prefix negation and not, or postfix factorial
+                Subtract | Bang if expect_operand => {}
+                Bang => {}
+                RightParen | And | Or | Add | Subtract | Multiply | Divide | Modulo | Remainder | Caret
+                | Comma | Assign => {
+                    if expect_operand {
+                        return None;
+                    }
+                    expect_operand = *token != RightParen;
+                }
+                Whitespace(_) | EOF => {}
+            }
+            let symbol = token.display_symbol();
+            if word(text.chars().last()) && word(symbol.chars().next()) {
+                text.push(' ');
+            }
+            text.push_str(&symbol);
+        }
+        (!expect_operand || text.trim().is_empty()).then_some(text)
+    }
+}
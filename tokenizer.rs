@@ -1,24 +1,221 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 use super::token::Token;
 
 pub struct Tokenizer<'a> {
+    source: &'a str,
+    offset: usize,         // Byte offset of the next character of `source`
+    chars_consumed: usize, // Characters of `source` before `offset`
+    token_start: usize, // Byte offset where the token returned last by `next` begins
     expr: Peekable<Chars<'a>>,
+    decimal_separator: char, // `.` unless set with `new_with_decimal_separator`
 }
 
+// Shows how far tokenizing has got rather than the character iterator
+impl<'a> fmt::Debug for Tokenizer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tokenizer")
+            .field("position", &self.offset)
+            .field("remaining", &self.remaining_input())
+            .finish()
+    }
+}
+
+// A Tokenizer that also returns the whitespace between tokens as Token::Whitespace,
+// so that concatenating the text of every token gives back the source
+#[derive(Debug)]
+pub struct WhitespaceTokenizer<'a>(Tokenizer<'a>);
+
 impl<'a> Tokenizer<'a> {
+    // Tokenize `expr` keeping whitespace, see WhitespaceTokenizer
+    #[must_use]
+    pub fn new_with_whitespace(expr: &'a str) -> WhitespaceTokenizer<'a> {
+        Tokenizer::new(expr).with_whitespace()
+    }
+
+    // This tokenizer, also returning whitespace from now on, see WhitespaceTokenizer
+    #[must_use]
+    pub fn with_whitespace(self) -> WhitespaceTokenizer<'a> {
+        WhitespaceTokenizer(self)
+    }
+
+    #[must_use]
     pub fn new(new_expr: &'a str) -> Self {
         Tokenizer {
+            source: new_expr,
+            offset: 0,
+            chars_consumed: 0,
+            token_start: 0,
             expr: new_expr.chars().peekable(),
+            decimal_separator: '.',
+        }
+    }
+
+    // Tokenize `expr` with `separator` as the decimal point of numbers. With `,` the
+    // Token::Comma between function arguments is written `;` instead: `max(1,5;2)` is 2.
+    #[must_use]
+    pub fn new_with_decimal_separator(expr: &'a str, separator: char) -> Self {
+        Tokenizer {
+            decimal_separator: separator,
+            ..Tokenizer::new(expr)
         }
     }
 
+    // The full input being tokenized
+    #[must_use]
+    #[inline]
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    // Byte offset in `source` of the next character to be read
+    #[must_use]
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Number of characters read so far, whitespace included
+    #[must_use]
+    #[inline]
+    pub fn consumed(&self) -> usize {
+        self.chars_consumed
+    }
+
+    // The part of `source` not read yet
+    #[must_use]
+    #[inline]
+    pub fn remaining_input(&self) -> &'a str {
+        &self.source[self.offset..]
+    }
+
+    // Same as `remaining_input`, for handing the rest of the source to a `&str` combinator
+    // such as nom or winnow once the arithmetic tokens have been read
+    #[must_use]
+    #[inline]
+    pub fn as_str_remaining(&self) -> &'a str {
+        self.remaining_input()
+    }
+
+    // The next character to be read, without consuming it. None at the end of input.
+    #[must_use]
+    #[inline]
+    pub fn peek_char(&self) -> Option<char> {
+        self.remaining_input().chars().next()
+    }
+
+    // Owned copy of `remaining_input`, the source from the current offset to the end
+    #[must_use]
+    pub fn to_remaining_string(&self) -> String {
+        self.remaining_input().to_string()
+    }
+
+    // Number of characters not read yet
+    #[must_use]
+    pub fn remaining_chars(&self) -> usize {
+        self.remaining_input().chars().count()
+    }
+
+    // Byte offset where the token returned last by `next` begins, after any whitespace
+    #[must_use]
+    #[inline]
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    // Move back `n` characters so that they are tokenized again, stopping at the start of input.
+    // Lets callers backtrack, e.g. try one interpretation and fall back to another.
+    pub fn rewind(&mut self, n: usize) {
+        let consumed = &self.source[..self.offset];
+        let (chars, back) = consumed
+            .chars()
+            .rev()
+            .take(n)
+            .fold((0, 0), |(chars, bytes), c| (chars + 1, bytes + c.len_utf8()));
+        self.offset -= back;
+        self.chars_consumed -= chars;
+        self.expr = self.source[self.offset..].chars().peekable();
+    }
+
+    // True if `expr` holds nothing but the whitespace the tokenizer skips, so it has no tokens
+    #[must_use]
+    pub fn is_empty_or_whitespace(expr: &str) -> bool {
+        expr.chars().all(char::is_whitespace)
+    }
+
+    // Number of tokens in `expr` without storing them, not counting EOF or the `)` implied by
+    // each `(`, so `(1+2)*(3+4)` counts 9. An invalid character counts as one token, so this
+    // stays an upper bound for rate limiting.
+    #[must_use]
+    pub fn count_tokens(expr: &str) -> usize {
+        let mut tokenizer = Tokenizer::new(expr);
+        let mut count = 0;
+        loop {
+            match tokenizer.next() {
+                Some(Token::EOF) => return count,
+                Some(Token::RightParen) => {}
+                _ => count += 1,
+            }
+        }
+    }
+
+    // Every token of `expr` up to and including EOF, carrying on past invalid input, with the
+    // byte offset and first character of each piece that could not be tokenized.
+    // `2+$3` gives [Num(2), Add, Num(3), EOF] and [(2, '$')].
+    #[must_use]
+    pub fn tokenize_with_errors(expr: &str) -> (Vec<Token>, Vec<(usize, char)>) {
+        let mut tokenizer = Tokenizer::new(expr);
+        let (mut tokens, mut errors) = (Vec::new(), Vec::new());
+        loop {
+            match tokenizer.next() {
+                Some(Token::EOF) => break,
+                Some(token) => tokens.push(token),
+                None => {
+                    let start = tokenizer.token_start;
+                    errors.extend(expr[start..].chars().next().map(|c| (start, c)));
+                }
+            }
+        }
+        tokens.push(Token::EOF);
+        (tokens, errors)
+    }
+
+    // Parse a number literal, as the tokenizer reads them, from the start of `s`.
+    // Returns the value and the number of bytes it takes up, None if `s` does not start with one.
+    #[must_use]
+    pub fn try_parse_number(s: &str) -> Option<(f64, usize)> {
+        let mut tokenizer = Tokenizer::new(s);
+        let first_digit = tokenizer.expr.peek().copied().filter(char::is_ascii_digit)?;
+        tokenizer.advance();
+        match tokenizer.parse_number(first_digit)? {
+            Token::Num(value) => Some((value, tokenizer.offset)),
+            _ => None,
+        }
+    }
+
+    // Consume one character, keeping track of the byte offset
+    fn advance(&mut self) -> Option<char> {
+        let c = self.expr.next()?;
+        self.offset += c.len_utf8();
+        self.chars_consumed += 1;
+        Some(c)
+    }
+
     fn parse_number(&mut self, first_digit: char) -> Option<Token> {
+        if first_digit == '0' && matches!(self.expr.peek(), Some('x') | Some('X')) {
+            self.advance();
+            return self.parse_hex_number();
+        }
         let mut num_str = first_digit.to_string();
 
         while let Some(&next) = self.expr.peek() {
-            if next.is_ascii_digit() || next == '.' {
-                num_str.push(self.expr.next().unwrap());
+            if next.is_ascii_digit() {
+                num_str.push(next);
+                self.advance();
+            } else if next == self.decimal_separator {
+                num_str.push('.');
+                self.advance();
             } else {
                 break;
             }
@@ -29,6 +226,35 @@ impl<'a> Tokenizer<'a> {
             Err(_) => None,
         }
     }
+
+    // Parse a variable name made of letters, digits and underscores
+    fn parse_identifier(&mut self) -> Token {
+        let mut name = String::new();
+        while let Some(&c) = self.expr.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                name.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        Token::Ident(name)
+    }
+
+    // Parse the digits of a hex literal such as 0xFF, after the 0x prefix
+    fn parse_hex_number(&mut self) -> Option<Token> {
+        let mut value = 0.0;
+        let mut digits = 0;
+        while let Some(digit) = self.expr.peek().and_then(|c| c.to_digit(16)) {
+            self.advance();
+            value = value * 16.0 + digit as f64;
+            digits += 1;
+        }
+        if digits == 0 {
+            return None;
+        }
+        Some(Token::Num(value))
+    }
 }
 
 impl<'a> Iterator for Tokenizer<'a> {
@@ -36,61 +262,113 @@ impl<'a> Iterator for Tokenizer<'a> {
 
     fn next(&mut self) -> Option<Token> {
         while let Some(&c) = self.expr.peek() {
+            self.token_start = self.offset;
             match c {
                 '0'..='9' => {
-                    self.expr.next();
+                    self.advance();
                     return self.parse_number(c);
                 }
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    return Some(self.parse_identifier());
+                }
                 '+' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Add);
                 }
                 '-' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Subtract);
                 }
                 '*' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Multiply);
                 }
                 '/' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Divide);
                 }
                 '^' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Caret);
                 }
+                '%' => {
+                    self.advance();
+                    return Some(Token::Modulo);
+                }
                 '&' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::And);
                 }
                 '|' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::Or);
                 }
                 '(' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::LeftParen);
                 }
                 ')' => {
-                    self.expr.next();
+                    self.advance();
                     return Some(Token::RightParen);
                 }
-                ' ' | '\t' | '\n' => {
-                    self.expr.next();
+                ',' if self.decimal_separator != ',' => {
+                    self.advance();
+                    return Some(Token::Comma);
+                }
+                ';' if self.decimal_separator == ',' => {
+                    self.advance();
+                    return Some(Token::Comma);
+                }
+                '=' => {
+                    self.advance();
+                    return Some(Token::Assign);
+                }
+                '!' => {
+                    self.advance();
+                    return Some(Token::Bang);
+                }
+                c if c.is_whitespace() => {
+                    self.advance();
                 }
                 _ => {
-                    self.expr.next();
+                    self.advance();
                     return None;
                 }
             }
         }
+        self.token_start = self.offset;
         Some(Token::EOF)
     }
 }
 
+impl<'a> WhitespaceTokenizer<'a> {
+    // Byte offset where the token returned last by `next` begins
+    #[must_use]
+    #[inline]
+    pub fn token_start(&self) -> usize {
+        self.0.token_start
+    }
+}
+
+impl<'a> Iterator for WhitespaceTokenizer<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let tokenizer = &mut self.0;
+        let start = tokenizer.offset;
+        while tokenizer.expr.peek().is_some_and(|c| c.is_whitespace()) {
+            tokenizer.advance();
+        }
+        if tokenizer.offset > start {
+            tokenizer.token_start = start;
+            return Some(Token::Whitespace(tokenizer.source[start..tokenizer.offset].to_string()));
+        }
+        tokenizer.next()
+    }
+}
+
 #[cfg(test)]
+#[deny(unused_must_use)]
 mod tests {
     use super::*;
 
@@ -129,6 +407,50 @@ mod tests {
         assert_eq!(tokenizer.next().unwrap(), Token::EOF);
     }
 
+    #[test]
+    fn test_tokenize_hex_number() {
+        let mut tokenizer = Tokenizer::new("0xFF&0x0f");
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(255.0));
+        assert_eq!(tokenizer.next().unwrap(), Token::And);
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(15.0));
+        assert!(Tokenizer::new("0x").next().is_none());
+    }
+
+    #[test]
+    fn test_tokenize_identifiers() {
+        let mut tokenizer = Tokenizer::new("rate_2*x");
+        assert_eq!(tokenizer.next().unwrap(), Token::Ident("rate_2".into()));
+        assert_eq!(tokenizer.next().unwrap(), Token::Multiply);
+        assert_eq!(tokenizer.next().unwrap(), Token::Ident("x".into()));
+        assert_eq!(tokenizer.next().unwrap(), Token::EOF);
+    }
+
+    #[test]
+    fn test_rewind_retokenizes() {
+        let mut tokenizer = Tokenizer::new("2+3*4");
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(2.0));
+        assert_eq!(tokenizer.next().unwrap(), Token::Add);
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(3.0));
+        tokenizer.rewind(3);
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(2.0));
+        tokenizer.rewind(100);
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(2.0));
+    }
+
+    #[test]
+    fn test_rewind_advance_cycles() {
+        let mut tokenizer = Tokenizer::new("12 + 0x1F");
+        let all: Vec<Token> = Tokenizer::new("12 + 0x1F").take(4).collect();
+        for _ in 0..3 {
+            let first: Vec<Token> = tokenizer.by_ref().take(4).collect();
+            assert_eq!(first, all);
+            tokenizer.rewind(9);
+        }
+        tokenizer.next();
+        tokenizer.rewind(1);
+        assert_eq!(tokenizer.next().unwrap(), Token::Num(2.0));
+    }
+
     #[test]
     fn test_tokenize_whitespace() {
         let mut tokenizer = Tokenizer::new("   4   +  6 ");
@@ -136,4 +458,322 @@ mod tests {
         assert_eq!(tokenizer.next().unwrap(), Token::Add);
         assert_eq!(tokenizer.next().unwrap(), Token::Num(6.0));
     }
+
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(Tokenizer::count_tokens("2+3*4"), 5);
+        assert_eq!(Tokenizer::count_tokens("  "), 0);
+        assert_eq!(Tokenizer::count_tokens(""), 0);
+        assert_eq!(Tokenizer::count_tokens("(1+2)*(3+4)"), 9);
+        assert_eq!(Tokenizer::count_tokens("max(1, (2))"), 6);
+        assert_eq!(Tokenizer::count_tokens(" rate * 0x10 "), 3);
+    }
+
+    #[test]
+    fn test_decimal_separator() {
+        use super::Token::*;
+        let tokens = |expr: &str, separator: char| -> Vec<Option<Token>> {
+            let mut tokenizer = Tokenizer::new_with_decimal_separator(expr, separator);
+            std::iter::from_fn(|| Some(tokenizer.next()).filter(|token| *token != Some(EOF))).collect()
+        };
+        assert_eq!(tokens("3,14", ','), [Some(Num(3.14))]);
+        let call = [Ident("f".into()), LeftParen, Num(1.5), Comma, Num(2.0), RightParen];
+        assert_eq!(tokens("f(1,5;2)", ','), call.map(Some));
+        assert_eq!(tokens("1.5", ','), [Some(Num(1.0)), None, Some(Num(5.0))]);
+        let list = [Some(LeftParen), Some(Num(1.0)), Some(RightParen), None, Some(Num(2.0))];
+        assert_eq!(tokens("(1),2", ','), list);
+        assert_eq!(tokens("3,14", '.'), [Some(Num(3.0)), Some(Comma), Some(Num(14.0))]);
+        assert_eq!(tokens("1;2", '.'), [Some(Num(1.0)), None, Some(Num(2.0))]);
+    }
+
+    #[test]
+    fn test_tokenize_with_errors() {
+        use super::Token::*;
+        let (tokens, errors) = Tokenizer::tokenize_with_errors("2+$3*4");
+        assert_eq!(tokens, [Num(2.0), Add, Num(3.0), Multiply, Num(4.0), EOF]);
+        assert_eq!(errors, [(2, '$')]);
+        let (tokens, errors) = Tokenizer::tokenize_with_errors("é$ 1 #");
+        assert_eq!(tokens, [Num(1.0), EOF]);
+        assert_eq!(errors, [(0, 'é'), (2, '$'), (6, '#')]);
+        assert_eq!(Tokenizer::tokenize_with_errors("x^2"), (vec![Ident("x".into()), Caret, Num(2.0), EOF], vec![]));
+    }
+
+    #[test]
+    fn test_count_tokens_invalid_characters() {
+        assert_eq!(Tokenizer::count_tokens("2$3"), 3);
+        assert_eq!(Tokenizer::count_tokens("$$"), 2);
+    }
+
+    #[test]
+    fn test_is_empty_or_whitespace() {
+        assert!(Tokenizer::is_empty_or_whitespace(""));
+        assert!(Tokenizer::is_empty_or_whitespace("  \t\n"));
+        assert!(!Tokenizer::is_empty_or_whitespace(" 1 "));
+        assert!(!Tokenizer::is_empty_or_whitespace("$"));
+    }
+
+    #[test]
+    fn test_token_start() {
+        let mut tokenizer = Tokenizer::new(" 12 +\tx");
+        let mut starts = Vec::new();
+        while tokenizer.next() != Some(Token::EOF) {
+            starts.push((tokenizer.token_start(), tokenizer.offset()));
+        }
+        assert_eq!(starts, [(1, 3), (4, 5), (6, 7)]);
+        assert_eq!(tokenizer.token_start(), 7);
+    }
+
+    #[test]
+    fn test_modulo_token() {
+        let tokens: Vec<Token> = Tokenizer::new("7%3").take(4).collect();
+        assert_eq!(tokens, [Token::Num(7.0), Token::Modulo, Token::Num(3.0), Token::EOF]);
+        assert_eq!(Token::Modulo.get_oper_prec(), Token::Divide.get_oper_prec());
+        assert_eq!(Token::Remainder.get_oper_prec(), Token::Multiply.get_oper_prec());
+    }
+
+    #[test]
+    fn test_debug_shows_position() {
+        let mut tokenizer = Tokenizer::new("1 + 2");
+        tokenizer.next();
+        assert_eq!(format!("{:?}", tokenizer), r#"Tokenizer { position: 1, remaining: " + 2" }"#);
+    }
+
+    #[test]
+    fn test_consumed_and_remaining_input() {
+        let mut tokenizer = Tokenizer::new("12 + π*3");
+        assert_eq!((tokenizer.consumed(), tokenizer.remaining_chars()), (0, 8));
+        assert_eq!(tokenizer.next(), Some(Token::Num(12.0)));
+        assert_eq!(tokenizer.consumed(), 2);
+        assert_eq!(tokenizer.remaining_input(), " + π*3");
+        assert_eq!(tokenizer.next(), Some(Token::Add));
+        assert_eq!(tokenizer.next(), None); // π is not a valid character
+        assert_eq!(tokenizer.consumed(), 6);
+        assert_eq!(tokenizer.remaining_input(), "*3");
+        assert_eq!(tokenizer.remaining_chars(), 2);
+        tokenizer.rewind(2);
+        assert_eq!((tokenizer.consumed(), tokenizer.remaining_input()), (4, " π*3"));
+        while tokenizer.next() != Some(Token::EOF) {}
+        assert_eq!((tokenizer.consumed(), tokenizer.remaining_chars()), (8, 0));
+    }
+
+    #[test]
+    fn test_as_str_remaining() {
+        let mut tokenizer = Tokenizer::new("2+3*4");
+        assert_eq!(tokenizer.as_str_remaining(), "2+3*4");
+        assert_eq!((tokenizer.next(), tokenizer.next()), (Some(Token::Num(2.0)), Some(Token::Add)));
+        assert_eq!(tokenizer.as_str_remaining(), "3*4");
+        let mut tokenizer = Tokenizer::new("12 + 7 km");
+        tokenizer.by_ref().take(3).for_each(drop);
+        assert_eq!(tokenizer.as_str_remaining(), " km");
+    }
+
+    #[test]
+    fn test_to_remaining_string() {
+        let mut tokenizer = Tokenizer::new("2+3*4");
+        assert_eq!(tokenizer.to_remaining_string(), "2+3*4");
+        for _ in 0..3 {
+            tokenizer.next();
+        }
+        assert_eq!(tokenizer.to_remaining_string(), "*4");
+        while tokenizer.next() != Some(Token::EOF) {}
+        assert_eq!(tokenizer.to_remaining_string(), "");
+    }
+
+    #[test]
+    fn test_try_parse_number() {
+        assert_eq!(Tokenizer::try_parse_number("3.14abc"), Some((3.14, 4)));
+        assert_eq!(Tokenizer::try_parse_number("42"), Some((42.0, 2)));
+        assert_eq!(Tokenizer::try_parse_number("0xFF+1"), Some((255.0, 4)));
+        assert_eq!(Tokenizer::try_parse_number("abc"), None);
+        assert_eq!(Tokenizer::try_parse_number(" 1"), None);
+        assert_eq!(Tokenizer::try_parse_number("1.2.3"), None);
+        assert_eq!(Tokenizer::try_parse_number(""), None);
+    }
+
+    #[test]
+    fn test_whitespace_tokenizer() {
+        use super::Token::*;
+        let mut tokenizer = Tokenizer::new_with_whitespace("2 + 3");
+        let expected = [Num(2.0), Whitespace(" ".into()), Add, Whitespace(" ".into()), Num(3.0), EOF];
+        for token in expected {
+            assert_eq!(tokenizer.next(), Some(token));
+        }
+    }
+
+    #[test]
+    fn test_whitespace_tokenizer_reconstructs_source() {
+        for expr in ["\t 1\n", "max( 1,\t2 )  *x", "7"] {
+            let mut tokenizer = Tokenizer::new_with_whitespace(expr);
+            let mut starts = Vec::new();
+            let mut rendered = String::new();
+            while let Some(token) = tokenizer.next().filter(|t| *t != Token::EOF) {
+                starts.push(tokenizer.token_start());
+                rendered.push_str(&token.display_symbol());
+            }
+            assert_eq!(rendered, expr);
+            assert_eq!(starts[0], 0);
+            assert!(starts.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let tokens: Vec<Token> = Tokenizer::new_with_whitespace("\t 1\n").take(4).collect();
+        use super::Token::*;
+        let expected = [Whitespace("\t ".into()), Num(1.0), Whitespace("\n".into()), EOF];
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn test_to_infix_string() {
+        use super::super::pool::tokenize;
+        use super::super::token::TokensExt;
+        let mut tokens = tokenize("2+3*4").unwrap();
+        assert_eq!(tokens.to_infix_string().as_deref(), Some("2+3*4"));
+        tokens[1] = Token::Subtract;
+        tokens.insert(3, Token::Num(0.5));
+        assert_eq!(tokens.to_infix_string().as_deref(), Some("2-3 0.5*4"));
+        let render = |expr: &str| tokenize(expr).unwrap().to_infix_string();
+        assert_eq!(render("7 rem 3").as_deref(), Some("7 rem 3"));
+        assert_eq!(render("-max(x, 2.50)! ^ 2").as_deref(), Some("-max(x,2.5)!^2"));
+        assert_eq!(render("let y = 2 in y").as_deref(), Some("let y=2 in y"));
+        assert_eq!(render("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_to_infix_string_invalid() {
+        use super::super::pool::tokenize;
+        use super::super::token::TokensExt;
+        for expr in ["2+*3", "(*2)", "2+", "*", "f(1,)"] {
+            assert_eq!(tokenize(expr).unwrap().to_infix_string(), None, "{}", expr);
+        }
+        assert_eq!([Token::Num(1.0), Token::Add, Token::Add].to_infix_string(), None);
+    }
+
+    #[test]
+    fn test_same_operator() {
+        assert!(Token::Add.same_operator(&Token::Add));
+        assert!(Token::Bang.same_operator(&Token::Bang));
+        assert!(!Token::Add.same_operator(&Token::Subtract));
+        assert!(!Token::Num(1.0).same_operator(&Token::Num(2.0)));
+        assert!(!Token::Num(1.0).same_operator(&Token::Num(1.0)));
+        let punctuation = [Token::LeftParen, Token::RightParen, Token::Comma, Token::Assign, Token::EOF];
+        for token in punctuation.into_iter().chain([Token::Ident("x".into())]) {
+            assert!(!token.same_operator(&token), "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn test_token_arity() {
+        assert_eq!(Token::Add.arity(), Some(2));
+        assert_eq!(Token::Modulo.arity(), Some(2));
+        assert_eq!(Token::Bang.arity(), Some(1));
+        assert_eq!(Token::Num(3.0).arity(), None);
+        assert_eq!(Token::EOF.arity(), None);
+        assert_eq!(Token::LeftParen.arity(), None);
+        assert_eq!(Token::RightParen.arity(), None);
+    }
+
+    #[test]
+    fn test_token_arity_balances_operands() {
+        // Each operator of arity n turns n operands into one, leaving a single value
+        for expr in ["2+3*4-5", "(1+2)^3!", "2 & 3 | 4 % 5", "7"] {
+            let tokens: Vec<Token> = Tokenizer::new(expr).take_while(|t| *t != Token::EOF).collect();
+            let merged: usize = tokens.iter().filter_map(Token::arity).map(|n| usize::from(n) - 1).sum();
+            let operands = tokens.iter().filter(|t| matches!(t, Token::Num(_))).count();
+            assert_eq!(merged, operands - 1, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_peek_char() {
+        let mut tokenizer = Tokenizer::new(" 2*x");
+        assert_eq!(tokenizer.peek_char(), Some(' '));
+        assert_eq!(tokenizer.peek_char(), Some(' '));
+        assert_eq!(tokenizer.offset(), 0);
+        assert_eq!(tokenizer.next(), Some(Token::Num(2.0)));
+        assert_eq!(tokenizer.peek_char(), Some('*'));
+        assert_eq!(tokenizer.next(), Some(Token::Multiply));
+        assert_eq!(tokenizer.next(), Some(Token::Ident("x".into())));
+        assert_eq!(tokenizer.peek_char(), None);
+        assert_eq!(Tokenizer::new("é+1").peek_char(), Some('é'));
+    }
+
+    #[test]
+    fn test_token_char_round_trip() {
+        use super::Token::*;
+        let singles =
+            [And, Or, Add, Subtract, Multiply, Divide, Modulo, Caret, LeftParen, RightParen, Comma, Assign, Bang];
+        for token in singles {
+            let c = token.to_char().unwrap();
+            assert_eq!(Token::from_char(c), Some(token.clone()));
+            assert_eq!(Tokenizer::new(&c.to_string()).next(), Some(token));
+        }
+        assert_eq!(Token::from_char('+'), Some(Add));
+        assert_eq!(Token::from_char('a'), None);
+        assert_eq!(Token::from_char('1'), None);
+        assert_eq!(Token::from_char('$'), None);
+        assert_eq!(Num(3.0).to_char(), None);
+        assert_eq!(EOF.to_char(), None);
+        assert_eq!(Remainder.to_char(), None);
+    }
+
+    #[test]
+    fn test_precedence_table() {
+        use crate::parsemath::token::{precedence_of, PRECEDENCE_TABLE};
+        for (symbol, prec) in PRECEDENCE_TABLE {
+            assert_eq!(Token::from_symbol(symbol).unwrap().get_oper_prec(), *prec, "{}", symbol);
+            assert_eq!(precedence_of(symbol), Some(*prec));
+        }
+        // Every binary operator the tokenizer produces needs an entry
+        for symbol in ["&", "|", "+", "-", "*", "/", "%", "^", "mod", "rem", "(", ")", ",", "=", "!"] {
+            let token = Token::from_symbol(symbol).unwrap();
+            let listed = PRECEDENCE_TABLE.iter().any(|(s, _)| Token::from_symbol(s) == Some(token.clone()));
+            assert_eq!(listed, token.arity() == Some(2), "{}", symbol);
+        }
+        assert_eq!(precedence_of("!"), None);
+        assert_eq!(precedence_of("x"), None);
+        assert_eq!(Token::from_symbol("x"), None);
+        assert_eq!(Token::from_symbol("1"), None);
+    }
+
+    #[test]
+    fn test_oper_prec_for_token_and_of_string() {
+        use crate::parsemath::token::OperPrec;
+        assert_eq!(OperPrec::for_token(&Token::Multiply), OperPrec::MulDiv);
+        assert_eq!(OperPrec::for_token(&Token::Num(1.0)), OperPrec::DefaultZero);
+        assert_eq!(OperPrec::of_string("+"), Some(OperPrec::AddSub));
+        assert_eq!(OperPrec::of_string("mod"), Some(OperPrec::MulDiv));
+        assert_eq!(OperPrec::of_string("?"), None);
+    }
+
+    #[test]
+    fn test_oper_prec_display() {
+        use crate::parsemath::token::OperPrec::*;
+        assert_eq!(AddSub.to_string(), "AddSub (level 2)");
+        assert_eq!(DefaultZero.to_string(), "DefaultZero (level 0)");
+        assert_eq!(PostfixUnary.to_string(), "PostfixUnary (level 6)");
+        let mut names: Vec<String> = [DefaultZero, Bitwise, AddSub, MulDiv, Exponent, Unary, PostfixUnary]
+            .iter()
+            .map(|level| level.to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 7);
+    }
+
+    #[test]
+    fn test_oper_prec_between() {
+        use crate::parsemath::token::OperPrec::{self, *};
+        let levels = [DefaultZero, Bitwise, AddSub, MulDiv, Exponent, Unary, PostfixUnary];
+        for (i, low) in levels.iter().enumerate() {
+            for (j, high) in levels.iter().enumerate() {
+                for (k, level) in levels.iter().enumerate() {
+                    let expected = i < k && k < j;
+                    assert_eq!(level.between(low, high), expected, "{:?} < {:?} < {:?}", low, level, high);
+                }
+            }
+        }
+        // Adjacent levels have nothing between them, non-adjacent ones do
+        assert!(!levels.iter().any(|level| level.between(&AddSub, &MulDiv)));
+        assert!(MulDiv.between(&AddSub, &Exponent));
+        assert!(!AddSub.between(&AddSub, &Exponent));
+        assert!(OperPrec::Negative.between(&Exponent, &PostfixUnary));
+    }
 }
@@ -0,0 +1,72 @@
+/// This records every stage of evaluating an expression, from its tokens to its value,
+/// so that a teaching tool can show how `2+3*4` becomes 14.
+// Internal modules
+use super::ast;
+use super::parser::{ParseError, Parser};
+use super::token::Token;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalTrace {
+    // Tokens of the expression as the parser read them, ending with Token::EOF
+    pub tokens: Vec<Token>,
+    // The parsed AST with every nested operator parenthesised, e.g. `2 + (3 * 4)`
+    pub ast: String,
+    // The AST after folding constant subtrees, None when folding left it unchanged
+    pub simplified_ast: Option<String>,
+    pub result: f64,
+}
+
+// Tokenize, parse, fold and evaluate `expr`, keeping the output of each stage
+pub fn evaluate_verbose(expr: &str) -> Result<EvalTrace, ParseError> {
+    let (node, tokens) = Parser::new(expr)?.parse_with_tokens()?;
+    let ast = node.to_string();
+    let mut folded = node.clone();
+    // Folding stops at subtrees that fail, the error is reported by `eval` below
+    let _ = ast::eval_annotate(&mut folded);
+    let simplified_ast = (folded != node).then(|| folded.to_string());
+    let result = ast::eval(node).map_err(|e| ParseError::from(e).with_expression(expr))?;
+    Ok(EvalTrace { tokens, ast, simplified_ast, result })
+}
+
+#[cfg(test)]
+#[deny(unused_must_use)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_verbose() {
+        use crate::parsemath::token::Token::*;
+        let trace = evaluate_verbose("2+3*4").unwrap();
+        assert_eq!(trace.tokens, [Num(2.0), Add, Num(3.0), Multiply, Num(4.0), EOF]);
+        assert_eq!(trace.ast, "2 + (3 * 4)");
+        assert_eq!(trace.simplified_ast.as_deref(), Some("14"));
+        assert_eq!(trace.result, 14.0);
+    }
+
+    #[test]
+    fn test_evaluate_verbose_tokens_from_parser() {
+        use crate::parsemath::pool::tokenize;
+        let expr = "let y = 2 in max(y, -(1+2)!) ^ 2";
+        assert_eq!(evaluate_verbose(expr).unwrap().tokens, tokenize(expr).unwrap());
+        let tokens = evaluate_verbose("7 mod 3").unwrap().tokens;
+        assert_eq!(tokens, [Token::Num(7.0), Token::Modulo, Token::Num(3.0), Token::EOF]);
+    }
+
+    #[test]
+    fn test_evaluate_verbose_unchanged_by_folding() {
+        let trace = evaluate_verbose("7").unwrap();
+        assert_eq!((trace.ast.as_str(), trace.simplified_ast, trace.result), ("7", None, 7.0));
+        let trace = evaluate_verbose("let x = 1 + 1 in x * 3").unwrap();
+        assert_eq!(trace.result, 6.0);
+        assert_ne!(trace.simplified_ast.as_deref(), Some(trace.ast.as_str()));
+    }
+
+    #[test]
+    fn test_evaluate_verbose_errors() {
+        assert!(evaluate_verbose("2 $ 3").is_err());
+        assert!(evaluate_verbose("2 +").is_err());
+        let error = evaluate_verbose("1/0 + 2").unwrap_err();
+        assert!(matches!(error, ParseError::EvalFailed(..)));
+        assert_eq!(error.source_expression(), Some("1/0 + 2"));
+    }
+}